@@ -27,6 +27,9 @@ pub enum Subcommand {
         /// The url of the item
         #[structopt(short, long)]
         url: Option<String>,
+        /// A file on disk to add instead of a url. monkd keeps a copy of it
+        #[structopt(short, long, parse(from_os_str), conflicts_with = "url")]
+        file: Option<PathBuf>,
         /// Any associated comment for the item
         #[structopt(short, long)]
         comment: Option<String>,
@@ -34,6 +37,17 @@ pub enum Subcommand {
         #[structopt(short, long)]
         tags: Vec<String>,
     },
+    /// Write a markdown note in $EDITOR, or edit an existing one
+    Note {
+        /// The name or id of the note to edit. Starts a new note if not set
+        id: Option<String>,
+        /// Name of a new note. Defaults to its first line
+        #[structopt(short, long)]
+        name: Option<String>,
+        /// A space seperated list of tags for a new note
+        #[structopt(short, long)]
+        tags: Vec<String>,
+    },
     /// List all items in the database
     List {
         /// Print lists of metadata items on a single line. Much like `git log --oneline`
@@ -69,6 +83,9 @@ pub enum Subcommand {
     Download {
         // #[structopt(short, long)]
         // all: bool,
+        /// Retry every download that failed
+        #[structopt(long)]
+        retry_failed: bool,
         id: Option<String>,
     },
     /// Open an ID with the system's default program for the item's filetype.
@@ -132,7 +149,18 @@ pub enum Subcommand {
         #[structopt(subcommand)]
         kind: StatusRequestKind,
     },
-    /// Shutdown the daemon with no cleanup
+    /// List the downloads and index runs that haven't finished yet
+    Jobs {
+        #[structopt(subcommand)]
+        command: Option<JobsSubcommand>,
+    },
+    /// Manage how the daemon is run
+    Daemon {
+        #[structopt(subcommand)]
+        command: DaemonSubcommand,
+    },
+    /// Stop the daemon right away, aborting downloads and index runs. They
+    /// resume the next time the daemon starts
     ForceShutdown,
     /// Cleanly shutdown the daemon
     Stop,
@@ -153,6 +181,22 @@ pub enum IndexSubcommand {
     Id(Vec<String>),
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, StructOpt)]
+pub enum JobsSubcommand {
+    /// Cancel a job. The id can be shortened to any unique prefix.
+    Cancel { id: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, StructOpt)]
+pub enum DaemonSubcommand {
+    /// Write a systemd user unit that runs monkd as a service
+    InstallService {
+        /// Overwrite an existing unit
+        #[structopt(short, long)]
+        force: bool,
+    },
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, StructOpt)]
 pub enum StatusRequestKind {
     /// Get the status of the meta store, offline store, and search index.
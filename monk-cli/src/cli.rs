@@ -1,6 +1,4 @@
 use colored::*;
-use scraper::{Html, Selector};
-use std::net::SocketAddr;
 use std::path::PathBuf;
 use term_table::{
     row::Row,
@@ -9,13 +7,20 @@ use term_table::{
 };
 use url::Url;
 
-use crate::args::{Args, IndexSubcommand, StatusRequestKind, Subcommand};
+use crate::args::{
+    Args, DaemonSubcommand, IndexSubcommand, JobsSubcommand, StatusRequestKind, Subcommand,
+};
+use crate::client::{DaemonClient, Endpoint, EventStream};
 use crate::error::Error;
+use crate::{note, service};
 
-use monkd::metadata::Meta;
+use monkd::events::Event;
+use monkd::jobs::{JobInfo, JobKind};
+use monkd::metadata::{offline_store::Status as OfflineStatus, Meta};
 use monkd::server::{
     request::{Edit, Request, StatusKind},
     response::{Response, SnippetDef},
+    PROTOCOL_VERSION,
 };
 use monkd::settings::Settings;
 use monkd::status::StatusResponse;
@@ -24,8 +29,24 @@ pub struct Cli;
 
 impl Cli {
     pub async fn run(settings: Settings, mut args: Args) -> Result<(), Error> {
+        // Setting up the daemon doesn't need one running
+        if let Subcommand::Daemon { command } = &args.subcommand {
+            return match command {
+                DaemonSubcommand::InstallService { force } => service::install(&settings, *force),
+            };
+        }
+
         check_or_spawn(&settings).await?;
 
+        // Notes take a round trip through the editor, not a single request
+        if let Subcommand::Note { id, name, tags } = args.subcommand.clone() {
+            let client = handshake(&settings).await?;
+            let response = note::edit(&client, id, name, tags).await?;
+            handle_response(&args, response);
+
+            return Ok(());
+        }
+
         let request = match args.subcommand.clone() {
             Subcommand::Config { file } => {
                 let config = Settings::get_settings(file).unwrap();
@@ -42,30 +63,31 @@ impl Cli {
                 std::process::exit(0);
             }
             Subcommand::Add {
-                mut name,
+                name,
                 url,
+                file,
                 comment,
                 tags,
             } => {
-                if name.is_none() && url.is_none() && comment.is_none() {
-                    println!("either name, url, or comment must be set");
+                if name.is_none() && url.is_none() && file.is_none() && comment.is_none() {
+                    println!("either name, url, file, or comment must be set");
                     std::process::exit(1);
                 }
-                if let Some(ref u) = url {
-                    let res = reqwest::get(u).await?;
-                    let body = res.text().await?;
-                    if name.is_none() {
-                        let fragment = Html::parse_fragment(&body); // Parse the top of the page
-                        let selector = Selector::parse("title").unwrap();
-                        if let Some(title) = fragment.select(&selector).next() {
-                            name = Some(title.inner_html());
-                        }
-                    }
-                    // TODO: Scrape for a description
-                }
 
-                let url: Option<Url> = url.map(|s| Url::parse(&s)).transpose()?;
+                let url: Option<Url> = match file {
+                    // monkd doesn't run in our working directory
+                    Some(file) => match file.canonicalize() {
+                        Ok(path) if path.is_file() => Url::from_file_path(&path).ok(),
+                        _ => {
+                            println!("{} is not a file", file.display());
+                            std::process::exit(1);
+                        }
+                    },
+                    None => url.map(|s| Url::parse(&s)).transpose()?,
+                };
 
+                // monkd fills in the title and the rest of the page's
+                // metadata once it has fetched the page
                 Request::Add {
                     name,
                     url,
@@ -135,9 +157,18 @@ impl Cli {
                     kind: StatusKind::Id(ids[0].clone()),
                 },
             },
+            Subcommand::Jobs { command } => match command {
+                None => Request::Jobs,
+                Some(JobsSubcommand::Cancel { id }) => Request::CancelJob { id },
+            },
+            Subcommand::Daemon { .. } => unreachable!("handled before connecting"),
+            Subcommand::Note { .. } => unreachable!("handled before building a request"),
             Subcommand::Stop => Request::Stop,
             Subcommand::ForceShutdown => Request::ForceShutdown,
-            Subcommand::Download { id } => Request::Download { id },
+            Subcommand::Download {
+                retry_failed: true, ..
+            } => Request::RetryFailed,
+            Subcommand::Download { id, .. } => Request::Download { id },
             Subcommand::Open { id, online, .. } => Request::Open { id, online },
             Subcommand::Export { file, full } => Request::ExportFile {
                 file: PathBuf::from(file),
@@ -158,17 +189,25 @@ impl Cli {
             }
         };
 
-        let socket = SocketAddr::new(settings.daemon().address, settings.daemon().port);
-        let socket_url = format!("http://{}", socket.to_string());
-        let url = reqwest::Url::parse(&socket_url)?;
+        let client = handshake(&settings).await?;
+
+        // Subscribe before sending the request, so the download can't finish
+        // before we start listening
+        let events = match args.subcommand {
+            Subcommand::Open { blocking: true, .. } => Some(client.events().await?),
+            _ => None,
+        };
 
-        let response: Response = reqwest::Client::new()
-            .get(url.clone())
-            .json(&request)
-            .send()
-            .await?
-            .json::<Response>()
-            .await?;
+        let response = match (events, client.send(&request).await?) {
+            (Some(mut events), Response::OpenStatus(id, OfflineStatus::Downloading))
+            | (Some(mut events), Response::MetaOfflineStatus(id, OfflineStatus::Downloading)) => {
+                match wait_for_download(&mut events, &id).await? {
+                    Some(error) => Response::Error(error),
+                    None => client.send(&request).await?,
+                }
+            }
+            (_, response) => response,
+        };
 
         handle_response(&args, response);
 
@@ -176,11 +215,102 @@ impl Cli {
     }
 }
 
+// Connect to the daemon and make sure it speaks our protocol. If it doesn't,
+// e.g. because it was started before `monk` was upgraded, offer to restart it.
+async fn handshake(settings: &Settings) -> Result<DaemonClient, Error> {
+    let client = DaemonClient::new(settings)?;
+    let hello = Request::Hello {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        protocol: PROTOCOL_VERSION,
+    };
+
+    let daemon = match client.send(&hello).await {
+        Ok(Response::Hello { protocol, .. }) if protocol == PROTOCOL_VERSION => return Ok(client),
+        Ok(Response::Hello {
+            version, protocol, ..
+        }) => format!("monkd {} (protocol {})", version, protocol),
+        // Daemons from before the handshake don't know `Hello`
+        Ok(_) | Err(Error::Status(_)) | Err(Error::Json(_)) => "an older monkd".to_string(),
+        Err(e) => return Err(e),
+    };
+
+    println!(
+        "warning: {} is running, which doesn't speak the protocol of monk {} (protocol {})",
+        daemon,
+        env!("CARGO_PKG_VERSION"),
+        PROTOCOL_VERSION
+    );
+
+    if !confirm("Restart it? Running downloads are resumed afterwards") {
+        return Ok(client);
+    }
+
+    // `Stop` is understood by every version
+    client.send(&Request::Stop).await?;
+    wait_for_exit(settings).await;
+    check_or_spawn(settings).await?;
+
+    DaemonClient::new(settings)
+}
+
+// Ask a yes or no question. No if nobody is there to answer it.
+fn confirm(question: &str) -> bool {
+    use std::io::{BufRead, IsTerminal, Write};
+
+    if !std::io::stdin().is_terminal() {
+        return false;
+    }
+
+    print!("{} [Y/n] ", question);
+    let _ = std::io::stdout().flush();
+
+    let mut answer = String::new();
+    if std::io::stdin().lock().read_line(&mut answer).is_err() {
+        return false;
+    }
+
+    matches!(answer.trim().to_lowercase().as_str(), "" | "y" | "yes")
+}
+
+// Wait up to five seconds for a stopped daemon to finish and let go of the
+// data directory
+async fn wait_for_exit(settings: &Settings) {
+    for _ in 0..50u8 {
+        if monkd::lock::read(settings).is_none() && !Endpoint::find(settings).is_reachable().await {
+            return;
+        }
+
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+
+    println!("warning: monkd is still running");
+}
+
+// Wait for the download of `id` to finish. Returns the error if it failed.
+async fn wait_for_download(events: &mut EventStream, id: &str) -> Result<Option<String>, Error> {
+    println!("[{}] downloading", id.bright_purple());
+
+    while let Some(event) = events.next().await? {
+        match event {
+            Event::DownloadFinished { id: done } if done == id => return Ok(None),
+            Event::DownloadFailed { id: failed, error } if failed == id => {
+                return Ok(Some(format!("download failed: {}", error)))
+            }
+            _ => {}
+        }
+    }
+
+    Ok(Some("monkd closed the event stream".to_string()))
+}
+
 pub fn handle_response(args: &Args, response: Response) {
     match response {
         Response::Item(meta) => {
             print_tabled(vec![meta]);
         }
+        Response::Note(_, body) => {
+            print!("{}", body);
+        }
         Response::List(mut items) => {
             items.sort_by_key(|i| *i.found());
 
@@ -213,6 +343,13 @@ pub fn handle_response(args: &Args, response: Response) {
                 id.bright_purple()
             );
         }
+        Response::Offline(data) => {
+            println!(
+                "status for [{}]: {:?}",
+                data.id().bright_purple(),
+                data.status
+            );
+        }
         Response::MetaOfflineStatus(id, status) => {
             println!("status for [{}]: {:?}", id.bright_purple(), status);
         }
@@ -237,6 +374,14 @@ pub fn handle_response(args: &Args, response: Response) {
         Response::Unhandled => {
             println!("monk could not handle the request");
         }
+        Response::UnknownRequest(e) => {
+            println!("monkd did not understand the request: {}", e);
+        }
+        Response::Hello {
+            version, protocol, ..
+        } => {
+            println!("monkd {} (protocol {})", version, protocol);
+        }
         Response::Many(responses) => {
             for response in responses {
                 handle_response(args, response);
@@ -245,6 +390,13 @@ pub fn handle_response(args: &Args, response: Response) {
         Response::Status(status) => {
             print_status(status);
         }
+        Response::Jobs(jobs) => {
+            if jobs.is_empty() {
+                println!("no jobs running");
+            } else {
+                print_jobs(jobs);
+            }
+        }
         Response::SearchResult(mut items) => {
             items.sort_by_key(|i| *i.0.found());
 
@@ -473,6 +625,23 @@ fn print_status(status: StatusResponse) {
             get_byte_unit(index.bytes_on_disk).to_string().green()
         );
         println!("  {} {}", index.item_count, "item(s)".blue());
+
+        if index.queue_depth > 0 || index.in_progress > 0 {
+            println!(
+                "  {} queued, {} in progress",
+                index.queue_depth, index.in_progress
+            );
+        }
+
+        if let Some(rebuild) = index.rebuild {
+            println!(
+                "  {} {}/{} (from schema {})",
+                "rebuilding".yellow(),
+                rebuild.done,
+                rebuild.total,
+                rebuild.from_version
+            );
+        }
     }
 
     if let Some(meta) = status.meta {
@@ -496,6 +665,52 @@ fn print_status(status: StatusResponse) {
     }
 }
 
+fn print_jobs(jobs: Vec<JobInfo>) {
+    let mut table = Table::new();
+    table.max_column_width = 40;
+    table.style = TableStyle::rounded();
+
+    let row: Vec<TableCell> = [
+        "id", "item", "kind", "state", "adapter", "started", "progress",
+    ]
+    .iter()
+    .map(|header| TableCell::new_with_alignment(header, 1, Alignment::Center))
+    .collect();
+    table.add_row(Row::new(row));
+
+    for job in jobs {
+        let kind = match job.kind {
+            JobKind::Download => "download",
+            JobKind::Index => "index",
+        };
+        let adapter = job
+            .adapter
+            .map(|a| format!("{:?}", a))
+            .unwrap_or_else(|| "n/a".into());
+        let started = job
+            .started
+            .map(|s| s.format("%b %d %R").to_string())
+            .unwrap_or_else(|| "n/a".into());
+        let progress = job
+            .bytes_downloaded
+            .map(|b| get_byte_unit(b as usize).to_string())
+            .unwrap_or_else(|| "n/a".into());
+
+        let row = vec![
+            TableCell::new_with_alignment(job.id, 1, Alignment::Left),
+            TableCell::new_with_alignment(job.item, 1, Alignment::Left),
+            TableCell::new_with_alignment(kind, 1, Alignment::Left),
+            TableCell::new_with_alignment(format!("{:?}", job.state), 1, Alignment::Left),
+            TableCell::new_with_alignment(adapter, 1, Alignment::Left),
+            TableCell::new_with_alignment(started, 1, Alignment::Left),
+            TableCell::new_with_alignment(progress, 1, Alignment::Right),
+        ];
+        table.add_row(Row::new(row));
+    }
+
+    print!("{}", table.render());
+}
+
 fn get_byte_unit(bytes: usize) -> byte_unit::AdjustedByte {
     let byte = byte_unit::Byte::from_u128(bytes as u128).unwrap_or_default();
     byte.get_appropriate_unit(byte_unit::UnitType::Binary)
@@ -503,17 +718,13 @@ fn get_byte_unit(bytes: usize) -> byte_unit::AdjustedByte {
 
 pub async fn check_or_spawn(settings: &Settings) -> Result<(), std::io::Error> {
     use std::process::{Command, Stdio};
-    use sysinfo::{ProcessExt, SystemExt};
-    use tokio::net::TcpStream;
 
-    let mut system = sysinfo::System::new_all();
-    system.refresh_all();
+    if Endpoint::find(settings).is_reachable().await {
+        return Ok(());
+    }
 
-    if !system
-        .processes()
-        .iter()
-        .any(|(_pid, proc)| proc.name() == "monkd")
-    {
+    // A daemon that holds the lock but isn't reachable yet is still starting
+    if monkd::lock::read(settings).is_none() {
         // println!("Spawning the daemon");
 
         let command_path = if std::env::var("MONK_DEBUG").is_ok() {
@@ -522,7 +733,14 @@ pub async fn check_or_spawn(settings: &Settings) -> Result<(), std::io::Error> {
             "monkd"
         };
 
-        let _monkd = Command::new(command_path)
+        let mut command = Command::new(command_path);
+
+        // Start it on the same data directory `monk` looks at
+        if !settings.config_path().as_os_str().is_empty() {
+            command.arg("--config").arg(settings.config_path());
+        }
+
+        let _monkd = command
             .stdout(Stdio::null())
             .stderr(Stdio::null())
             .stdin(Stdio::null())
@@ -533,25 +751,17 @@ pub async fn check_or_spawn(settings: &Settings) -> Result<(), std::io::Error> {
             })?;
 
         tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
 
-        let mut connect_flag = false;
-        for _ in 0..5u8 {
-            match TcpStream::connect((settings.daemon().address, settings.daemon().port)).await {
-                Ok(_) => {
-                    connect_flag = true;
-                    break;
-                }
-                Err(_) => {
-                    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
-                }
-            }
+    // The lock file tells where the daemon listens once it's up
+    for _ in 0..5u8 {
+        if Endpoint::find(settings).is_reachable().await {
+            return Ok(());
         }
 
-        if !connect_flag {
-            println!("error: could not connect to daemon within 350 ms. of spawning");
-            std::process::exit(1)
-        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
     }
 
-    Ok(())
+    println!("error: could not connect to daemon within 350 ms. of spawning");
+    std::process::exit(1)
 }
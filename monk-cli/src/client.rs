@@ -0,0 +1,352 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use monkd::events::Event;
+use monkd::server::{request::Request, response::Response};
+use monkd::settings::{Settings, TlsSettings};
+use reqwest::{Certificate, RequestBuilder, StatusCode, Url};
+
+use crate::error::Error;
+
+/// Where a daemon listens for requests.
+#[derive(Debug, Clone)]
+pub enum Endpoint {
+    Tcp {
+        address: SocketAddr,
+        tls: Option<TlsSettings>,
+    },
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    /// Where the daemon for the data directory in `settings` listens,
+    /// according to its lock file. Falls back to the configured endpoint
+    /// when no daemon holds the lock, e.g. for a daemon on another machine.
+    pub fn find(settings: &Settings) -> Self {
+        let daemon = settings.daemon();
+        let address = SocketAddr::new(daemon.address, daemon.port);
+
+        match monkd::lock::read(settings) {
+            Some(info) => Self::new(info.address.unwrap_or(address), info.tls, info.socket),
+            None => Self::new(address, daemon.tls.clone(), daemon.socket.clone()),
+        }
+    }
+
+    // The Unix socket is used whenever there is one, TCP otherwise
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    fn new(address: SocketAddr, tls: Option<TlsSettings>, socket: Option<PathBuf>) -> Self {
+        #[cfg(unix)]
+        {
+            if let Some(path) = socket {
+                return Endpoint::Unix(path);
+            }
+        }
+
+        Endpoint::Tcp { address, tls }
+    }
+
+    /// Whether something accepts connections here. A leftover socket file
+    /// with nobody listening doesn't count.
+    pub async fn is_reachable(&self) -> bool {
+        match self {
+            Endpoint::Tcp { address, .. } => tokio::net::TcpStream::connect(address).await.is_ok(),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => tokio::net::UnixStream::connect(path).await.is_ok(),
+        }
+    }
+}
+
+/// A connection to a running monkd.
+pub struct DaemonClient {
+    transport: Transport,
+    token: Option<String>,
+}
+
+enum Transport {
+    Tcp {
+        client: reqwest::Client,
+        url: Url,
+    },
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl DaemonClient {
+    pub fn new(settings: &Settings) -> Result<Self, Error> {
+        let token = settings.daemon().token.clone();
+
+        let transport = match Endpoint::find(settings) {
+            Endpoint::Tcp { address, tls } => {
+                let mut builder = reqwest::Client::builder();
+
+                // Trust the daemon's own certificate, which is usually self-signed
+                let scheme = match &tls {
+                    Some(tls) => {
+                        let cert = std::fs::read(&tls.cert)?;
+                        builder = builder.add_root_certificate(Certificate::from_pem(&cert)?);
+                        "https"
+                    }
+                    None => "http",
+                };
+
+                Transport::Tcp {
+                    client: builder.build()?,
+                    url: Url::parse(&format!("{}://{}", scheme, address))?,
+                }
+            }
+            #[cfg(unix)]
+            Endpoint::Unix(path) => Transport::Unix(path),
+        };
+
+        Ok(Self { transport, token })
+    }
+
+    pub async fn send(&self, request: &Request) -> Result<Response, Error> {
+        match &self.transport {
+            Transport::Tcp { client, url } => {
+                let response = self.get(client, url.clone()).json(request).send().await?;
+                let response = check_auth(response)?;
+
+                // Daemons from before the handshake answer requests they
+                // don't know with a bare 400
+                if !response.status().is_success() {
+                    return Err(Error::Status(response.status().as_u16()));
+                }
+
+                Ok(serde_json::from_slice(&response.bytes().await?)?)
+            }
+            #[cfg(unix)]
+            Transport::Unix(path) => {
+                let body = serde_json::to_vec(request)?;
+                let response = unix::get(path, "/", body, self.token.as_deref()).await?;
+                let body = hyper::body::to_bytes(response.into_body()).await?;
+
+                Ok(serde_json::from_slice(&body)?)
+            }
+        }
+    }
+
+    /// Subscribe to the daemon's events. Only events emitted after this
+    /// returns are received.
+    pub async fn events(&self) -> Result<EventStream, Error> {
+        let body = match &self.transport {
+            Transport::Tcp { client, url } => {
+                let response = self.get(client, url.join("events")?).send().await?;
+                EventBody::Tcp(check_auth(response)?.error_for_status()?)
+            }
+            #[cfg(unix)]
+            Transport::Unix(path) => {
+                let response =
+                    unix::get(path, "/events", Vec::new(), self.token.as_deref()).await?;
+                EventBody::Unix(response.into_body())
+            }
+        };
+
+        Ok(EventStream {
+            body,
+            buffer: Vec::new(),
+        })
+    }
+
+    fn get(&self, client: &reqwest::Client, url: Url) -> RequestBuilder {
+        let request = client.get(url);
+
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+}
+
+fn check_auth(response: reqwest::Response) -> Result<reqwest::Response, Error> {
+    if response.status() == StatusCode::UNAUTHORIZED {
+        Err(Error::Unauthorized)
+    } else {
+        Ok(response)
+    }
+}
+
+// reqwest can't connect to a Unix socket, so these requests go through hyper
+// directly. One connection is made per request.
+#[cfg(unix)]
+mod unix {
+    use std::path::Path;
+
+    use hyper::{client::conn, header, Body, Request, Response, StatusCode};
+    use tokio::net::UnixStream;
+
+    use crate::error::Error;
+
+    pub async fn get(
+        path: &Path,
+        uri: &str,
+        body: Vec<u8>,
+        token: Option<&str>,
+    ) -> Result<Response<Body>, Error> {
+        let stream = UnixStream::connect(path).await?;
+        let (mut sender, connection) = conn::handshake(stream).await?;
+
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                eprintln!("error: connection to monkd failed: {}", e);
+            }
+        });
+
+        let mut request = Request::get(uri)
+            .header(header::HOST, "localhost")
+            .header(header::CONTENT_TYPE, "application/json");
+
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+
+        let response = sender.send_request(request.body(Body::from(body))?).await?;
+
+        match response.status() {
+            StatusCode::UNAUTHORIZED => Err(Error::Unauthorized),
+            status if !status.is_success() => Err(Error::Status(status.as_u16())),
+            _ => Ok(response),
+        }
+    }
+}
+
+enum EventBody {
+    Tcp(reqwest::Response),
+    #[cfg(unix)]
+    Unix(hyper::Body),
+}
+
+/// The server-sent events from `/events`.
+pub struct EventStream {
+    body: EventBody,
+    buffer: Vec<u8>,
+}
+
+impl EventStream {
+    /// The next event, or `None` once the daemon closes the stream.
+    pub async fn next(&mut self) -> Result<Option<Event>, Error> {
+        loop {
+            if let Some(event) = take_event(&mut self.buffer)? {
+                return Ok(Some(event));
+            }
+
+            let chunk = match &mut self.body {
+                EventBody::Tcp(response) => response.chunk().await?,
+                #[cfg(unix)]
+                EventBody::Unix(body) => {
+                    use hyper::body::HttpBody;
+                    body.data().await.transpose()?
+                }
+            };
+
+            match chunk {
+                Some(chunk) => self.buffer.extend_from_slice(&chunk),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+// The first complete event in `buffer`, if any. Messages read are removed from
+// it, and an incomplete one is left for the next chunk.
+fn take_event(buffer: &mut Vec<u8>) -> Result<Option<Event>, Error> {
+    // Messages are separated by a blank line
+    while let Some(end) = buffer.windows(2).position(|w| w == b"\n\n") {
+        let message: Vec<u8> = buffer.drain(..end + 2).collect();
+        let message = String::from_utf8_lossy(&message);
+
+        let data: Vec<&str> = message
+            .lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .map(|data| data.trim_start())
+            .collect();
+
+        // Keep-alive comments have no data
+        if data.is_empty() {
+            continue;
+        }
+
+        return Ok(Some(serde_json::from_str(&data.join("\n"))?));
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_one_event_per_message() {
+        let mut buffer = b"data:{\"ItemAdded\":{\"id\":\"a1\"}}\n\n\
+                           data: {\"DownloadStarted\":{\"id\":\"a1\"}}\n\n"
+            .to_vec();
+
+        assert_eq!(
+            take_event(&mut buffer).unwrap(),
+            Some(Event::ItemAdded {
+                id: "a1".to_string()
+            })
+        );
+        assert_eq!(
+            take_event(&mut buffer).unwrap(),
+            Some(Event::DownloadStarted {
+                id: "a1".to_string()
+            })
+        );
+        assert_eq!(take_event(&mut buffer).unwrap(), None);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn skips_keep_alives() {
+        let mut buffer = b":\n\n:\n\ndata:{\"IndexFinished\":{\"id\":\"a1\"}}\n\n".to_vec();
+
+        assert_eq!(
+            take_event(&mut buffer).unwrap(),
+            Some(Event::IndexFinished {
+                id: "a1".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_a_message() {
+        let mut buffer = b"data:{\"DownloadFinished\":".to_vec();
+
+        assert_eq!(take_event(&mut buffer).unwrap(), None);
+
+        buffer.extend_from_slice(b"{\"id\":\"a1\"}}\n");
+        assert_eq!(take_event(&mut buffer).unwrap(), None);
+
+        buffer.extend_from_slice(b"\n");
+        assert_eq!(
+            take_event(&mut buffer).unwrap(),
+            Some(Event::DownloadFinished {
+                id: "a1".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn joins_data_lines() {
+        let mut buffer =
+            b"data:{\"DownloadFailed\":\ndata:{\"id\":\"a1\",\"error\":\"timeout\"}}\n\n".to_vec();
+
+        assert_eq!(
+            take_event(&mut buffer).unwrap(),
+            Some(Event::DownloadFailed {
+                id: "a1".to_string(),
+                error: "timeout".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn bad_data_is_an_error() {
+        let mut buffer = b"data:{\"NotAnEvent\":{}}\n\n".to_vec();
+
+        assert!(take_event(&mut buffer).is_err());
+    }
+}
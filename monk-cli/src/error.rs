@@ -17,6 +17,20 @@ pub enum Error {
     ReqwestError(#[from] reqwest::Error),
     #[error("IO: {0}")]
     Io(#[from] std::io::Error),
+    #[error("Json: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("monkd rejected the request, check that `daemon.token` matches the daemon's")]
+    Unauthorized,
+    #[error("Hyper: {0}")]
+    Hyper(#[from] hyper::Error),
+    #[error("Http: {0}")]
+    Http(#[from] hyper::http::Error),
+    #[error("monkd responded with status {0}")]
+    Status(u16),
+    #[error("{0}")]
+    Service(String),
+    #[error("Editor: {0}")]
+    Editor(String),
 }
 
 impl From<TantivyError> for Error {
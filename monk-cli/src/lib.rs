@@ -1,4 +1,7 @@
 pub mod args;
 pub mod cli;
+pub mod client;
 pub mod error;
+pub mod note;
 pub mod schema;
+pub mod service;
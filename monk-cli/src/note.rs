@@ -0,0 +1,102 @@
+// `monk note`: write notes in the user's editor.
+//
+// The body goes to a temporary file, the editor is run on it, and whatever is
+// left once it exits is sent to monkd. Nothing is sent if a new note is left
+// empty or an existing one unchanged.
+
+use colored::*;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::client::DaemonClient;
+use crate::error::Error;
+
+use monkd::server::{request::Request, response::Response};
+
+/// Write a new note, or edit the note `id`. `name` and `tags` are only used
+/// for new notes.
+pub async fn edit(
+    client: &DaemonClient,
+    id: Option<String>,
+    name: Option<String>,
+    tags: Vec<String>,
+) -> Result<Response, Error> {
+    let id = match id {
+        Some(id) => id,
+        None => {
+            let body = edit_text("new", "")?;
+
+            if body.trim().is_empty() {
+                println!("empty note, nothing added");
+                return Ok(Response::Ok);
+            }
+
+            return client.send(&Request::AddNote { name, body, tags }).await;
+        }
+    };
+
+    let (meta, body) = match client.send(&Request::GetNote { id }).await? {
+        Response::Note(meta, body) => (meta, body),
+        response => return Ok(response),
+    };
+
+    let edited = edit_text(meta.id(), &body)?;
+
+    if edited == body {
+        println!("[{}] unchanged", meta.id().bright_purple());
+        return Ok(Response::Ok);
+    }
+
+    client
+        .send(&Request::SetNote {
+            id: meta.id().to_string(),
+            body: edited,
+        })
+        .await
+}
+
+// Let the user edit `text` and return the result
+fn edit_text(id: &str, text: &str) -> Result<String, Error> {
+    let path = temp_path(id);
+    std::fs::write(&path, text)?;
+
+    let result =
+        run_editor(&path).and_then(|()| std::fs::read_to_string(&path).map_err(Error::from));
+    let _ = std::fs::remove_file(&path);
+
+    result
+}
+
+fn run_editor(path: &Path) -> Result<(), Error> {
+    // `$EDITOR` may carry arguments, e.g. `code --wait`
+    let editor = editor();
+    let mut words = editor.split_whitespace();
+    let program = words
+        .next()
+        .ok_or_else(|| Error::Editor("$EDITOR is empty".to_string()))?;
+
+    let status = Command::new(program)
+        .args(words)
+        .arg(path)
+        .status()
+        .map_err(|e| Error::Editor(format!("could not run `{}`: {}", editor, e)))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::Editor(format!("`{}` failed: {}", editor, status)))
+    }
+}
+
+fn editor() -> String {
+    std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .ok()
+        .filter(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| "vi".to_string())
+}
+
+// The `.md` extension gets editors to highlight the note as markdown
+fn temp_path(id: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("monk-note-{}-{}.md", id, std::process::id()))
+}
@@ -0,0 +1,93 @@
+use std::path::{Path, PathBuf};
+
+use monkd::settings::Settings;
+
+use crate::error::Error;
+
+const UNIT_NAME: &str = "monkd.service";
+
+/// Write a systemd user unit that runs `monkd --foreground` with the same
+/// config as `monk`.
+pub fn install(settings: &Settings, force: bool) -> Result<(), Error> {
+    let dir = unit_dir().ok_or_else(|| {
+        Error::Service("could not find the systemd user unit directory".to_string())
+    })?;
+    let path = dir.join(UNIT_NAME);
+
+    if path.exists() && !force {
+        return Err(Error::Service(format!(
+            "{} already exists, use --force to overwrite it",
+            path.display()
+        )));
+    }
+
+    let monkd = find_monkd().ok_or_else(|| {
+        Error::Service("could not find monkd next to monk or in $PATH".to_string())
+    })?;
+
+    // The unit doesn't run in our working directory
+    let config = settings.config_path();
+    let config = config.canonicalize().unwrap_or_else(|_| config.clone());
+
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(&path, unit(&monkd, &config))?;
+
+    println!("wrote {}", path.display());
+    println!("start monkd now and on every login with:");
+    println!("    systemctl --user daemon-reload");
+    println!("    systemctl --user enable --now {}", UNIT_NAME);
+
+    Ok(())
+}
+
+fn unit(monkd: &Path, config: &Path) -> String {
+    let mut exec = format!("{} --foreground", quote(monkd));
+    if !config.as_os_str().is_empty() {
+        exec.push_str(&format!(" --config {}", quote(config)));
+    }
+
+    format!(
+        "[Unit]
+Description=monk daemon
+Documentation=https://gitlab.com/fisherdarling/monk
+
+[Service]
+Type=notify
+ExecStart={}
+Restart=on-failure
+
+[Install]
+WantedBy=default.target
+",
+        exec
+    )
+}
+
+// Paths in `ExecStart` may contain spaces
+fn quote(path: &Path) -> String {
+    let path = path.display().to_string();
+
+    if path.contains(char::is_whitespace) {
+        format!("\"{}\"", path.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        path
+    }
+}
+
+// `$XDG_CONFIG_HOME/systemd/user`
+fn unit_dir() -> Option<PathBuf> {
+    directories_next::BaseDirs::new().map(|dirs| dirs.config_dir().join("systemd").join("user"))
+}
+
+// systemd wants an absolute path, so look next to `monk` first, then in $PATH
+fn find_monkd() -> Option<PathBuf> {
+    let sibling = std::env::current_exe().ok()?.with_file_name("monkd");
+    if sibling.is_file() {
+        return Some(sibling);
+    }
+
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join("monkd"))
+        .find(|candidate| candidate.is_file())
+}
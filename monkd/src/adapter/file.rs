@@ -0,0 +1,380 @@
+// Local files, added with `monk add --file` or a `file://` url.
+//
+// "Downloading" a local file copies it into the offline folder as
+// `<id>.<extension>`, so the item keeps working if the original is moved or
+// deleted, and records where it came from in `OfflineData::source`. The copy
+// is indexed by the extractor for its type: HTML like a saved page, PDFs like
+// the PDF adapter's, and anything else that is text as is.
+
+use async_channel::Sender;
+use async_trait::async_trait;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use tokio::sync::oneshot;
+use tracing::{info, instrument};
+use url::Url;
+
+use crate::{
+    adapter::{http, pdf, Adapter, AdapterType, Extractor, InFlight},
+    error::Error,
+    index::IndexDoc,
+    metadata::{
+        offline_store::{OfflineData, RetryInfo, Status},
+        Meta,
+    },
+    Request, Response,
+};
+
+// Bytes looked at to tell what a file without a known extension is
+const SNIFF_LEN: usize = 1024;
+
+const HTML_EXTENSIONS: &[&str] = &["html", "htm", "xhtml", "shtml"];
+const TEXT_EXTENSIONS: &[&str] = &[
+    "txt", "text", "md", "markdown", "rst", "org", "adoc", "tex", "csv", "tsv", "log", "json",
+    "yaml", "yml", "toml", "xml",
+];
+
+/// The kinds of files that can be indexed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    Html,
+    Pdf,
+    Text,
+}
+
+impl FileKind {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            FileKind::Html => "text/html",
+            FileKind::Pdf => pdf::PDF_CONTENT_TYPE,
+            FileKind::Text => "text/plain",
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            FileKind::Html => "html",
+            FileKind::Pdf => "pdf",
+            FileKind::Text => "txt",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FileAdapter {
+    sender: Sender<(Request, Option<oneshot::Sender<Response>>)>,
+    in_flight: InFlight,
+    offline_folder: PathBuf,
+}
+
+impl FileAdapter {
+    pub fn new(
+        offline_folder: PathBuf,
+        sender: Sender<(Request, Option<oneshot::Sender<Response>>)>,
+    ) -> Self {
+        info!("[file] Created File Adapter");
+
+        Self {
+            sender,
+            in_flight: InFlight::default(),
+            offline_folder,
+        }
+    }
+}
+
+#[async_trait]
+impl Adapter for FileAdapter {
+    async fn init_download(
+        &mut self,
+        meta: Option<&Meta>,
+        offline: Option<OfflineData>,
+    ) -> Option<OfflineData> {
+        if let Some(offline) = offline {
+            if offline.status == Status::Ready
+                || offline.status.is_error()
+                || local_path(offline.url.as_ref()).is_none()
+            {
+                None
+            } else {
+                Some(offline)
+            }
+        } else if let Some(meta) = meta {
+            let source = local_path(meta.url())?;
+
+            Some(OfflineData {
+                id: meta.id().to_string(),
+                name: meta.name().map(ToOwned::to_owned),
+                url: meta.url().cloned(),
+                file: None,
+                adapter: AdapterType::File,
+                status: Status::Downloading,
+                retry: RetryInfo::default(),
+                source: Some(source),
+            })
+        } else {
+            None
+        }
+    }
+
+    #[instrument(skip(self, offline))]
+    async fn handle_download<'s, 'a>(
+        &'s mut self,
+        meta: Option<&'a Meta>,
+        offline: Option<OfflineData>,
+    ) -> Option<Result<Response, Error>> {
+        let meta = meta?;
+        let source = local_path(meta.url())?;
+
+        if let Some(ref offline) = offline {
+            if offline.status == Status::Ready {
+                return Some(Ok(Response::MetaOfflineStatus(
+                    meta.id().to_string(),
+                    Status::Ready,
+                )));
+            }
+        }
+
+        let mut offline_data = offline.unwrap_or_else(|| OfflineData {
+            id: meta.id().to_string(),
+            name: meta.name().map(ToOwned::to_owned),
+            url: meta.url().cloned(),
+            file: None,
+            adapter: AdapterType::File,
+            status: Status::Downloading,
+            retry: RetryInfo::default(),
+            source: None,
+        });
+        offline_data.adapter = AdapterType::File;
+        offline_data.source = Some(source.clone());
+
+        let sender = self.sender.clone();
+        let offline_folder = self.offline_folder.clone();
+        let id = meta.id().to_string();
+
+        self.in_flight.spawn(id.clone(), async move {
+            match copy_file(&id, &source, &offline_folder).await {
+                Ok(path) => offline_data.ready(path),
+                Err(e) => {
+                    tracing::error!("[file] {:?}: {}", source, e);
+                    offline_data.failed(&e);
+                }
+            }
+
+            if let Err(e) = sender
+                .send((Request::UpdateOffline(offline_data), None))
+                .await
+            {
+                tracing::error!("{}", e);
+            }
+        });
+
+        Some(Ok(Response::Ok))
+    }
+
+    fn cancel_download(&mut self, id: &str) -> bool {
+        self.in_flight.cancel(id)
+    }
+
+    fn cancel_all(&mut self) -> usize {
+        self.in_flight.cancel_all()
+    }
+
+    // Above every adapter that downloads from the web, none of which can
+    // read a `file://` url
+    fn score_meta(&self, meta: &Meta) -> usize {
+        if local_path(meta.url()).is_some() {
+            20
+        } else {
+            0
+        }
+    }
+
+    fn adt_type(&self) -> AdapterType {
+        AdapterType::File
+    }
+
+    fn extractor(&self) -> Option<Extractor> {
+        Some(extract_file)
+    }
+
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        while !self.in_flight.is_empty() {
+            tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+        }
+
+        info!("Finished shutting down File Adapter");
+
+        Ok(())
+    }
+}
+
+/// The path of a `file://` url.
+pub fn local_path(url: Option<&Url>) -> Option<PathBuf> {
+    url.filter(|url| url.scheme() == "file")?
+        .to_file_path()
+        .ok()
+}
+
+/// What kind of file `path` is, by its extension or else by its first bytes.
+/// `None` for binary files, which are kept but can't be indexed.
+pub fn detect(path: &Path) -> Option<FileKind> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase());
+
+    match extension.as_deref() {
+        Some("pdf") => return Some(FileKind::Pdf),
+        Some(e) if HTML_EXTENSIONS.contains(&e) => return Some(FileKind::Html),
+        Some(e) if TEXT_EXTENSIONS.contains(&e) => return Some(FileKind::Text),
+        _ => {}
+    }
+
+    let mut head = Vec::with_capacity(SNIFF_LEN);
+    std::fs::File::open(path)
+        .ok()?
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut head)
+        .ok()?;
+
+    sniff(&head)
+}
+
+fn sniff(head: &[u8]) -> Option<FileKind> {
+    if head.starts_with(b"%PDF-") {
+        return Some(FileKind::Pdf);
+    }
+
+    if head.contains(&0) {
+        return None;
+    }
+
+    // The last character may have been cut in half
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&head[..e.valid_up_to()]).ok()?,
+        Err(_) => return None,
+    };
+
+    let start = text.trim_start().to_lowercase();
+    if start.starts_with("<!doctype html") || start.starts_with("<html") {
+        Some(FileKind::Html)
+    } else {
+        Some(FileKind::Text)
+    }
+}
+
+// Copy `source` into the offline folder, by way of a temporary file so a
+// partial copy never looks finished
+async fn copy_file(id: &str, source: &Path, offline_folder: &Path) -> Result<PathBuf, Error> {
+    info!("[file] copying {:?}", source);
+
+    let metadata = tokio::fs::metadata(source).await?;
+    if !metadata.is_file() {
+        return Err(Error::Custom(format!("{} is not a file", source.display())));
+    }
+
+    let extension = source
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase())
+        .or_else(|| detect(source).map(|kind| kind.extension().to_string()));
+
+    let name = match extension {
+        Some(extension) => format!("{}.{}", id, extension),
+        None => id.to_string(),
+    };
+    let path = offline_folder.join(name);
+    let partial = offline_folder.join(format!("{}.part", id));
+
+    tokio::fs::create_dir_all(offline_folder).await?;
+    tokio::fs::copy(source, &partial).await?;
+    tokio::fs::rename(&partial, &path).await?;
+
+    Ok(path)
+}
+
+fn extract_file(meta: &Meta, offline: &OfflineData) -> Option<Result<IndexDoc, Error>> {
+    let path = offline.file()?;
+
+    match detect(path)? {
+        FileKind::Html => http::extract_html(meta, offline),
+        FileKind::Pdf => pdf::extract_pdf(meta, offline),
+        FileKind::Text => {
+            info!("[file] reading: {}", meta.id());
+
+            let body = match std::fs::read(path) {
+                Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+                Err(e) => return Some(Err(e.into())),
+            };
+
+            Some(Ok(IndexDoc {
+                title: meta.name().map(String::from),
+                body: Some(body),
+                extra: None,
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn sniffs_pdfs_and_html() {
+        assert_eq!(sniff(b"%PDF-1.7\n%\xe2\xe3"), Some(FileKind::Pdf));
+        assert_eq!(sniff(b"\n  <!DOCTYPE html><p>"), Some(FileKind::Html));
+        assert_eq!(sniff(b"<HTML lang=\"en\">"), Some(FileKind::Html));
+    }
+
+    #[test]
+    fn sniffs_text() {
+        assert_eq!(sniff(b"# Notes\n<html> comes later"), Some(FileKind::Text));
+        assert_eq!(sniff(b""), Some(FileKind::Text));
+        // A multi-byte character cut off at the end of the sniffed bytes
+        assert_eq!(sniff(&"caf\u{e9}".as_bytes()[..4]), Some(FileKind::Text));
+    }
+
+    #[test]
+    fn binary_is_not_sniffed() {
+        assert_eq!(sniff(b"\x7fELF\x02\x01\x01\x00"), None);
+        assert_eq!(sniff(b"\xff\xfe not utf-8"), None);
+    }
+
+    #[test]
+    fn detects_by_extension() {
+        // The files don't exist, so nothing is sniffed
+        assert_eq!(detect(Path::new("paper.PDF")), Some(FileKind::Pdf));
+        assert_eq!(detect(Path::new("saved.htm")), Some(FileKind::Html));
+        assert_eq!(detect(Path::new("notes.md")), Some(FileKind::Text));
+        assert_eq!(detect(Path::new("missing.bin")), None);
+    }
+
+    #[test]
+    fn detects_by_content_without_a_known_extension() {
+        let dir = TempDir::new().unwrap();
+        let html = dir.path().join("page");
+        let text = dir.path().join("README");
+        std::fs::write(&html, "<!doctype html><title>Saved</title>").unwrap();
+        std::fs::write(&text, "x".repeat(SNIFF_LEN) + "\0").unwrap();
+
+        assert_eq!(detect(&html), Some(FileKind::Html));
+        // Only the start of the file is looked at
+        assert_eq!(detect(&text), Some(FileKind::Text));
+    }
+
+    #[test]
+    fn only_file_urls_are_local() {
+        let file = Url::parse("file:///home/ada/notes.md").unwrap();
+        let http = Url::parse("https://example.com/notes.md").unwrap();
+
+        assert_eq!(
+            local_path(Some(&file)),
+            Some(PathBuf::from("/home/ada/notes.md"))
+        );
+        assert_eq!(local_path(Some(&http)), None);
+        assert_eq!(local_path(None), None);
+    }
+}
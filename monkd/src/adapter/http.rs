@@ -1,20 +1,16 @@
 use async_channel::Sender;
 use async_trait::async_trait;
 use std::path::PathBuf;
-use std::sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc,
-};
 use tokio::sync::oneshot;
 use url::Url;
 
 use crate::{
-    adapter::{Adapter, AdapterType},
+    adapter::{readability, Adapter, AdapterType, CancelToken, Extractor, InFlight},
     error::Error,
-    index::Index,
+    index::IndexDoc,
     metadata::{
         monolith,
-        offline_store::{OfflineData, Status},
+        offline_store::{OfflineData, RetryInfo, Status},
         Meta,
     },
     Request, Response,
@@ -23,7 +19,7 @@ use crate::{
 #[derive(Debug)]
 pub struct HttpAdapter {
     sender: Sender<(Request, Option<oneshot::Sender<Response>>)>,
-    in_flight: Arc<AtomicUsize>,
+    in_flight: InFlight,
     offline_folder: PathBuf,
 }
 
@@ -36,7 +32,7 @@ impl HttpAdapter {
 
         Self {
             sender,
-            in_flight: Arc::new(AtomicUsize::new(0)),
+            in_flight: InFlight::default(),
             offline_folder,
         }
     }
@@ -70,6 +66,8 @@ impl Adapter for HttpAdapter {
                 file: None,
                 adapter: AdapterType::Http,
                 status: Status::Downloading,
+                retry: RetryInfo::default(),
+                source: None,
             })
         } else {
             None
@@ -106,16 +104,19 @@ impl Adapter for HttpAdapter {
                 file: None,
                 adapter: AdapterType::Http,
                 status: Status::Downloading,
+                retry: RetryInfo::default(),
+                source: None,
             });
 
             let meta = meta.clone();
-            let semaphore = Arc::clone(&self.in_flight);
             let sender = self.sender.clone();
             let offline_folder = self.offline_folder.clone();
+            let failed = offline_data.clone();
+
+            let id = meta.id().to_string();
 
-            tokio::spawn(async move {
-                semaphore.fetch_add(1, Ordering::SeqCst);
-                match download_meta(meta, offline_folder, offline_data).await {
+            self.in_flight.spawn_with_token(id, |token| async move {
+                match download_meta(meta, offline_folder, offline_data, token).await {
                     Ok(new_data) => {
                         tracing::info!("sending updated offline_data: {:?}", new_data);
                         if let Err(e) = sender.send((Request::UpdateOffline(new_data), None)).await
@@ -123,9 +124,14 @@ impl Adapter for HttpAdapter {
                             tracing::error!("{}", e);
                         }
                     }
-                    Err(e) => tracing::error!("{}", e),
+                    Err(e) => {
+                        tracing::error!("{}", e);
+                        // Report the failure so the item's job doesn't stay running
+                        let mut failed = failed;
+                        failed.failed(&e);
+                        let _ = sender.send((Request::UpdateOffline(failed), None)).await;
+                    }
                 }
-                semaphore.fetch_sub(1, Ordering::SeqCst);
             });
 
             Some(Ok(Response::Ok))
@@ -134,6 +140,14 @@ impl Adapter for HttpAdapter {
         }
     }
 
+    fn cancel_download(&mut self, id: &str) -> bool {
+        self.in_flight.cancel(id)
+    }
+
+    fn cancel_all(&mut self) -> usize {
+        self.in_flight.cancel_all()
+    }
+
     fn can_modify(&self, meta: &Meta, offline: Option<&OfflineData>) -> bool {
         if let Some(data) = offline {
             return data.adapter == AdapterType::Youtube;
@@ -141,74 +155,21 @@ impl Adapter for HttpAdapter {
         valid_url(meta.url()) && offline.map(|o| o.file().is_some()).unwrap_or_default()
     }
 
-    async fn handle_index(
-        &mut self,
-        meta: &Meta,
-        offline: Option<&OfflineData>,
-        index: &mut Index,
-    ) -> Option<Result<(), Error>> {
-        use scraper::{Html, Selector};
-
-        tracing::info!("[http] indexing: {}", meta.id());
-
-        let offline = offline?;
-        let path = offline.file()?;
-
-        let data = match std::fs::read_to_string(path) {
-            Ok(s) => s,
-            Err(e) => {
-                tracing::warn!("Could not read file into string {:?}", path);
-                return Some(Err(e.into()));
-            }
-        };
-
-        // At this point there is data to be parsed,
-        // so we delete whatever is in the current index
-        // and re-add the meta item and data.
-        if let Err(e) = index.delete(meta.id()) {
-            return Some(Err(e));
-        }
-
-        tracing::info!("[http] scraping: {}", meta.id());
-
-        let document = Html::parse_document(&data);
-        let p_tag = Selector::parse("body p").unwrap();
-
-        let mut body_data = String::with_capacity(512);
-
-        for paragraph in document.select(&p_tag) {
-            for text in paragraph.text() {
-                body_data.push_str(text);
-            }
-        }
-
-        // TODO: selector for <meta name="description" content="***">
-        let title_selector = Selector::parse("title").unwrap();
-        let title = document
-            .select(&title_selector)
-            .next()
-            .map(|node| node.inner_html());
-
-        tracing::info!("[http] indexing: {}", meta.id());
-
-        Some(
-            index
-                .insert_meta_with_data(meta, title.as_deref(), Some(&body_data), None)
-                .map(|_| ()),
-        )
+    fn extractor(&self) -> Option<Extractor> {
+        Some(extract_html)
     }
 
     async fn shutdown(&mut self) -> Result<(), Error> {
         tracing::info!("Shutting down Http Adapter");
 
-        let in_flight = self.in_flight.load(Ordering::Relaxed);
+        let in_flight = self.in_flight.len();
         if in_flight != 0 {
             tracing::info!("Downloads in flight: {}", in_flight)
         }
 
         loop {
             tokio::time::sleep(tokio::time::Duration::from_millis(2000)).await;
-            if self.in_flight.load(Ordering::Relaxed) == 0 {
+            if self.in_flight.is_empty() {
                 break;
             }
         }
@@ -223,19 +184,18 @@ async fn download_meta(
     meta: Meta,
     offline_folder: PathBuf,
     mut data: OfflineData,
+    token: CancelToken,
 ) -> Result<OfflineData, Error> {
     tracing::info!("[HTTP] download_meta: {:?}", meta.url());
 
-    match tokio::task::spawn_blocking(move || monolith::download_meta(&meta, offline_folder))
-        .await?
-    {
-        Ok(path) => {
-            data.status = Status::Ready;
-            data.file = Some(path);
-        }
-        Err(e) => {
-            data.status = Status::Error(e.to_string());
-        }
+    // Keeps running when the download is cancelled, until it checks the token
+    let download = tokio::task::spawn_blocking(move || {
+        monolith::download_meta(&meta, offline_folder, || token.is_cancelled())
+    });
+
+    match download.await? {
+        Ok(path) => data.ready(path),
+        Err(e) => data.failed(&e),
     }
 
     Ok(data)
@@ -248,3 +208,32 @@ pub fn valid_url(url: Option<&Url>) -> bool {
         false
     }
 }
+
+pub(crate) fn extract_html(meta: &Meta, offline: &OfflineData) -> Option<Result<IndexDoc, Error>> {
+    let path = offline.file()?;
+
+    let data = match std::fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) => {
+            tracing::warn!("Could not read file into string {:?}", path);
+            return Some(Err(e.into()));
+        }
+    };
+
+    tracing::info!("[http] scraping: {}", meta.id());
+
+    let article = readability::extract(&data);
+    let title = article.title.clone().or_else(|| {
+        article
+            .meta
+            .iter()
+            .find(|(key, _)| key == "og:title")
+            .map(|(_, v)| v.clone())
+    });
+
+    Some(Ok(IndexDoc {
+        title,
+        extra: article.meta_text(),
+        body: Some(article.content),
+    }))
+}
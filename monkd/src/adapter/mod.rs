@@ -1,19 +1,30 @@
 #![allow(unused_variables)]
 
+pub mod file;
 pub mod http;
+pub mod note;
+pub mod pdf;
+pub mod readability;
 pub mod youtube;
 
 use crate::error::Error;
-use crate::index::Index;
+use crate::index::IndexDoc;
 use crate::metadata::{offline_store::OfflineData, Meta};
 use crate::Response;
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::task::AbortHandle;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum AdapterType {
     Http,
     Youtube,
+    Pdf,
+    File,
+    Note,
 }
 
 impl Default for AdapterType {
@@ -22,6 +33,117 @@ impl Default for AdapterType {
     }
 }
 
+/// The downloads an adapter has running, by item id. Tasks remove themselves
+/// when they finish, and can be aborted with `cancel`.
+#[derive(Debug, Clone, Default)]
+pub struct InFlight {
+    tasks: Arc<Mutex<HashMap<String, (u64, AbortHandle)>>>,
+    next: Arc<Mutex<u64>>,
+}
+
+impl InFlight {
+    pub fn spawn<F>(&self, id: String, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.spawn_with_token(id, |_| task)
+    }
+
+    /// Like `spawn`, for tasks that hand work to a blocking thread. Aborting
+    /// the task doesn't stop the thread, so it should check the token before
+    /// it writes anything.
+    pub fn spawn_with_token<T, F>(&self, id: String, task: T)
+    where
+        T: FnOnce(CancelToken) -> F,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let generation = {
+            let mut next = self.next.lock().unwrap();
+            *next += 1;
+            *next
+        };
+
+        let task = task(CancelToken {
+            tasks: self.tasks.clone(),
+            id: id.clone(),
+            generation,
+        });
+
+        // Hold the lock until the handle is registered so a task that
+        // finishes right away can't try to remove itself first.
+        let mut tasks = self.tasks.lock().unwrap();
+        let registry = self.tasks.clone();
+        let key = id.clone();
+
+        let handle = tokio::spawn(async move {
+            task.await;
+
+            let mut tasks = registry.lock().unwrap();
+            // A newer download of the same item may have replaced this one
+            if tasks.get(&key).map(|(g, _)| *g) == Some(generation) {
+                tasks.remove(&key);
+            }
+        });
+
+        // The replacement works on the same item, so it finishes the job the
+        // aborted task would have
+        if let Some((_, previous)) = tasks.insert(id, (generation, handle.abort_handle())) {
+            previous.abort();
+        }
+    }
+
+    /// Abort the download of `id`. Returns `false` if none was running.
+    pub fn cancel(&self, id: &str) -> bool {
+        match self.tasks.lock().unwrap().remove(id) {
+            Some((_, handle)) => {
+                handle.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Abort every running download. Returns how many there were.
+    pub fn cancel_all(&self) -> usize {
+        let tasks: Vec<_> = self.tasks.lock().unwrap().drain().collect();
+
+        for (_, (_, handle)) in &tasks {
+            handle.abort();
+        }
+
+        tasks.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tasks.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Whether a download spawned on `InFlight` is still wanted. It isn't once it
+/// was cancelled or replaced by a newer download of the same item.
+#[derive(Debug, Clone)]
+pub struct CancelToken {
+    tasks: Arc<Mutex<HashMap<String, (u64, AbortHandle)>>>,
+    id: String,
+    generation: u64,
+}
+
+impl CancelToken {
+    pub fn is_cancelled(&self) -> bool {
+        let tasks = self.tasks.lock().unwrap();
+        tasks.get(&self.id).map(|(g, _)| *g) != Some(self.generation)
+    }
+}
+
+/// Pulls the searchable text out of an item's offline copy. Extractors run
+/// on the indexing pipeline's blocking threads, so they must not touch the
+/// adapter's state. `None` means there was nothing to extract.
+pub type Extractor = fn(&Meta, &OfflineData) -> Option<Result<IndexDoc, Error>>;
+
 #[async_trait]
 pub trait Adapter: Send {
     // /// Initialize an adapter with the provided configuration string. If `None`
@@ -57,6 +179,18 @@ pub trait Adapter: Send {
         None
     }
 
+    /// Abort a running download of the item with `id`. Returns `true` if
+    /// this adapter was downloading it.
+    fn cancel_download(&mut self, id: &str) -> bool {
+        false
+    }
+
+    /// Abort every running download, for a forced shutdown. Returns how
+    /// many were aborted.
+    fn cancel_all(&mut self) -> usize {
+        0
+    }
+
     // Give a meta and an offline data, can an adapter handle
     // modifying the off line store for that meta data
     fn can_modify(&self, meta: &Meta, offline: Option<&OfflineData>) -> bool {
@@ -79,12 +213,7 @@ pub trait Adapter: Send {
         AdapterType::Http
     }
 
-    async fn handle_index(
-        &mut self,
-        meta: &Meta,
-        offline: Option<&OfflineData>,
-        index: &mut Index,
-    ) -> Option<Result<(), Error>> {
+    fn extractor(&self) -> Option<Extractor> {
         None
     }
 
@@ -92,3 +221,47 @@ pub trait Adapter: Send {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot;
+
+    // Spawn a download of `id` that never finishes, and return its token
+    async fn spawn(in_flight: &InFlight, id: &str) -> CancelToken {
+        let (send, recv) = oneshot::channel();
+
+        in_flight.spawn_with_token(id.to_string(), |token| async move {
+            let _ = send.send(token);
+            futures::future::pending::<()>().await;
+        });
+
+        recv.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn cancel_trips_the_token() {
+        let in_flight = InFlight::default();
+        let token = spawn(&in_flight, "a1").await;
+        let other = spawn(&in_flight, "b1").await;
+
+        assert!(!token.is_cancelled());
+        assert!(in_flight.cancel("a1"));
+        assert!(token.is_cancelled());
+        assert!(!other.is_cancelled());
+
+        assert_eq!(in_flight.cancel_all(), 1);
+        assert!(other.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn a_newer_download_cancels_the_old_one() {
+        let in_flight = InFlight::default();
+        let old = spawn(&in_flight, "a1").await;
+        let new = spawn(&in_flight, "a1").await;
+
+        assert!(old.is_cancelled());
+        assert!(!new.is_cancelled());
+        assert_eq!(in_flight.len(), 1);
+    }
+}
@@ -0,0 +1,109 @@
+// Notes written in monk itself.
+//
+// A note is an item without a url whose markdown body lives in the offline
+// folder as `<id>.md`. Notes are created with `Request::AddNote` and changed
+// with `Request::SetNote`, which write the file and index it again, so the
+// note is always "downloaded" and opens like any other item. The body is
+// indexed as is, markdown and all.
+
+use async_trait::async_trait;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+use crate::{
+    adapter::{Adapter, AdapterType, Extractor},
+    error::Error,
+    index::IndexDoc,
+    metadata::{
+        offline_store::{OfflineData, RetryInfo, Status},
+        Meta,
+    },
+};
+
+// Longest name taken from a note's first line
+const MAX_TITLE_LEN: usize = 80;
+
+#[derive(Debug, Default)]
+pub struct NoteAdapter;
+
+#[async_trait]
+impl Adapter for NoteAdapter {
+    fn adt_type(&self) -> AdapterType {
+        AdapterType::Note
+    }
+
+    fn extractor(&self) -> Option<Extractor> {
+        Some(extract_note)
+    }
+}
+
+/// Write the body of the note `meta` to the offline folder, returning its
+/// offline data.
+pub fn write(offline_folder: &Path, meta: &Meta, body: &str) -> Result<OfflineData, Error> {
+    std::fs::create_dir_all(offline_folder)?;
+
+    let path = path(offline_folder, meta.id());
+    let partial = path.with_extension("md.part");
+
+    // Written to the side and renamed into place, so a crash can't leave
+    // half a note
+    std::fs::write(&partial, body)?;
+    std::fs::rename(&partial, &path)?;
+
+    info!("[note] wrote {:?}", path);
+
+    Ok(OfflineData {
+        id: meta.id().to_string(),
+        name: meta.name().map(ToOwned::to_owned),
+        url: None,
+        file: Some(path),
+        adapter: AdapterType::Note,
+        status: Status::Ready,
+        retry: RetryInfo::default(),
+        source: None,
+    })
+}
+
+/// The body of a note.
+pub fn read(offline: &OfflineData) -> Result<String, Error> {
+    let path = offline
+        .file()
+        .ok_or_else(|| Error::Custom(format!("`{}` has no note file", offline.id())))?;
+
+    Ok(std::fs::read_to_string(path)?)
+}
+
+pub fn path(offline_folder: &Path, id: &str) -> PathBuf {
+    offline_folder.join(format!("{}.md", id))
+}
+
+/// A name for a note: its first line that isn't blank, without the `#` of a
+/// markdown heading.
+pub fn title(body: &str) -> Option<String> {
+    let line = body
+        .lines()
+        .map(|line| line.trim().trim_start_matches('#').trim())
+        .find(|line| !line.is_empty())?;
+
+    let mut title: String = line.chars().take(MAX_TITLE_LEN).collect();
+    if title.len() < line.len() {
+        title.push('…');
+    }
+
+    Some(title)
+}
+
+fn extract_note(meta: &Meta, offline: &OfflineData) -> Option<Result<IndexDoc, Error>> {
+    info!("[note] reading: {}", meta.id());
+
+    let body = match read(offline) {
+        Ok(body) => body,
+        Err(e) => return Some(Err(e)),
+    };
+
+    Some(Ok(IndexDoc {
+        title: meta.name().map(String::from).or_else(|| title(&body)),
+        body: Some(body),
+        extra: None,
+    }))
+}
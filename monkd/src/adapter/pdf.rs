@@ -0,0 +1,588 @@
+// PDFs, saved as is and indexed by their text.
+//
+// Items whose url ends in `.pdf`, or whose page turned out to be served as
+// `application/pdf`, are downloaded to `<id>.pdf` in the offline folder
+// instead of going through monolith. The text of every page is indexed as the
+// body, and the document's own title and author as the title and extra
+// fields.
+
+use async_channel::Sender;
+use async_trait::async_trait;
+use lopdf::{Document, Object};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::oneshot;
+use tracing::{info, instrument};
+use url::Url;
+
+use crate::{
+    adapter::{Adapter, AdapterType, Extractor, InFlight},
+    error::Error,
+    index::IndexDoc,
+    metadata::{
+        monolith::DEFAULT_USER_AGENT,
+        offline_store::{OfflineData, RetryInfo, Status},
+        Meta,
+    },
+    Request, Response,
+};
+
+pub const PDF_CONTENT_TYPE: &str = "application/pdf";
+
+// For the whole download, so a stalled server doesn't hold the item forever
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+// How much of the file is read before deciding whether it's a PDF. Readers
+// accept the header anywhere in the first KiB.
+const HEADER_WINDOW: usize = 1024;
+
+#[derive(Debug)]
+pub struct PdfAdapter {
+    sender: Sender<(Request, Option<oneshot::Sender<Response>>)>,
+    in_flight: InFlight,
+    offline_folder: PathBuf,
+}
+
+impl PdfAdapter {
+    pub fn new(
+        offline_folder: PathBuf,
+        sender: Sender<(Request, Option<oneshot::Sender<Response>>)>,
+    ) -> Self {
+        info!("[pdf] Created PDF Adapter");
+
+        Self {
+            sender,
+            in_flight: InFlight::default(),
+            offline_folder,
+        }
+    }
+}
+
+#[async_trait]
+impl Adapter for PdfAdapter {
+    async fn init_download(
+        &mut self,
+        meta: Option<&Meta>,
+        offline: Option<OfflineData>,
+    ) -> Option<OfflineData> {
+        if let Some(offline) = offline {
+            if offline.status == Status::Ready || offline.status.is_error() || offline.url.is_none()
+            {
+                None
+            } else {
+                Some(offline)
+            }
+        } else if let Some(meta) = meta {
+            if !is_pdf(meta) {
+                return None;
+            }
+
+            Some(OfflineData {
+                id: meta.id().to_string(),
+                name: meta.name().map(ToOwned::to_owned),
+                url: meta.url().cloned(),
+                file: None,
+                adapter: AdapterType::Pdf,
+                status: Status::Downloading,
+                retry: RetryInfo::default(),
+                source: None,
+            })
+        } else {
+            None
+        }
+    }
+
+    #[instrument(skip(self, offline))]
+    async fn handle_download<'s, 'a>(
+        &'s mut self,
+        meta: Option<&'a Meta>,
+        offline: Option<OfflineData>,
+    ) -> Option<Result<Response, Error>> {
+        let meta = meta?;
+        let url = meta.url()?.clone();
+
+        if let Some(ref offline) = offline {
+            if offline.status == Status::Ready {
+                return Some(Ok(Response::MetaOfflineStatus(
+                    meta.id().to_string(),
+                    Status::Ready,
+                )));
+            }
+        }
+
+        let mut offline_data = offline.unwrap_or_else(|| OfflineData {
+            id: meta.id().to_string(),
+            name: meta.name().map(ToOwned::to_owned),
+            url: Some(url.clone()),
+            file: None,
+            adapter: AdapterType::Pdf,
+            status: Status::Downloading,
+            retry: RetryInfo::default(),
+            source: None,
+        });
+        // An earlier attempt may have gone through another adapter
+        offline_data.adapter = AdapterType::Pdf;
+
+        let sender = self.sender.clone();
+        let path = self.offline_folder.join(format!("{}.pdf", meta.id()));
+        let id = meta.id().to_string();
+
+        self.in_flight.spawn(id, async move {
+            match download_pdf(&url, &path).await {
+                Ok(()) => offline_data.ready(path),
+                Err(e) => {
+                    tracing::error!("[pdf] {}: {}", url, e);
+                    offline_data.failed(&e);
+                }
+            }
+
+            if let Err(e) = sender
+                .send((Request::UpdateOffline(offline_data), None))
+                .await
+            {
+                tracing::error!("{}", e);
+            }
+        });
+
+        Some(Ok(Response::Ok))
+    }
+
+    fn cancel_download(&mut self, id: &str) -> bool {
+        self.in_flight.cancel(id)
+    }
+
+    fn cancel_all(&mut self) -> usize {
+        self.in_flight.cancel_all()
+    }
+
+    fn score_meta(&self, meta: &Meta) -> usize {
+        if is_pdf(meta) {
+            10
+        } else {
+            0
+        }
+    }
+
+    fn adt_type(&self) -> AdapterType {
+        AdapterType::Pdf
+    }
+
+    fn extractor(&self) -> Option<Extractor> {
+        Some(extract_pdf)
+    }
+
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        let in_flight = self.in_flight.len();
+        if in_flight != 0 {
+            info!("Downloads in flight: {}", in_flight)
+        }
+
+        while !self.in_flight.is_empty() {
+            tokio::time::sleep(tokio::time::Duration::from_millis(2000)).await;
+        }
+
+        info!("Finished shutting down PDF Adapter");
+
+        Ok(())
+    }
+}
+
+/// Whether the item's url points to a PDF, going by its extension or by the
+/// content type it was served with. Local PDFs are left to the file adapter.
+pub fn is_pdf(meta: &Meta) -> bool {
+    let url = match meta.url() {
+        Some(url) if matches!(url.scheme(), "http" | "https") => url,
+        _ => return false,
+    };
+
+    let served_as_pdf = meta.page().content_type.as_deref() == Some(PDF_CONTENT_TYPE);
+
+    served_as_pdf || has_pdf_extension(url)
+}
+
+fn has_pdf_extension(url: &Url) -> bool {
+    url.path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .map(|name| name.to_lowercase().ends_with(".pdf"))
+        .unwrap_or(false)
+}
+
+// Stream the file to `<path>.part` and move it into place once complete, so
+// an interrupted download never looks finished
+async fn download_pdf(url: &Url, path: &Path) -> Result<(), Error> {
+    info!("[pdf] downloading {} to {:?}", url, path);
+
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    let client = reqwest::Client::builder()
+        .timeout(DOWNLOAD_TIMEOUT)
+        .user_agent(DEFAULT_USER_AGENT)
+        .build()?;
+    let mut response = client.get(url.clone()).send().await?.error_for_status()?;
+
+    // Chunks can be any size, so the start of the file is collected before
+    // it's checked
+    let mut head = Vec::new();
+    while head.len() < HEADER_WINDOW {
+        match response.chunk().await? {
+            Some(chunk) => head.extend_from_slice(&chunk),
+            None => break,
+        }
+    }
+
+    if head.is_empty() {
+        return Err(Error::Pdf(format!("{} is empty", url)));
+    }
+
+    // Sites sometimes answer with a login or error page instead
+    if !starts_like_pdf(&head) {
+        return Err(Error::Pdf(format!("{} is not a PDF", url)));
+    }
+
+    let partial = path.with_extension("pdf.part");
+    let mut file = tokio::fs::File::create(&partial).await?;
+
+    let written = async {
+        file.write_all(&head).await?;
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+
+        Ok::<_, Error>(())
+    }
+    .await;
+    drop(file);
+
+    if let Err(e) = written {
+        let _ = tokio::fs::remove_file(&partial).await;
+        return Err(e);
+    }
+
+    tokio::fs::rename(&partial, path).await?;
+
+    Ok(())
+}
+
+// Whether `head` begins with the `%PDF-` header, allowing for a byte order
+// mark or whitespace before it
+fn starts_like_pdf(head: &[u8]) -> bool {
+    let head = head.strip_prefix(b"\xef\xbb\xbf").unwrap_or(head);
+    let start = head
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(head.len());
+
+    head[start..].starts_with(b"%PDF-")
+}
+
+pub(crate) fn extract_pdf(meta: &Meta, offline: &OfflineData) -> Option<Result<IndexDoc, Error>> {
+    let path = offline.file()?;
+
+    info!("[pdf] extracting: {}", meta.id());
+
+    let mut document = match Document::load(path) {
+        Ok(document) => document,
+        Err(e) => {
+            tracing::warn!("Could not read PDF {:?}", path);
+            return Some(Err(e.into()));
+        }
+    };
+
+    // Documents that only restrict editing or printing open without a
+    // password
+    if document.is_encrypted() {
+        if let Err(e) = document.decrypt("") {
+            return Some(Err(e.into()));
+        }
+    }
+
+    // Pages that can't be read are left out rather than failing the whole
+    // document
+    let pages: Vec<String> = document
+        .get_pages()
+        .keys()
+        .filter_map(|page| match document.extract_text(&[*page]) {
+            Ok(text) => Some(text.trim().to_string()),
+            Err(e) => {
+                tracing::debug!("[pdf] {}: page {}: {}", meta.id(), page, e);
+                None
+            }
+        })
+        .filter(|text| !text.is_empty())
+        .collect();
+
+    let title = info(&document, b"Title").or_else(|| meta.name().map(String::from));
+
+    let extra: Vec<String> = [&b"Author"[..], b"Subject", b"Keywords"]
+        .iter()
+        .filter_map(|key| info(&document, key))
+        .collect();
+
+    Some(Ok(IndexDoc {
+        title,
+        body: Some(pages.join("\n\n")),
+        extra: if extra.is_empty() {
+            None
+        } else {
+            Some(extra.join("\n"))
+        },
+    }))
+}
+
+// A text entry of the document information dictionary
+fn info(document: &Document, key: &[u8]) -> Option<String> {
+    let info = document.trailer.get(b"Info").ok()?;
+    let (_, info) = document.dereference(info).ok()?;
+    let (_, value) = document
+        .dereference(info.as_dict().ok()?.get(key).ok()?)
+        .ok()?;
+
+    match value {
+        Object::String(bytes, _) => Some(text_string(bytes)).filter(|text| !text.is_empty()),
+        _ => None,
+    }
+}
+
+// PDF text strings are UTF-16 with a byte order mark, or PDFDocEncoding,
+// which matches Latin-1 for the characters that matter here
+fn text_string(bytes: &[u8]) -> String {
+    let text = match bytes {
+        [0xfe, 0xff, rest @ ..] => {
+            let units: Vec<u16> = rest
+                .chunks_exact(2)
+                .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => bytes.iter().map(|&b| b as char).collect(),
+    };
+
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lopdf::content::{Content, Operation};
+    use lopdf::{dictionary, Dictionary, Stream, StringFormat};
+    use tempfile::TempDir;
+
+    // A document with one page per entry of `pages`, each a single line
+    fn write_pdf(path: &Path, pages: &[&str], info: Option<Dictionary>) {
+        let mut document = Document::with_version("1.5");
+        let pages_id = document.new_object_id();
+        let font_id = document.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "Type1",
+            "BaseFont" => "Helvetica",
+            "Encoding" => "WinAnsiEncoding",
+        });
+        let resources_id = document.add_object(dictionary! {
+            "Font" => dictionary! { "F1" => font_id },
+        });
+
+        let kids: Vec<Object> = pages
+            .iter()
+            .map(|text| {
+                let content = Content {
+                    operations: vec![
+                        Operation::new("BT", vec![]),
+                        Operation::new("Tf", vec!["F1".into(), 12.into()]),
+                        Operation::new("Td", vec![72.into(), 720.into()]),
+                        Operation::new("Tj", vec![Object::string_literal(*text)]),
+                        Operation::new("ET", vec![]),
+                    ],
+                };
+                let content_id =
+                    document.add_object(Stream::new(dictionary! {}, content.encode().unwrap()));
+
+                document
+                    .add_object(dictionary! {
+                        "Type" => "Page",
+                        "Parent" => pages_id,
+                        "Contents" => content_id,
+                    })
+                    .into()
+            })
+            .collect();
+
+        document.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Count" => kids.len() as i64,
+                "Kids" => kids,
+                "Resources" => resources_id,
+                "MediaBox" => vec![0.into(), 0.into(), 612.into(), 792.into()],
+            }),
+        );
+        let catalog_id = document.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        document.trailer.set("Root", catalog_id);
+
+        if let Some(info) = info {
+            let info_id = document.add_object(info);
+            document.trailer.set("Info", info_id);
+        }
+
+        document.save(path).unwrap();
+    }
+
+    fn utf16(text: &str) -> Object {
+        let mut bytes = vec![0xfe, 0xff];
+        for unit in text.encode_utf16() {
+            bytes.extend_from_slice(&unit.to_be_bytes());
+        }
+
+        Object::String(bytes, StringFormat::Hexadecimal)
+    }
+
+    fn extract(path: &Path, name: &str) -> IndexDoc {
+        let meta = Meta::builder().id("a1".to_string()).name(name).build();
+        let offline = OfflineData {
+            file: Some(path.to_path_buf()),
+            ..OfflineData::default()
+        };
+
+        extract_pdf(&meta, &offline).unwrap().unwrap()
+    }
+
+    #[test]
+    fn indexes_every_page() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("a1.pdf");
+        write_pdf(&path, &["First page", " ", "Third page"], None);
+
+        let doc = extract(&path, "paper");
+
+        // The blank page is left out
+        assert_eq!(doc.body.as_deref(), Some("First page\n\nThird page"));
+        // No document information, so the item's name is used
+        assert_eq!(doc.title.as_deref(), Some("paper"));
+        assert_eq!(doc.extra, None);
+    }
+
+    #[test]
+    fn reads_the_document_information() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("a1.pdf");
+        let info = dictionary! {
+            "Title" => utf16("Ünïcode  Title"),
+            "Author" => Object::string_literal("Ada Lovelace"),
+            "Keywords" => Object::string_literal("engines, notes"),
+        };
+        write_pdf(&path, &["Body"], Some(info));
+
+        let doc = extract(&path, "paper");
+
+        assert_eq!(doc.title.as_deref(), Some("Ünïcode Title"));
+        assert_eq!(doc.extra.as_deref(), Some("Ada Lovelace\nengines, notes"));
+    }
+
+    #[test]
+    fn unreadable_file_is_an_error() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("a1.pdf");
+        std::fs::write(&path, "<html>not a pdf</html>").unwrap();
+
+        let meta = Meta::builder().id("a1".to_string()).build();
+        let offline = OfflineData {
+            file: Some(path),
+            ..OfflineData::default()
+        };
+
+        assert!(matches!(extract_pdf(&meta, &offline), Some(Err(_))));
+    }
+
+    #[test]
+    fn decodes_text_strings() {
+        assert_eq!(text_string(b"\xfe\xff\x00R\x00u\x00s\x00t"), "Rust");
+        assert_eq!(text_string(b"Caf\xe9  au\nlait"), "Café au lait");
+    }
+
+    #[test]
+    fn pdf_urls() {
+        let item = |url: &str, content_type: Option<&str>| {
+            let mut meta = Meta::builder()
+                .id("a1".to_string())
+                .url(Url::parse(url).unwrap())
+                .build();
+            meta.page.content_type = content_type.map(String::from);
+            meta
+        };
+
+        assert!(is_pdf(&item("https://example.com/paper.PDF", None)));
+        assert!(is_pdf(&item(
+            "https://example.com/download?id=1",
+            Some(PDF_CONTENT_TYPE)
+        )));
+        assert!(!is_pdf(&item("https://example.com/paper.html", None)));
+        // Left to the file adapter
+        assert!(!is_pdf(&item("file:///home/ada/paper.pdf", None)));
+    }
+
+    #[test]
+    fn pdf_header() {
+        assert!(starts_like_pdf(b"%PDF-1.7\n"));
+        assert!(starts_like_pdf(b"\xef\xbb\xbf%PDF-1.4"));
+        assert!(starts_like_pdf(b"\r\n  %PDF-1.4"));
+
+        assert!(!starts_like_pdf(b"<!DOCTYPE html><p>%PDF-</p>"));
+        assert!(!starts_like_pdf(b"%PD"));
+        assert!(!starts_like_pdf(b""));
+    }
+
+    // Serve `chunks` as the body of every request, one at a time
+    async fn serve(chunks: &'static [&'static [u8]]) -> Url {
+        use warp::Filter;
+
+        let route = warp::any().map(move || {
+            let body = futures::stream::iter(
+                chunks
+                    .iter()
+                    .map(|chunk| Ok::<_, std::io::Error>(chunk.to_vec())),
+            );
+            warp::http::Response::new(warp::hyper::Body::wrap_stream(body))
+        });
+
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        Url::parse(&format!("http://{}/paper.pdf", address)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn header_split_across_chunks() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("a1.pdf");
+        let url = serve(&[b"\xef\xbb", b"\xbf%P", b"DF-1.7\n", b"%%EOF\n"]).await;
+
+        download_pdf(&url, &path).await.unwrap();
+
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            b"\xef\xbb\xbf%PDF-1.7\n%%EOF\n"
+        );
+        assert!(!path.with_extension("pdf.part").exists());
+    }
+
+    #[tokio::test]
+    async fn pages_are_not_saved_as_pdfs() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("a1.pdf");
+
+        let url = serve(&[b"<!DOCTYPE html>", b"<p>Please sign in</p>"]).await;
+        assert!(download_pdf(&url, &path).await.is_err());
+
+        let url = serve(&[]).await;
+        assert!(download_pdf(&url, &path).await.is_err());
+
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
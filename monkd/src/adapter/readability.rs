@@ -0,0 +1,587 @@
+// Main content extraction for saved web pages.
+//
+// A simplified version of the algorithm behind Firefox's reader view. Every
+// paragraph-like element with enough text scores points for its parent and
+// grandparent, so the element that contains most of the article's text ends
+// up with the highest score. Navigation, sidebars, comments and other
+// boilerplate are skipped by tag name and by their `class` and `id`, and
+// elements made up mostly of links are penalised. The winner, and any
+// siblings that scored nearly as well, are turned back into text with
+// paragraph boundaries kept.
+
+use ego_tree::{NodeId, NodeRef};
+use scraper::{ElementRef, Html, Node, Selector};
+use std::collections::HashMap;
+
+/// The parts of a page worth indexing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Article {
+    pub title: Option<String>,
+    /// The main content, with paragraphs separated by blank lines
+    pub content: String,
+    /// `<meta name="description">` and the page's `og:` tags
+    pub meta: Vec<(String, String)>,
+}
+
+impl Article {
+    /// The description and `og:` tags, one value per line, without repeats.
+    pub fn meta_text(&self) -> Option<String> {
+        let mut values: Vec<&str> = Vec::new();
+
+        for (_, value) in &self.meta {
+            if !values.contains(&value.as_str()) {
+                values.push(value);
+            }
+        }
+
+        if values.is_empty() {
+            None
+        } else {
+            Some(values.join("\n"))
+        }
+    }
+}
+
+// Never part of the content
+const SKIPPED_TAGS: &[&str] = &[
+    "script", "style", "noscript", "template", "nav", "header", "footer", "aside", "form",
+    "button", "input", "select", "textarea", "iframe", "svg", "canvas", "object", "embed",
+    "dialog", "menu",
+];
+
+// Elements whose text scores points for the elements around them
+const SCORED_TAGS: &[&str] = &[
+    "p",
+    "pre",
+    "td",
+    "li",
+    "blockquote",
+    "dd",
+    "dt",
+    "figcaption",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+];
+
+// Separated from what's around them by a blank line
+const BLOCK_TAGS: &[&str] = &[
+    "address",
+    "article",
+    "blockquote",
+    "dd",
+    "details",
+    "div",
+    "dl",
+    "dt",
+    "figcaption",
+    "figure",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "main",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "summary",
+    "table",
+    "ul",
+];
+
+// Start on a new line
+const LINE_TAGS: &[&str] = &["br", "li", "tr"];
+
+// Parts of `class` or `id` that mark boilerplate or content
+const NEGATIVE: &[&str] = &[
+    "ad-",
+    "ads",
+    "advert",
+    "banner",
+    "breadcrumb",
+    "comment",
+    "cookie",
+    "footer",
+    "masthead",
+    "menu",
+    "modal",
+    "nav",
+    "newsletter",
+    "popup",
+    "promo",
+    "related",
+    "share",
+    "sidebar",
+    "social",
+    "sponsor",
+    "subscribe",
+    "toolbar",
+    "widget",
+];
+const POSITIVE: &[&str] = &[
+    "article", "body", "content", "entry", "main", "page", "post", "story", "text",
+];
+
+// Paragraphs shorter than this don't count
+const MIN_TEXT_LEN: usize = 25;
+
+/// Find the title, main content and description of an HTML page.
+pub fn extract(html: &str) -> Article {
+    let document = Html::parse_document(html);
+
+    Article {
+        title: title(&document),
+        content: content(&document),
+        meta: meta(&document),
+    }
+}
+
+fn title(document: &Html) -> Option<String> {
+    let selector = Selector::parse("title").unwrap();
+
+    document
+        .select(&selector)
+        .next()
+        .map(|title| normalize(&title.text().collect::<String>()))
+        .filter(|title| !title.is_empty())
+}
+
+fn meta(document: &Html) -> Vec<(String, String)> {
+    let selector = Selector::parse("meta[content]").unwrap();
+    let mut meta = Vec::new();
+
+    for element in document.select(&selector) {
+        let element = element.value();
+        let key = element
+            .attr("property")
+            .or_else(|| element.attr("name"))
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase();
+
+        if key == "description" || key.starts_with("og:") {
+            let value = normalize(element.attr("content").unwrap_or_default());
+
+            if !value.is_empty() {
+                meta.push((key, value));
+            }
+        }
+    }
+
+    meta
+}
+
+fn content(document: &Html) -> String {
+    let root = document.root_element();
+    let mut scores: HashMap<NodeId, f64> = HashMap::new();
+
+    score(*root, &mut scores);
+
+    // Link heavy elements are likely lists of links to other pages
+    let weighted = |id: NodeId| {
+        let element = ElementRef::wrap(document.tree.get(id)?)?;
+        Some((element, scores.get(&id)? * (1.0 - link_density(element))))
+    };
+
+    let best = scores
+        .keys()
+        .filter_map(|id| weighted(*id))
+        .max_by(|(_, a), (_, b)| a.total_cmp(b));
+
+    let mut text = Text::default();
+
+    match best {
+        Some((best, best_score)) => {
+            // Content is sometimes split into sibling elements, e.g. by ads
+            let threshold = (best_score * 0.2).max(10.0);
+            let siblings = best
+                .parent()
+                .into_iter()
+                .flat_map(|parent| parent.children());
+
+            for sibling in siblings {
+                let related = sibling.id() == best.id()
+                    || weighted(sibling.id())
+                        .map(|(_, score)| score >= threshold)
+                        .unwrap_or(false)
+                    || is_paragraph(sibling);
+
+                if related {
+                    text.block();
+                    render(sibling, &mut text);
+                }
+            }
+        }
+        // Nothing scored, e.g. a page without paragraphs
+        None => {
+            let fallback = Selector::parse("article, main, body").unwrap();
+            if let Some(element) = document.select(&fallback).next() {
+                render(*element, &mut text);
+            }
+        }
+    }
+
+    text.finish()
+}
+
+// Score the paragraphs under `node` and give their points to the elements
+// containing them
+fn score(node: NodeRef<Node>, scores: &mut HashMap<NodeId, f64>) {
+    let element = match ElementRef::wrap(node) {
+        Some(element) => element,
+        None => return,
+    };
+
+    if is_skipped(element) {
+        return;
+    }
+
+    if SCORED_TAGS.contains(&element.value().name()) {
+        let text = normalize(&element.text().collect::<String>());
+
+        if text.len() >= MIN_TEXT_LEN {
+            // A point for the paragraph, one per comma, and one per 100
+            // characters up to three
+            let points = 1.0 + text.matches(',').count() as f64 + (text.len() / 100).min(3) as f64;
+
+            let ancestors = node.ancestors().filter_map(ElementRef::wrap);
+            for (ancestor, share) in ancestors.zip([1.0, 0.5]) {
+                *scores
+                    .entry(ancestor.id())
+                    .or_insert_with(|| initial_score(ancestor)) += points * share;
+            }
+        }
+    }
+
+    for child in node.children() {
+        score(child, scores);
+    }
+}
+
+fn initial_score(element: ElementRef) -> f64 {
+    let tag = match element.value().name() {
+        "article" | "main" => 10.0,
+        "div" | "section" => 5.0,
+        "pre" | "td" | "blockquote" => 3.0,
+        "address" | "ol" | "ul" | "dl" | "dd" | "dt" | "li" | "form" => -3.0,
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "th" => -5.0,
+        _ => 0.0,
+    };
+
+    tag + class_weight(element)
+}
+
+fn class_weight(element: ElementRef) -> f64 {
+    let names = names(element);
+    let mut weight = 0.0;
+
+    if NEGATIVE.iter().any(|n| names.contains(n)) {
+        weight -= 25.0;
+    }
+    if POSITIVE.iter().any(|p| names.contains(p)) {
+        weight += 25.0;
+    }
+
+    weight
+}
+
+// The element's `class` and `id`
+fn names(element: ElementRef) -> String {
+    let value = element.value();
+    let class = value.attr("class").unwrap_or_default();
+    let id = value.attr("id").unwrap_or_default();
+
+    format!("{} {}", class, id).to_lowercase()
+}
+
+// Boilerplate by tag, or by its `class` or `id` unless those also say it's
+// content
+fn is_skipped(element: ElementRef) -> bool {
+    let value = element.value();
+    if SKIPPED_TAGS.contains(&value.name()) || value.attr("hidden").is_some() {
+        return true;
+    }
+
+    if matches!(value.name(), "html" | "body" | "article" | "main") {
+        return false;
+    }
+
+    let names = names(element);
+    NEGATIVE.iter().any(|n| names.contains(n)) && !POSITIVE.iter().any(|p| names.contains(p))
+}
+
+// How much of the element's text is in links
+fn link_density(element: ElementRef) -> f64 {
+    let selector = Selector::parse("a").unwrap();
+    let total = element.text().map(str::len).sum::<usize>();
+
+    if total == 0 {
+        return 0.0;
+    }
+
+    let links: usize = element
+        .select(&selector)
+        .flat_map(|link| link.text())
+        .map(str::len)
+        .sum();
+
+    links as f64 / total as f64
+}
+
+fn is_paragraph(node: NodeRef<Node>) -> bool {
+    ElementRef::wrap(node)
+        .filter(|element| element.value().name() == "p" && !is_skipped(*element))
+        .map(|p| normalize(&p.text().collect::<String>()).len() >= MIN_TEXT_LEN * 3)
+        .unwrap_or(false)
+}
+
+// Turn `node` back into text, keeping paragraphs and line breaks
+fn render(node: NodeRef<Node>, text: &mut Text) {
+    match node.value() {
+        Node::Text(t) => text.push(t),
+        Node::Element(element) => {
+            let element_ref = ElementRef::wrap(node).unwrap();
+            if is_skipped(element_ref) {
+                return;
+            }
+
+            let name = element.name();
+            let block = BLOCK_TAGS.contains(&name);
+            let line = LINE_TAGS.contains(&name);
+
+            if block {
+                text.block();
+            } else if line {
+                text.line();
+            }
+
+            if name == "pre" {
+                text.preformatted += 1;
+            }
+
+            for child in node.children() {
+                render(child, text);
+            }
+
+            if name == "pre" {
+                text.preformatted -= 1;
+            }
+
+            // Keep table cells apart
+            if matches!(name, "td" | "th") {
+                text.space();
+            }
+
+            if block {
+                text.block();
+            }
+        }
+        _ => {}
+    }
+}
+
+// Builds the text of the content. Whitespace is collapsed like a browser
+// would, except in `<pre>`, and breaks between blocks are only written once
+// there is more text after them.
+#[derive(Debug, Default)]
+struct Text {
+    out: String,
+    // Newlines owed before the next text
+    pending: usize,
+    preformatted: usize,
+}
+
+impl Text {
+    fn push(&mut self, text: &str) {
+        if self.preformatted > 0 {
+            if !text.is_empty() {
+                self.flush();
+                self.out.push_str(text);
+            }
+            return;
+        }
+
+        let mut words = text.split_whitespace().peekable();
+
+        // Only whitespace, which separates the text around it
+        if words.peek().is_none() {
+            if !text.is_empty() {
+                self.space();
+            }
+            return;
+        }
+
+        // Words from different text nodes stay together unless the page
+        // separated them, e.g. "a <b>b</b>" but "a<b>b</b>"
+        if self.pending > 0 {
+            self.flush();
+        } else if text.starts_with(char::is_whitespace) {
+            self.space();
+        }
+
+        for (i, word) in words.enumerate() {
+            if i > 0 {
+                self.out.push(' ');
+            }
+            self.out.push_str(word);
+        }
+
+        if text.ends_with(char::is_whitespace) {
+            self.out.push(' ');
+        }
+    }
+
+    fn space(&mut self) {
+        if !self.out.is_empty() && !self.out.ends_with(char::is_whitespace) {
+            self.out.push(' ');
+        }
+    }
+
+    fn line(&mut self) {
+        self.pending = self.pending.max(1);
+    }
+
+    fn block(&mut self) {
+        self.pending = 2;
+    }
+
+    fn flush(&mut self) {
+        let trimmed = self.out.trim_end_matches(' ').len();
+        self.out.truncate(trimmed);
+
+        if !self.out.is_empty() {
+            for _ in 0..self.pending {
+                self.out.push('\n');
+            }
+        }
+
+        self.pending = 0;
+    }
+
+    fn finish(self) -> String {
+        self.out.trim().to_string()
+    }
+}
+
+// Collapse whitespace into single spaces
+fn normalize(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PARAGRAPH: &str = "Rust is a systems programming language, fast and memory safe, \
+                             without a garbage collector.";
+
+    fn page(body: &str) -> String {
+        format!(
+            "<html><head><title> Rust \n Book </title></head><body>{}</body></html>",
+            body
+        )
+    }
+
+    #[test]
+    fn finds_the_article_among_boilerplate() {
+        let html = page(&format!(
+            r#"<nav><p>{p}</p></nav>
+            <div class="sidebar"><p>{p}</p></div>
+            <div id="story">
+                <h1>Chapter  one</h1>
+                <p>{p}</p>
+                <p>Second   paragraph,
+                   across lines, with enough text to count.</p>
+                <script>var tracking = "not content";</script>
+            </div>
+            <footer><p>Copyright, all rights reserved, do not copy this.</p></footer>"#,
+            p = PARAGRAPH
+        ));
+
+        let article = extract(&html);
+
+        assert_eq!(article.title.as_deref(), Some("Rust Book"));
+        assert_eq!(
+            article.content,
+            format!(
+                "Chapter one\n\n{}\n\nSecond paragraph, across lines, with enough text to count.",
+                PARAGRAPH
+            )
+        );
+    }
+
+    #[test]
+    fn skips_link_lists() {
+        let links: String = (0..10)
+            .map(|i| {
+                format!(
+                    r#"<li><a href="/{i}">Link number {i}, to another page</a></li>"#,
+                    i = i
+                )
+            })
+            .collect();
+        let html = page(&format!(
+            "<div><ul>{}</ul></div><div><p>{}</p></div>",
+            links, PARAGRAPH
+        ));
+
+        assert_eq!(extract(&html).content, PARAGRAPH);
+    }
+
+    #[test]
+    fn keeps_preformatted_text() {
+        let html = page(&format!(
+            "<article><p>{}</p><pre>fn main() {{\n    println!();\n}}</pre></article>",
+            PARAGRAPH
+        ));
+
+        assert_eq!(
+            extract(&html).content,
+            format!("{}\n\nfn main() {{\n    println!();\n}}", PARAGRAPH)
+        );
+    }
+
+    #[test]
+    fn falls_back_to_the_body() {
+        let html = page("<div>Short</div><div>and <b>sweet</b><br>page</div>");
+
+        assert_eq!(extract(&html).content, "Short\n\nand sweet\npage");
+    }
+
+    #[test]
+    fn reads_description_and_open_graph_tags() {
+        let html = r#"<html><head>
+            <meta name="description" content="A book  about Rust">
+            <meta property="og:title" content="The Rust Book">
+            <meta property="og:description" content="A book about Rust">
+            <meta name="keywords" content="rust">
+            <meta property="og:image" content="">
+        </head><body></body></html>"#;
+
+        let article = extract(html);
+
+        assert_eq!(article.title, None);
+        assert_eq!(
+            article.meta,
+            vec![
+                ("description".to_string(), "A book about Rust".to_string()),
+                ("og:title".to_string(), "The Rust Book".to_string()),
+                (
+                    "og:description".to_string(),
+                    "A book about Rust".to_string()
+                ),
+            ]
+        );
+        assert_eq!(
+            article.meta_text().as_deref(),
+            Some("A book about Rust\nThe Rust Book")
+        );
+    }
+}
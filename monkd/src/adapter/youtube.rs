@@ -4,20 +4,16 @@ use std::fs::{create_dir_all, read_dir};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use std::str;
-use std::sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc,
-};
 use tokio::sync::oneshot;
 use tracing::{info, instrument};
 use url::Url;
 
 use crate::{
-    adapter::{Adapter, AdapterType},
+    adapter::{Adapter, AdapterType, Extractor, InFlight},
     error::Error,
-    index::Index,
+    index::IndexDoc,
     metadata::{
-        offline_store::{OfflineData, Status},
+        offline_store::{OfflineData, RetryInfo, Status},
         Meta,
     },
     Request, Response,
@@ -26,7 +22,7 @@ use crate::{
 #[derive(Debug)]
 pub struct YoutubeAdapter {
     sender: Sender<(Request, Option<oneshot::Sender<Response>>)>,
-    in_flight: Arc<AtomicUsize>,
+    in_flight: InFlight,
     offline_folder: PathBuf,
 }
 
@@ -38,7 +34,7 @@ impl YoutubeAdapter {
         info!("[Youtube] Created Youtube Adapter");
         Self {
             sender,
-            in_flight: Arc::new(AtomicUsize::new(0)),
+            in_flight: InFlight::default(),
             offline_folder,
         }
     }
@@ -80,6 +76,8 @@ impl Adapter for YoutubeAdapter {
                 file: None,
                 adapter: AdapterType::Youtube,
                 status: Status::Downloading,
+                retry: RetryInfo::default(),
+                source: None,
             })
         } else {
             None
@@ -113,15 +111,18 @@ impl Adapter for YoutubeAdapter {
                 file: None,
                 adapter: AdapterType::Youtube,
                 status: Status::Downloading,
+                retry: RetryInfo::default(),
+                source: None,
             });
 
             let meta = meta.clone();
-            let semaphore = Arc::clone(&self.in_flight);
             let sender = self.sender.clone();
             let offline_folder = self.offline_folder.clone();
+            let failed = offline_data.clone();
+
+            let id = meta.id().to_string();
 
-            tokio::spawn(async move {
-                semaphore.fetch_add(1, Ordering::SeqCst);
+            self.in_flight.spawn(id, async move {
                 match download_meta(meta, offline_folder, offline_data).await {
                     Ok(new_data) => {
                         info!("sending updated offline_data: {:?}", new_data);
@@ -130,9 +131,14 @@ impl Adapter for YoutubeAdapter {
                             tracing::error!("{}", e);
                         }
                     }
-                    Err(e) => tracing::error!("{}", e),
+                    Err(e) => {
+                        tracing::error!("{}", e);
+                        // Report the failure so the item's job doesn't stay running
+                        let mut failed = failed;
+                        failed.failed(&e);
+                        let _ = sender.send((Request::UpdateOffline(failed), None)).await;
+                    }
                 }
-                semaphore.fetch_sub(1, Ordering::SeqCst);
             });
 
             Some(Ok(Response::Ok))
@@ -141,6 +147,14 @@ impl Adapter for YoutubeAdapter {
         }
     }
 
+    fn cancel_download(&mut self, id: &str) -> bool {
+        self.in_flight.cancel(id)
+    }
+
+    fn cancel_all(&mut self) -> usize {
+        self.in_flight.cancel_all()
+    }
+
     fn can_modify(&self, meta: &Meta, offline: Option<&OfflineData>) -> bool {
         if let Some(data) = offline {
             return data.adapter == AdapterType::Youtube;
@@ -159,49 +173,21 @@ impl Adapter for YoutubeAdapter {
         }
     }
 
-    #[instrument(skip(self, index))]
-    async fn handle_index(
-        &mut self,
-        meta: &Meta,
-        offline: Option<&OfflineData>,
-        index: &mut Index,
-    ) -> Option<Result<(), Error>> {
-        let offline = offline?;
-        // TODO: make this less janky
-        let path = offline.file()?.parent()?.join(meta.id.clone() + ".en.vtt");
-
-        let data = match std::fs::read_to_string(&path) {
-            Ok(s) => s,
-            Err(e) => {
-                tracing::warn!("Could not read file into string {:?}", path);
-                return Some(Err(e.into()));
-            }
-        };
-
-        // At this point there is data to be parsed,
-        // so we delete whatever is in the current index
-        // and re-add the meta item and data.
-        if let Err(e) = index.delete(meta.id()) {
-            return Some(Err(e));
-        }
-
-        Some(
-            index
-                .insert_meta_with_data(meta, meta.name(), Some(&data), None)
-                .map(|_| ()),
-        )
+    #[instrument(skip(self))]
+    fn extractor(&self) -> Option<Extractor> {
+        Some(extract_subtitles)
     }
 
     #[instrument(skip(self))]
     async fn shutdown(&mut self) -> Result<(), Error> {
-        let in_flight = self.in_flight.load(Ordering::Relaxed);
+        let in_flight = self.in_flight.len();
         if in_flight != 0 {
             info!("Downloads in flight: {}", in_flight)
         }
 
         loop {
             tokio::time::sleep(tokio::time::Duration::from_millis(2000)).await;
-            if self.in_flight.load(Ordering::Relaxed) == 0 {
+            if self.in_flight.is_empty() {
                 break;
             }
         }
@@ -224,19 +210,33 @@ async fn download_meta(
 ) -> Result<OfflineData, Error> {
     info!("[Youtube] download_meta: {:?}", meta.url());
 
-    match tokio::task::spawn_blocking(move || download_youtube(&meta, offline_folder)).await? {
-        Ok(path) => {
-            data.status = Status::Ready;
-            data.file = Some(path);
-        }
-        Err(e) => {
-            data.status = Status::Error(e.to_string());
-        }
+    match download_youtube(&meta, offline_folder).await {
+        Ok(path) => data.ready(path),
+        Err(e) => data.failed(&e),
     }
 
     Ok(data)
 }
 
+fn extract_subtitles(meta: &Meta, offline: &OfflineData) -> Option<Result<IndexDoc, Error>> {
+    // TODO: make this less janky
+    let path = offline.file()?.parent()?.join(meta.id.clone() + ".en.vtt");
+
+    let data = match std::fs::read_to_string(&path) {
+        Ok(s) => s,
+        Err(e) => {
+            tracing::warn!("Could not read file into string {:?}", path);
+            return Some(Err(e.into()));
+        }
+    };
+
+    Some(Ok(IndexDoc {
+        title: meta.name().map(String::from),
+        body: Some(data),
+        extra: None,
+    }))
+}
+
 #[instrument(level = "debug")]
 pub fn valid_url(url: Option<&Url>) -> bool {
     if let Some(url) = url {
@@ -252,7 +252,7 @@ pub fn valid_url(url: Option<&Url>) -> bool {
 }
 
 #[instrument(skip(folder))]
-fn download_youtube(meta: &Meta, folder: impl AsRef<Path>) -> Result<PathBuf, Error> {
+async fn download_youtube(meta: &Meta, folder: impl AsRef<Path>) -> Result<PathBuf, Error> {
     let filename = meta.id();
     let folder = folder.as_ref().join(meta.id.clone());
     if read_dir(&folder).is_err() && create_dir_all(&folder).is_err() {
@@ -267,9 +267,13 @@ fn download_youtube(meta: &Meta, folder: impl AsRef<Path>) -> Result<PathBuf, Er
         return Err(Error::Custom("Youtube-dl error".to_string()));
     }
 
-    let mut available_subs = Command::new("youtube-dl");
-    available_subs.arg(url_str).arg("--list-subs");
-    let out = str::from_utf8(available_subs.output()?.stdout.as_slice())
+    // The children are killed if the download is cancelled
+    let mut available_subs = tokio::process::Command::new("youtube-dl");
+    available_subs
+        .arg(url_str)
+        .arg("--list-subs")
+        .kill_on_drop(true);
+    let out = str::from_utf8(available_subs.output().await?.stdout.as_slice())
         .unwrap()
         .to_string();
     let subs_command;
@@ -282,7 +286,7 @@ fn download_youtube(meta: &Meta, folder: impl AsRef<Path>) -> Result<PathBuf, Er
         info!("Video {:?} does not have subs", meta.name);
     }
 
-    match Command::new("youtube-dl")
+    match tokio::process::Command::new("youtube-dl")
         .arg(url_str)
         .arg("--recode-video")
         .arg("mkv")
@@ -290,7 +294,9 @@ fn download_youtube(meta: &Meta, folder: impl AsRef<Path>) -> Result<PathBuf, Er
         .arg("-o")
         .arg(&file_path)
         .stdout(Stdio::null())
+        .kill_on_drop(true)
         .output()
+        .await
     {
         Ok(_) => Ok(folder.join(meta.id.clone() + ".mkv")),
         _ => Err(Error::Custom("Youtube-dl error".to_string())),
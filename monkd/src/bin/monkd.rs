@@ -9,6 +9,10 @@ use monkd::settings::Settings;
 pub struct Args {
     #[structopt(short, long)]
     config: Option<PathBuf>,
+    /// Run as a service: don't exit after `daemon.timeout` ms without a
+    /// request, only when stopped or sent SIGINT or SIGTERM
+    #[structopt(short, long)]
+    foreground: bool,
 }
 
 #[tokio::main]
@@ -31,9 +35,13 @@ async fn main() -> Result<()> {
     tracing::info!("Starting up");
     tracing::info!("{:?}", settings);
 
-    match monkd::run(settings).await {
+    match monkd::run(settings, args.foreground).await {
         Ok(_) => (),
-        Err(e) => tracing::error!("monkd shutting down with fatal error: {:?}", e),
+        Err(e) => {
+            // Also shown when monkd is started by hand, e.g. a second time
+            eprintln!("error: {}", e);
+            tracing::error!("monkd shutting down with fatal error: {:?}", e)
+        }
     }
 
     Ok(())
@@ -1,15 +1,22 @@
-use crate::adapter::{Adapter, AdapterType};
+use crate::adapter::{file, note, Adapter, AdapterType, Extractor};
 use crate::error::Error;
-use crate::index::Index;
+use crate::events::{Event, EventSender};
+use crate::index::{
+    pipeline::{extract, IndexJob},
+    Index, Pipeline,
+};
+use crate::jobs::{Job, JobInfo, JobKind, JobQueue, JobState};
 use crate::metadata::{
     deep_transfer,
     meta::IndexStatus,
-    offline_store::{OfflineStore, Status as OfflineStatus},
-    FileStore, Meta,
+    offline_store::{OfflineData, OfflineStore, RetryInfo, Status as OfflineStatus},
+    page::PageMeta,
+    store, Meta, MetaStore,
 };
 use crate::server::{
     request::{Edit, Request, StatusKind},
     response::{Response, SnippetDef},
+    FEATURES, PROTOCOL_VERSION,
 };
 use crate::settings::Settings;
 use crate::status::*;
@@ -17,17 +24,25 @@ use crate::status::*;
 use anyhow::Result;
 use async_channel::Sender;
 use async_lock::Lock;
-use std::{collections::BTreeSet, path::PathBuf, sync::Arc};
+use chrono::Utc;
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 use tokio::sync::RwLock;
 
 use tracing::{error, info};
 
 pub struct Daemon<'s> {
-    store: Arc<RwLock<FileStore>>,
+    store: Arc<RwLock<Box<dyn MetaStore>>>,
     index: Arc<RwLock<Index>>,
     offline: Arc<RwLock<OfflineStore>>,
+    jobs: Arc<RwLock<JobQueue>>,
     daemon_sender: Sender<(Request, Option<tokio::sync::oneshot::Sender<Response>>)>,
     adapters: Vec<Lock<Box<dyn Adapter>>>,
+    pipeline: Pipeline,
+    events: EventSender,
     settings: &'s Settings,
 }
 
@@ -36,12 +51,21 @@ impl<'s> Daemon<'s> {
         settings: &'s Settings,
         daemon_sender: Sender<(Request, Option<tokio::sync::oneshot::Sender<Response>>)>,
         adapters: Vec<Lock<Box<dyn Adapter>>>,
+        events: EventSender,
     ) -> Result<Self, Error> {
-        let store = Arc::new(RwLock::new(FileStore::read_file(&settings.store().path)?));
-        let index = Arc::new(RwLock::new(Index::new(&settings.index())?));
-        let offline = Arc::new(RwLock::new(OfflineStore::read_file(
-            &settings.offline().store_file,
-        )?));
+        let store = store::open(settings.store())?;
+        let index = Index::new(&settings.index())?;
+        let rebuilding = index.is_rebuilding();
+        let offline = OfflineStore::read_file(&settings.offline().store_file)?;
+        let retries = scheduled_retries(&offline);
+
+        let mut jobs = JobQueue::read_file(&settings.jobs().file)?;
+        let resume = recover_jobs(&mut jobs, store.as_ref(), &offline);
+
+        let store = Arc::new(RwLock::new(store));
+        let index = Arc::new(RwLock::new(index));
+        let offline = Arc::new(RwLock::new(offline));
+        let jobs = Arc::new(RwLock::new(jobs));
 
         let store_clone = store.clone();
         let store_delay = std::time::Duration::from_millis(std::cmp::max(
@@ -52,19 +76,86 @@ impl<'s> Daemon<'s> {
         let offline_clone = offline.clone();
         let offline_delay = store_delay;
 
-        tokio::spawn(async move { FileStore::commit_loop(store_clone, store_delay).await });
+        tokio::spawn(async move { store::commit_loop(store_clone, store_delay).await });
         tokio::spawn(async move { OfflineStore::commit_loop(offline_clone, offline_delay).await });
 
+        let jobs_clone = jobs.clone();
+        tokio::spawn(async move { JobQueue::commit_loop(jobs_clone, store_delay).await });
+
+        let index_clone = index.clone();
+        let index_sender = daemon_sender.clone();
+        let index_delay = settings.index().commit_interval();
+        tokio::spawn(
+            async move { Index::commit_loop(index_clone, index_sender, index_delay).await },
+        );
+
+        let pipeline = Pipeline::spawn(index.clone(), daemon_sender.clone(), settings.index());
+
+        if rebuilding {
+            let store = store.clone();
+            let index = index.clone();
+            let offline = offline.clone();
+            let adapters = adapters.clone();
+
+            tokio::spawn(async move {
+                if let Err(e) = rebuild_index(store, offline, index, adapters).await {
+                    error!("index rebuild failed: {}", e);
+                }
+            });
+        }
+
+        for (id, delay) in retries {
+            schedule_download(daemon_sender.clone(), id, delay);
+        }
+
+        // Dispatch the jobs a previous run didn't get to. They are sent as
+        // requests so they go through the same handlers as new ones.
+        if !resume.is_empty() {
+            info!("resuming {} job(s)", resume.len());
+
+            let sender = daemon_sender.clone();
+            tokio::spawn(async move {
+                for job in resume {
+                    let request = match job.kind {
+                        JobKind::Download => Request::Download { id: Some(job.item) },
+                        JobKind::Index => Request::Index { id: job.item },
+                    };
+
+                    if sender.send((request, None)).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
         Ok(Self {
             store,
             index,
             offline,
+            jobs,
             daemon_sender,
             adapters,
+            pipeline,
+            events,
             settings,
         })
     }
 
+    // Publish an event to everyone listening on `/events`. Nobody listening
+    // is not an error.
+    fn emit(&self, event: Event) {
+        tracing::debug!("[event] {:?}", event);
+        let _ = self.events.send(event);
+    }
+
+    /// Whether there are downloads or index runs that haven't finished.
+    pub async fn has_pending_jobs(&self) -> bool {
+        self.jobs
+            .read()
+            .await
+            .has_pending(self.settings.jobs().stale_after())
+    }
+
     async fn best_adapter_type(&self, meta: &Meta) -> AdapterType {
         let mut max_score = 0;
         // Default adapter is the http addapter
@@ -85,6 +176,23 @@ impl<'s> Daemon<'s> {
         Arc::clone(&self.offline)
     }
 
+    pub fn handle_hello(&self, version: String, protocol: u32) -> Response {
+        if protocol != PROTOCOL_VERSION {
+            tracing::warn!(
+                "[hello] monk {} speaks protocol {}, monkd speaks {}",
+                version,
+                protocol,
+                PROTOCOL_VERSION
+            );
+        }
+
+        Response::Hello {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            protocol: PROTOCOL_VERSION,
+            features: FEATURES.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
     pub async fn handle_status(&self, kind: StatusKind) -> Result<Response, Error> {
         tracing::info!("[status] {:?}", kind);
 
@@ -94,9 +202,9 @@ impl<'s> Daemon<'s> {
                 let offline_store = self.offline.read().await;
                 let index = self.index.read().await;
 
-                let file_store_status = FileStoreStatus::new(&file_store)?;
+                let file_store_status = FileStoreStatus::new(file_store.as_ref())?;
                 let offline_status = OfflineStoreStatus::new(&offline_store)?;
-                let index_status = TIndexStatus::new(&index)?;
+                let index_status = TIndexStatus::new(&index, &self.pipeline)?;
 
                 let status = StatusResponse {
                     meta: None,
@@ -109,7 +217,7 @@ impl<'s> Daemon<'s> {
             }
             StatusKind::Index => {
                 let index = self.index.read().await;
-                let index_status = TIndexStatus::new(&index)?;
+                let index_status = TIndexStatus::new(&index, &self.pipeline)?;
 
                 Ok(Response::Status(StatusResponse {
                     meta: None,
@@ -131,7 +239,7 @@ impl<'s> Daemon<'s> {
             }
             StatusKind::Store => {
                 let store = self.store.read().await;
-                let store_status = FileStoreStatus::new(&store)?;
+                let store_status = FileStoreStatus::new(store.as_ref())?;
 
                 Ok(Response::Status(StatusResponse {
                     meta: None,
@@ -170,6 +278,13 @@ impl<'s> Daemon<'s> {
         info!("[add] {:?} {:?} {:?}", name, url, comment.is_some());
         let mut builder = Meta::builder();
 
+        // Pages are named after their title once they're fetched, local
+        // files after themselves
+        let name = name.or_else(|| {
+            let path = file::local_path(url.as_ref())?;
+            Some(path.file_name()?.to_string_lossy().into_owned())
+        });
+
         if let Some(name) = name {
             builder = builder.name(name);
         }
@@ -202,7 +317,11 @@ impl<'s> Daemon<'s> {
             }
         }
 
-        if self.settings.daemon().download_after_add {
+        let download = self.settings.daemon().download_after_add;
+
+        // Items with a url are downloaded once their page has been fetched,
+        // see `fetch_page`
+        if download && meta.url().is_none() {
             info!("auto downloading: [{}]", meta.id());
 
             let download_req = Request::Download {
@@ -221,56 +340,465 @@ impl<'s> Daemon<'s> {
         // indexing fails.
         let _ = self.index.write().await.insert_meta(&meta);
 
-        self.store.write().await.push(meta.clone());
+        self.store.write().await.push(meta.clone())?;
+
+        self.emit(Event::ItemAdded {
+            id: meta.id().to_string(),
+        });
+
+        if let Some(url) = meta.url() {
+            fetch_page(
+                self.daemon_sender.clone(),
+                meta.id().to_string(),
+                url.clone(),
+                download,
+            );
+        }
 
-        Ok(Response::Item(meta.clone()))
+        Ok(Response::Item(meta))
+    }
+
+    pub async fn handle_add_note(
+        &mut self,
+        name: Option<String>,
+        body: String,
+        tags: Vec<String>,
+    ) -> Result<Response, Error> {
+        info!("[note] add {:?}", name);
+        let mut builder = Meta::builder();
+
+        if let Some(name) = name.or_else(|| note::title(&body)) {
+            builder = builder.name(name);
+        }
+
+        if !tags.is_empty() {
+            builder = builder.tags(tags.into_iter().collect());
+        }
+
+        let meta = builder.build();
+        let id = meta.id().to_string();
+
+        let data = note::write(&self.settings.offline().data_folder, &meta, &body)?;
+        self.offline.write().await.update(&id, data)?;
+        self.store.write().await.push(meta.clone())?;
+
+        self.emit(Event::ItemAdded { id: id.clone() });
+
+        self.handle_index(id).await?;
+
+        Ok(Response::Item(meta))
+    }
+
+    pub async fn handle_get_note(&mut self, id: String) -> Result<Response, Error> {
+        let meta = self.store.read().await.get(&id)?.clone();
+
+        match self.note_data(&meta).await {
+            Some(data) => Ok(Response::Note(meta, note::read(&data)?)),
+            None => Ok(not_a_note(&meta)),
+        }
+    }
+
+    pub async fn handle_set_note(&mut self, id: String, body: String) -> Result<Response, Error> {
+        let mut meta = self.store.read().await.get(&id)?.clone();
+        let id = meta.id().to_string();
+        info!("[note] set {}", id);
+
+        // Only items that are notes have a body to replace
+        if self.note_data(&meta).await.is_none() {
+            return Ok(not_a_note(&meta));
+        }
+
+        let data = note::write(&self.settings.offline().data_folder, &meta, &body)?;
+        self.offline.write().await.update(&id, data)?;
+
+        meta.last_updated = Utc::now();
+        self.store.write().await.update(&id, meta.clone())?;
+
+        self.emit(Event::ItemUpdated { id: id.clone() });
+
+        self.handle_index(id).await?;
+
+        Ok(Response::Item(meta))
+    }
+
+    // The offline data of `meta` if it's a note
+    async fn note_data(&self, meta: &Meta) -> Option<OfflineData> {
+        match self.offline.read().await.get(meta.id()) {
+            Ok(data) if data.adapter == AdapterType::Note => Some(data.clone()),
+            _ => None,
+        }
+    }
+
+    // Mark the item as `Indexing` and pick the extractor of the adapter that
+    // downloaded it, or the best adapter for it if it hasn't been downloaded.
+    async fn index_job(&self, id: &str) -> Result<Option<IndexJob>, Error> {
+        let mut meta = self.store.read().await.get(id)?.clone();
+        let offline = self.offline.read().await.get(meta.id()).ok().cloned();
+
+        let adapter_type = match offline.as_ref() {
+            Some(data) => data.adapter,
+            None => self.best_adapter_type(&meta).await,
+        };
+
+        let extractor = match find_extractor(&self.adapters, adapter_type).await {
+            Some(extractor) => extractor,
+            None => return Ok(None),
+        };
+
+        // Go through `update` rather than `get_mut` so the change is journaled
+        meta.index_status = Some(IndexStatus::Indexing);
+        self.store
+            .write()
+            .await
+            .update(&meta.id().to_string(), meta.clone())?;
+
+        Ok(Some(IndexJob {
+            meta,
+            offline,
+            adapter: adapter_type,
+            extractor,
+        }))
     }
 
     pub async fn handle_index(&mut self, id: String) -> Result<Response, Error> {
-        let mut meta = {
-            let mut store = self.store.write().await;
+        let id = self.store.read().await.get(&id)?.id().to_string();
+
+        // The meta is marked `Indexed` once the index commits it, see
+        // `handle_index_committed`
+        let job = self.jobs.write().await.push(&id, JobKind::Index);
+
+        match self.index_job(&id).await? {
+            Some(index_job) => {
+                self.jobs.write().await.start(&job, index_job.adapter)?;
+
+                // Waiting for room in a full queue would hold up the daemon
+                let submit = self.pipeline.submit(index_job);
+                tokio::spawn(async move {
+                    if let Err(e) = submit.await {
+                        error!("{}", e);
+                    }
+                });
+
+                Ok(Response::Ok)
+            }
+            None => {
+                let state = JobState::Failed("no adapter found".to_string());
+                self.jobs.write().await.set_state(&job, state)?;
+                Ok(Response::NoAdapterFound(id))
+            }
+        }
+    }
+
+    pub async fn handle_index_committed(&mut self, ids: Vec<String>) -> Result<Response, Error> {
+        let mut store = self.store.write().await;
+        let mut jobs = self.jobs.write().await;
+
+        for id in ids {
+            jobs.finish(&id, JobKind::Index, JobState::Done);
+
+            // Deleted items and plain metadata inserts have nothing to update
+            let mut meta = match store.get(&id) {
+                Ok(meta) if meta.index_status == Some(IndexStatus::Indexing) => meta.clone(),
+                _ => continue,
+            };
+
+            tracing::info!("[{}] indexed", meta.id());
+            meta.index_status = Some(IndexStatus::Indexed);
+            store.update(&id, meta)?;
+
+            self.emit(Event::IndexFinished { id });
+        }
+
+        Ok(Response::Ok)
+    }
+
+    pub async fn handle_index_failed(
+        &mut self,
+        id: String,
+        error: String,
+    ) -> Result<Response, Error> {
+        self.jobs
+            .write()
+            .await
+            .finish(&id, JobKind::Index, JobState::Failed(error.clone()));
+
+        self.emit(Event::IndexFailed {
+            id: id.clone(),
+            error,
+        });
+
+        self.reset_indexing(&id).await?;
+
+        Ok(Response::Ok)
+    }
+
+    pub async fn handle_index_cancelled(&mut self, id: String) -> Result<Response, Error> {
+        self.jobs
+            .write()
+            .await
+            .finish(&id, JobKind::Index, JobState::Cancelled);
+
+        self.reset_indexing(&id).await?;
+
+        Ok(Response::Ok)
+    }
+
+    // Clear the `Indexing` status of an item that won't be indexed after all.
+    // The item may have been deleted in the meantime.
+    async fn reset_indexing(&self, id: &str) -> Result<(), Error> {
+        let mut store = self.store.write().await;
+        if let Ok(meta) = store.get(id) {
+            if meta.index_status == Some(IndexStatus::Indexing) {
+                let mut meta = meta.clone();
+                meta.index_status = None;
+                store.update(id, meta)?;
+            }
+        }
 
-            let mut meta = store.get_mut(&id)?;
-            meta.index_status = Some(IndexStatus::Indexing);
+        Ok(())
+    }
+
+    pub async fn handle_update_offline(
+        &mut self,
+        mut data: OfflineData,
+    ) -> Result<Response, Error> {
+        // Transient failures are retried with an increasing delay
+        if data.status.is_error() && data.retry.transient {
+            if let Some(delay) = self.settings.offline().retry().backoff(data.retry.attempts) {
+                info!(
+                    "[{}] download failed {} time(s), retrying in {:?}",
+                    data.id(),
+                    data.retry.attempts,
+                    delay
+                );
+
+                let next =
+                    chrono::Duration::from_std(delay).unwrap_or_else(|_| chrono::Duration::zero());
+                data.retry.next_attempt = Some(Utc::now() + next);
+                schedule_download(self.daemon_sender.clone(), data.id().to_string(), delay);
+            }
+        }
+
+        // Downloads are reported as started by `handle_download`
+        let id = data.id().to_string();
+        let (state, event) = match &data.status {
+            OfflineStatus::Ready => (Some(JobState::Done), Some(Event::DownloadFinished { id })),
+            OfflineStatus::Error(e) => (
+                Some(JobState::Failed(e.clone())),
+                Some(Event::DownloadFailed {
+                    id,
+                    error: e.clone(),
+                }),
+            ),
+            OfflineStatus::Downloading => (None, None),
+        };
+
+        if let Some(state) = state {
+            self.jobs
+                .write()
+                .await
+                .finish(data.id(), JobKind::Download, state);
+        }
+
+        self.offline
+            .write()
+            .await
+            .update(data.id().to_string(), data)?;
+
+        if let Some(event) = event {
+            self.emit(event);
+        }
+
+        Ok(Response::Ok)
+    }
+
+    pub async fn handle_update_meta(&mut self, meta: Meta) -> Result<Response, Error> {
+        let id = meta.id().to_string();
+        let indexed = meta.index_status == Some(IndexStatus::Indexed);
 
-            meta.clone()
+        let mut store = self.store.write().await;
+        let was_indexed = store
+            .get(&id)
+            .map(|m| m.index_status == Some(IndexStatus::Indexed))
+            .unwrap_or(false);
+        store.update(&id, meta)?;
+
+        if indexed && !was_indexed {
+            self.emit(Event::IndexFinished { id });
+        }
+
+        Ok(Response::Ok)
+    }
+
+    pub async fn handle_update_page(
+        &mut self,
+        id: String,
+        url: url::Url,
+        page: PageMeta,
+    ) -> Result<Response, Error> {
+        // The item was deleted, or its url changed while the page was fetched
+        let mut meta = match self.store.read().await.get(&id) {
+            Ok(meta) if meta.url() == Some(&url) => meta.clone(),
+            _ => return Ok(Response::Ok),
         };
+        let id = meta.id().to_string();
 
-        let offline = { self.offline.read().await.get(&id).ok().cloned() };
+        if meta.page == page {
+            return Ok(Response::Ok);
+        }
 
-        let best_adapter = self.best_adapter_type(&meta).await;
+        info!("[{}] page metadata: {:?}", id, page);
 
-        for adapter in &mut self.adapters {
-            let locked_adapter = adapter.lock().await;
-            if locked_adapter.adt_type() != best_adapter {
-                continue;
+        if meta.name.is_none() {
+            meta.name = page.title.clone();
+        }
+        meta.page = page;
+        meta.last_updated = Utc::now();
+
+        self.store.write().await.update(&id, meta.clone())?;
+
+        self.emit(Event::ItemUpdated { id: id.clone() });
+
+        match meta.index_status {
+            // Index the content again so the new fields are searchable
+            Some(IndexStatus::Indexed) | Some(IndexStatus::Old) => self.handle_index(id).await,
+            // The index run already has the old metadata. Rare, since pages
+            // are fetched as soon as the item is added.
+            Some(IndexStatus::Indexing) => Ok(Response::Ok),
+            None => {
+                let mut index = self.index.write().await;
+                index.delete(&id)?;
+                index.insert_meta(&meta)?;
+                Ok(Response::Ok)
             }
-            drop(locked_adapter);
-            let adapter = adapter.clone();
-            let daemon_sender = self.daemon_sender.clone();
-            let index = self.index.clone();
+        }
+    }
 
-            tokio::spawn(async move {
-                let mut index = index.write().await;
-                let mut adapter = adapter.lock().await;
+    pub async fn handle_jobs(&self) -> Result<Response, Error> {
+        let data_folder = &self.settings.offline().data_folder;
 
-                if let Some(result) = adapter
-                    .handle_index(&meta, offline.as_ref(), &mut index)
-                    .await
-                {
-                    // This meta was successfully indexed by this adapter:
-                    if result.is_ok() {
-                        tracing::info!("[{}] indexed", meta.id());
-                        meta.index_status = Some(IndexStatus::Indexed);
-                        let _ = daemon_sender.send((Request::UpdateMeta(meta), None)).await;
+        let jobs = self
+            .jobs
+            .read()
+            .await
+            .jobs()
+            .iter()
+            .filter(|job| job.state.is_pending())
+            .map(|job| {
+                let bytes = match job.kind {
+                    JobKind::Download => Some(download_size(data_folder, &job.item)),
+                    JobKind::Index => None,
+                };
+                JobInfo::new(job, bytes)
+            })
+            .collect();
+
+        Ok(Response::Jobs(jobs))
+    }
+
+    pub async fn handle_cancel_job(&mut self, id: String) -> Result<Response, Error> {
+        let job = self.jobs.read().await.find_id(&id)?.clone();
+
+        if !job.state.is_pending() {
+            return Ok(Response::Error(format!(
+                "job `{}` has already finished",
+                job.id
+            )));
+        }
+
+        info!("[cancel] {:?} job for [{}]", job.kind, job.item);
+
+        match job.kind {
+            JobKind::Download => {
+                for adapter in &self.adapters {
+                    if adapter.lock().await.cancel_download(&job.item) {
+                        break;
                     }
                 }
-            });
 
+                // An aborted download never sends `UpdateOffline`. This also
+                // stops any scheduled retry.
+                let mut offline = self.offline.write().await;
+                if let Ok(data) = offline.get(&job.item) {
+                    if data.status != OfflineStatus::Ready {
+                        let mut data = data.clone();
+                        data.status = OfflineStatus::Error("Download cancelled".to_string());
+                        data.retry.next_attempt = None;
+                        offline.update(job.item.clone(), data)?;
+                    }
+                }
+            }
+            JobKind::Index => {
+                // Otherwise the job was never submitted and nothing will
+                // report back
+                if !self.pipeline.cancel(&job.item) {
+                    self.reset_indexing(&job.item).await?;
+                }
+            }
+        }
+
+        self.jobs
+            .write()
+            .await
+            .set_state(&job.id, JobState::Cancelled)?;
+
+        Ok(Response::Ok)
+    }
+
+    // A retry scheduled by `handle_update_offline`. Skipped if the item was
+    // downloaded, deleted or cancelled in the meantime.
+    pub async fn handle_retry_download(&mut self, id: String) -> Result<Response, Error> {
+        let due = self
+            .offline
+            .read()
+            .await
+            .get(&id)
+            .map(|data| data.status.is_error() && data.retry.next_attempt.is_some())
+            .unwrap_or(false);
+
+        if !due {
             return Ok(Response::Ok);
         }
 
-        Ok(Response::NoAdapterFound(meta.id().to_string()))
+        self.handle_download(Some(id)).await
+    }
+
+    pub async fn handle_retry_failed(&mut self) -> Result<Response, Error> {
+        let ids: Vec<String> = {
+            let mut offline = self.offline.write().await;
+
+            let failed: Vec<OfflineData> = offline
+                .data()
+                .iter()
+                .filter(|d| d.status.is_error())
+                .cloned()
+                .collect();
+
+            // Start counting attempts from scratch
+            for mut data in failed.iter().cloned() {
+                data.retry = RetryInfo::default();
+                offline.update(data.id().to_string(), data)?;
+            }
+
+            failed.into_iter().map(|d| d.id).collect()
+        };
+
+        let count = ids.len();
+
+        for id in ids {
+            let req = Request::Download { id: Some(id) };
+            let _ = self
+                .daemon_sender
+                .send((req, None))
+                .await
+                .map_err(|_| error!("error sending download req"));
+        }
+
+        match count {
+            0 => Ok(Response::Custom("no failed downloads".to_string())),
+            1 => Ok(Response::Custom("retrying 1 item".to_string())),
+            count => Ok(Response::Custom(format!("retrying {} items", count))),
+        }
     }
 
     pub async fn handle_index_status(&self, id: String) -> Result<Response, Error> {
@@ -290,18 +818,48 @@ impl<'s> Daemon<'s> {
             self.store
                 .read()
                 .await
-                .get_union_tags(tags)
+                .get_union_tags(&tags)
                 .iter()
                 .map(|m| m.id().to_string())
                 .collect()
         };
 
+        let mut jobs = Vec::new();
         for id in ids {
-            if let Err(e) = self.handle_index(id).await {
-                response.push(Response::Error(e.to_string()));
-            }
+            let job = self.jobs.write().await.push(&id, JobKind::Index);
+
+            let state = match self.index_job(&id).await {
+                Ok(Some(index_job)) => {
+                    self.jobs.write().await.start(&job, index_job.adapter)?;
+                    jobs.push(index_job);
+                    continue;
+                }
+                Ok(None) => JobState::Failed("no adapter found".to_string()),
+                Err(e) => {
+                    response.push(Response::Error(e.to_string()));
+                    JobState::Failed(e.to_string())
+                }
+            };
+
+            self.jobs.write().await.set_state(&job, state)?;
         }
 
+        // Feed the queue from a task so a full queue doesn't hold up the
+        // daemon. The jobs are submitted here so they can be cancelled
+        // right away.
+        let submits: Vec<_> = jobs
+            .into_iter()
+            .map(|job| self.pipeline.submit(job))
+            .collect();
+        tokio::spawn(async move {
+            for submit in submits {
+                if let Err(e) = submit.await {
+                    error!("{}", e);
+                    break;
+                }
+            }
+        });
+
         if !response.is_empty() {
             Ok(Response::Many(response))
         } else {
@@ -311,12 +869,13 @@ impl<'s> Daemon<'s> {
 
     pub async fn handle_download(&mut self, id: Option<String>) -> Result<Response, Error> {
         if let Some(id) = id {
-            let store = self.store.read().await;
-            let meta = store.get(&id)?;
+            let meta = self.store.read().await.get(&id)?.clone();
+            let id = meta.id().to_string();
+            let job = self.jobs.write().await.push(&id, JobKind::Download);
 
             let best_adapter = self.best_adapter_type(&meta).await;
 
-            for adapter in &mut self.adapters {
+            for adapter in &self.adapters {
                 let mut adapter = adapter.lock().await;
 
                 if adapter.adt_type() != best_adapter {
@@ -335,10 +894,28 @@ impl<'s> Daemon<'s> {
 
                 // If this adapter handled the request use it
                 if let Some(resp) = adapter.handle_download(Some(&meta), data).await {
+                    // A download that was started finishes with `UpdateOffline`
+                    let mut jobs = self.jobs.write().await;
+                    match &resp {
+                        Ok(Response::Ok) => {
+                            jobs.start(&job, best_adapter)?;
+                            restart_download(&mut *self.offline.write().await, &id)?;
+                            self.emit(Event::DownloadStarted { id: id.clone() });
+                        }
+                        Ok(Response::Error(e)) => {
+                            jobs.set_state(&job, JobState::Failed(e.clone()))?
+                        }
+                        Ok(_) => jobs.set_state(&job, JobState::Done)?,
+                        Err(e) => jobs.set_state(&job, JobState::Failed(e.to_string()))?,
+                    }
+
                     return resp;
                 }
             }
 
+            let state = JobState::Failed("no adapter handled the download".to_string());
+            self.jobs.write().await.set_state(&job, state)?;
+
             Ok(Response::Unhandled)
         } else {
             let offline_store = self.offline.read().await;
@@ -376,31 +953,35 @@ impl<'s> Daemon<'s> {
     pub async fn handle_edit(&mut self, id: String, edit: Edit) -> Result<Response, Error> {
         info!("[edit] {:?}", edit);
 
+        let url = match edit.url.as_deref().map(url::Url::parse).transpose() {
+            Ok(url) => url,
+            Err(e) => return Ok(Response::Error(format!("invalid url: {}", e))),
+        };
+
         let _ = self.offline.write().await.edit(&id, &edit);
 
-        if edit.url.is_some() {
-            let req = Request::Download {
-                id: Some(id.to_string()),
-            };
-            let _ = self
-                .daemon_sender
-                .send((req, None))
-                .await
-                .map_err(|_| error!("error sending download req"));
+        //This will respond with an ok or an error
+        let meta = self.store.write().await.edit(&id, &edit)?;
+
+        // The new url may point to a different page entirely
+        if let Some(url) = url {
+            fetch_page(
+                self.daemon_sender.clone(),
+                meta.id().to_string(),
+                url,
+                self.settings.daemon().download_after_add,
+            );
         }
 
-        //This will respond with an ok or an error
-        self.store
-            .write()
-            .await
-            .edit(&id, &edit)
-            .map(Response::Item)
+        Ok(Response::Item(meta))
     }
 
     pub async fn handle_delete(&mut self, id: String) -> Result<Response, Error> {
         info!("[delete] {:?}", id);
-        let id = self.store.read().await.get(id)?.id().to_string();
+        let id = self.store.read().await.get(&id)?.id().to_string();
 
+        self.pipeline.cancel(&id);
+        self.jobs.write().await.remove_item(&id);
         let _ = self.offline.write().await.delete(&id);
         let _ = self.index.write().await.delete(&id);
 
@@ -415,7 +996,7 @@ impl<'s> Daemon<'s> {
     ) -> Result<Response, Error> {
         info!("[list] {:?}, {:?}", count, tags);
         let mut metas: Vec<Meta> = if !tags.is_empty() {
-            self.store.read().await.get_intersection_tags(tags)
+            self.store.read().await.get_intersection_tags(&tags)
         } else {
             self.store.read().await.data().to_vec()
         };
@@ -432,10 +1013,19 @@ impl<'s> Daemon<'s> {
         info!("[get] {:?}", id);
         match self.store.read().await.get(&id) {
             Ok(m) => Ok(Response::Item(m.clone())),
-            Err(e) => match e {
-                Error::IdNotFound(id) => Ok(Response::NotFound(id)),
-                e => Ok(Response::Error(e.to_string())),
-            },
+            // Ambiguous ids come back as `TooManyMeta`, so they can be told
+            // apart from other errors
+            Err(e) => Ok(Response::from(e)),
+        }
+    }
+
+    pub async fn handle_get_offline(&mut self, id: String) -> Result<Response, Error> {
+        info!("[get offline] {:?}", id);
+        let id = self.store.read().await.get(&id)?.id().to_string();
+
+        match self.offline.read().await.get(&id) {
+            Ok(data) => Ok(Response::Offline(data.clone())),
+            Err(_) => Ok(Response::NotFound(id)),
         }
     }
 
@@ -447,8 +1037,13 @@ impl<'s> Daemon<'s> {
         let count = count.unwrap_or(5);
         let search_result = self.index.write().await.search(query, count)?;
         let mut results = Vec::new();
+        let store = self.store.read().await;
         for (id, snip) in search_result {
-            let meta = self.store.read().await.get(id)?.clone();
+            // Deletes only reach the index with the next commit
+            let meta = match store.get(&id) {
+                Ok(meta) => meta.clone(),
+                Err(_) => continue,
+            };
             results.push((meta, SnippetDef::from(snip)));
         }
 
@@ -459,14 +1054,13 @@ impl<'s> Daemon<'s> {
         info!("[open] {:?}", id);
         match self.offline.read().await.get(&id) {
             Ok(data) => {
-                use chrono::Utc;
-
                 {
                     let mut store = self.store.write().await;
-                    let meta = store.get_mut(&id)?;
+                    let mut meta = store.get(&id)?.clone();
 
                     let now = Utc::now();
                     meta.last_read = Some(now);
+                    store.update(&meta.id().to_string(), meta)?;
                 }
 
                 if online {
@@ -490,7 +1084,9 @@ impl<'s> Daemon<'s> {
             }
             Err(_e) => {
                 let store = self.store.read().await;
-                if self.settings.daemon().download_on_open && store.get(&id).is_ok() {
+                let found = store.get(&id).map(|meta| meta.id().to_string());
+
+                if let (true, Ok(id)) = (self.settings.daemon().download_on_open, found) {
                     let req = Request::Download {
                         id: Some(id.to_string()),
                     };
@@ -519,9 +1115,9 @@ impl<'s> Daemon<'s> {
         if deep_copy {
             let mut fs = self.store.write().await;
             let mut os = self.offline.write().await;
-            deep_transfer::import_deep_copy(file, &mut *fs, &mut *os)?;
+            deep_transfer::import_deep_copy(file, fs.as_mut(), &mut *os)?;
         } else {
-            self.store.write().await.import_file(file)?;
+            self.store.write().await.import_file(Path::new(&file))?;
         }
         Ok(Response::Ok)
     }
@@ -533,10 +1129,10 @@ impl<'s> Daemon<'s> {
         deep_copy: bool,
     ) -> Result<Response, Error> {
         if deep_copy {
-            deep_transfer::export_deep_copy(file)?;
+            deep_transfer::export_deep_copy(file, self.store.read().await.as_ref())?;
             Ok(Response::Ok)
         } else {
-            match self.store.read().await.write_file(file.clone()) {
+            match self.store.read().await.write_file(&file) {
                 Ok(_) => Ok(Response::Custom(format!(
                     "Monk store exported to: {:?}",
                     file
@@ -550,28 +1146,35 @@ impl<'s> Daemon<'s> {
         tracing::info!("handling request: {:?}", req);
 
         match req {
+            Request::Hello { version, protocol } => Ok(self.handle_hello(version, protocol)),
             Request::Add {
                 name,
                 url,
                 comment,
                 tags,
             } => self.handle_add(name, url, comment, tags).await,
+            Request::AddNote { name, body, tags } => self.handle_add_note(name, body, tags).await,
+            Request::GetNote { id } => self.handle_get_note(id).await,
+            Request::SetNote { id, body } => self.handle_set_note(id, body).await,
             Request::Edit { id, edit } => self.handle_edit(id, edit).await,
             Request::Delete { id } => self.handle_delete(id).await,
             Request::List { count, tags } => self.handle_list(count, tags).await,
             Request::Get { id } => self.handle_get(id).await,
+            Request::GetOffline { id } => self.handle_get_offline(id).await,
             Request::Download { id } => self.handle_download(id).await,
+            Request::RetryFailed => self.handle_retry_failed().await,
+            Request::RetryDownload(id) => self.handle_retry_download(id).await,
+            Request::Jobs => self.handle_jobs().await,
+            Request::CancelJob { id } => self.handle_cancel_job(id).await,
             Request::Open { id, online } => self.handle_open(id, online).await,
-            Request::UpdateMeta(m) => {
-                self.store.write().await.update(&m.id().to_string(), m)?;
-                Ok(Response::Ok)
-            }
-            Request::UpdateOffline(o) => {
-                self.offline.write().await.update(o.id().to_string(), o)?;
-                Ok(Response::Ok)
-            }
+            Request::UpdateMeta(m) => self.handle_update_meta(m).await,
+            Request::UpdateOffline(o) => self.handle_update_offline(o).await,
+            Request::UpdatePage { id, url, page } => self.handle_update_page(id, url, page).await,
             Request::Index { id } => self.handle_index(id).await,
             Request::IndexStatus { id } => self.handle_index_status(id).await,
+            Request::IndexCommitted(ids) => self.handle_index_committed(ids).await,
+            Request::IndexFailed { id, error } => self.handle_index_failed(id, error).await,
+            Request::IndexCancelled { id } => self.handle_index_cancelled(id).await,
             Request::IndexAll { tags } => self.handle_index_all(tags).await,
             Request::Status { kind } => self.handle_status(kind).await,
             Request::Search { count, query } => self.handle_search(query, count).await,
@@ -588,11 +1191,296 @@ impl<'s> Daemon<'s> {
         }
     }
 
+    /// Stop right away instead of waiting for downloads and index runs.
+    /// Downloads are aborted, killing any `youtube-dl` they started, and
+    /// whatever is still waiting to be indexed is dropped. The stores are
+    /// saved, and interrupted jobs are queued again so they resume on the
+    /// next start.
+    pub async fn force_shutdown(self) -> Result<(), Error> {
+        let mut aborted = 0;
+        for adapter in &self.adapters {
+            aborted += adapter.lock().await.cancel_all();
+        }
+
+        self.pipeline.cancel_all();
+
+        let interrupted = self.jobs.write().await.interrupt();
+        info!(
+            "[force shutdown] aborted {} download(s), interrupted {} job(s)",
+            aborted, interrupted
+        );
+
+        // The index isn't committed: items waiting on it are still marked
+        // `Indexing` and are indexed again on the next start
+        self.jobs.write().await.commit()?;
+        self.store.write().await.commit()?;
+        self.offline.write().await.commit()?;
+
+        Ok(())
+    }
+
     pub async fn shutdown(self) -> Result<(), Error> {
         // Commit any changes to the store
+        self.index.write().await.commit()?;
+        self.jobs.write().await.commit()?;
         self.store.write().await.commit()?;
         self.offline.write().await.commit()?;
 
         Ok(())
     }
 }
+
+// A failed download that was started again is shown as downloading until it
+// reports back, instead of keeping its old error
+fn restart_download(offline: &mut OfflineStore, id: &str) -> Result<(), Error> {
+    let mut data = match offline.get(id) {
+        Ok(data) if data.status.is_error() => data.clone(),
+        _ => return Ok(()),
+    };
+
+    data.retrying();
+    offline.update(id, data)
+}
+
+// Send a `RetryDownload` request for `id` once `delay` has passed.
+fn schedule_download(
+    sender: Sender<(Request, Option<tokio::sync::oneshot::Sender<Response>>)>,
+    id: String,
+    delay: std::time::Duration,
+) {
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+        let _ = sender.send((Request::RetryDownload(id), None)).await;
+    });
+}
+
+// Fetch the page at `url` in the background and send what it says about
+// itself back as `UpdatePage`, so adding an item doesn't wait on the network.
+// With `download`, the item is downloaded after that, when its content type
+// is known and the right adapter can be picked, e.g. the PDF adapter for a
+// url without a `.pdf` extension.
+fn fetch_page(
+    sender: Sender<(Request, Option<tokio::sync::oneshot::Sender<Response>>)>,
+    id: String,
+    url: url::Url,
+    download: bool,
+) {
+    tokio::spawn(async move {
+        match PageMeta::fetch(&url).await {
+            Ok(page) => {
+                let request = Request::UpdatePage {
+                    id: id.clone(),
+                    url: url.clone(),
+                    page,
+                };
+                let _ = sender.send((request, None)).await;
+            }
+            Err(e) => tracing::warn!("[{}] could not fetch {}: {}", id, url, e),
+        }
+
+        if download {
+            info!("auto downloading: [{}]", id);
+            let _ = sender
+                .send((Request::Download { id: Some(id) }, None))
+                .await;
+        }
+    });
+}
+
+fn not_a_note(meta: &Meta) -> Response {
+    Response::Error(format!("`{}` is not a note", meta.id()))
+}
+
+// Retries a previous run scheduled but didn't get to, with the time left
+// until each one is due.
+fn scheduled_retries(offline: &OfflineStore) -> Vec<(String, std::time::Duration)> {
+    let now = Utc::now();
+
+    offline
+        .data()
+        .iter()
+        .filter(|d| d.status.is_error())
+        .filter_map(|d| {
+            let next = d.retry.next_attempt?;
+            let delay = (next - now).to_std().unwrap_or_default();
+            Some((d.id().to_string(), delay))
+        })
+        .collect()
+}
+
+// Bytes on disk for everything in the offline folder that belongs to `item`,
+// e.g. `<id>.html` or the `<id>/` directory.
+fn download_size(data_folder: &Path, item: &str) -> u64 {
+    let entries = match std::fs::read_dir(data_folder) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with(item))
+        .map(|entry| get_size(entry) as u64)
+        .sum()
+}
+
+// Queue jobs for the items an interrupted run left half done, including ones
+// from before jobs were persisted, and return every job that should resume.
+fn recover_jobs(jobs: &mut JobQueue, store: &dyn MetaStore, offline: &OfflineStore) -> Vec<Job> {
+    let needs_job = |jobs: &JobQueue, item: &str, kind: JobKind| {
+        jobs.find(item, kind)
+            .map(|job| !job.state.is_pending())
+            .unwrap_or(true)
+    };
+
+    for meta in store.data() {
+        if meta.index_status == Some(IndexStatus::Indexing)
+            && needs_job(jobs, meta.id(), JobKind::Index)
+        {
+            jobs.push(meta.id(), JobKind::Index);
+        }
+    }
+
+    for data in offline.data() {
+        if data.status == OfflineStatus::Downloading
+            && needs_job(jobs, data.id(), JobKind::Download)
+        {
+            jobs.push(data.id(), JobKind::Download);
+        }
+    }
+
+    jobs.recover()
+}
+
+async fn find_extractor(
+    adapters: &[Lock<Box<dyn Adapter>>],
+    adapter_type: AdapterType,
+) -> Option<Extractor> {
+    for adapter in adapters {
+        let adapter = adapter.lock().await;
+        if adapter.adt_type() == adapter_type {
+            return adapter.extractor();
+        }
+    }
+
+    None
+}
+
+// After a schema change the index is rebuilt from scratch. Every meta is put
+// back along with the contents of its offline copy, extracted by the adapter
+// that downloaded it. The new index is only swapped in once all of it is done.
+async fn rebuild_index(
+    store: Arc<RwLock<Box<dyn MetaStore>>>,
+    offline: Arc<RwLock<OfflineStore>>,
+    index: Arc<RwLock<Index>>,
+    adapters: Vec<Lock<Box<dyn Adapter>>>,
+) -> Result<(), Error> {
+    let metas = store.read().await.data().to_vec();
+    info!("rebuilding the index from {} items", metas.len());
+
+    index.write().await.set_rebuild_total(metas.len());
+
+    for mut meta in metas {
+        let data = offline.read().await.get(meta.id()).ok().cloned();
+
+        let extractor = match data.as_ref() {
+            Some(data) => find_extractor(&adapters, data.adapter).await,
+            None => None,
+        };
+
+        let doc = match extractor {
+            Some(extractor) => extract(extractor, meta.clone(), data).await,
+            None => None,
+        };
+
+        let mut index = index.write().await;
+
+        // Deleted since the snapshot was taken
+        if index.deleted_during_rebuild(meta.id()) {
+            index.rebuild_step();
+            continue;
+        }
+
+        let indexed = match doc {
+            Some(Ok(doc)) => {
+                index.insert_doc(&meta, &doc)?;
+                true
+            }
+            Some(Err(e)) => {
+                error!("[{}] failed to reindex: {}", meta.id(), e);
+                false
+            }
+            None => false,
+        };
+
+        // The indexing pipeline keeps running alongside, and may have put the
+        // item in already
+        if !indexed {
+            index.replace_meta(&meta)?;
+        }
+        index.rebuild_step();
+        drop(index);
+
+        let status = match (indexed, meta.index_status) {
+            (true, _) => Some(IndexStatus::Indexed),
+            (false, Some(_)) => Some(IndexStatus::Old),
+            (false, None) => None,
+        };
+
+        if status != meta.index_status {
+            meta.index_status = status;
+            // The meta may have been deleted while the rebuild was running
+            let _ = store.write().await.update(&meta.id().to_string(), meta);
+        }
+    }
+
+    index.write().await.finish_rebuild()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn data(id: &str, status: OfflineStatus) -> OfflineData {
+        OfflineData {
+            id: id.to_string(),
+            status,
+            ..OfflineData::default()
+        }
+    }
+
+    #[test]
+    fn retried_download_is_downloading_again() {
+        let dir = TempDir::new().unwrap();
+        let mut offline = OfflineStore::read_file(dir.path().join("offline.json")).unwrap();
+
+        let mut failed = data("a1", OfflineStatus::Downloading);
+        failed.failed(&Error::Custom("503".to_string()));
+        failed.retry.next_attempt = Some(Utc::now());
+        offline.update("a1", failed).unwrap();
+
+        restart_download(&mut offline, "a1").unwrap();
+
+        let retried = offline.get("a1").unwrap();
+        assert_eq!(retried.status, OfflineStatus::Downloading);
+        assert_eq!(retried.retry.attempts, 1);
+        assert_eq!(retried.retry.next_attempt, None);
+    }
+
+    #[test]
+    fn only_failed_downloads_are_restarted() {
+        let dir = TempDir::new().unwrap();
+        let mut offline = OfflineStore::read_file(dir.path().join("offline.json")).unwrap();
+
+        let mut ready = data("a1", OfflineStatus::Downloading);
+        ready.ready(PathBuf::from("a1.html"));
+        offline.update("a1", ready.clone()).unwrap();
+
+        restart_download(&mut offline, "a1").unwrap();
+        assert_eq!(offline.get("a1").unwrap(), &ready);
+
+        // Nothing to restart for an item that was never downloaded
+        restart_download(&mut offline, "b1").unwrap();
+        assert!(offline.get("b1").is_err());
+    }
+}
@@ -32,6 +32,8 @@ pub enum Error {
     TooManyMetas(String, Vec<Meta>),
     #[error("Tantivy Error: {0}")]
     Tantivy(String),
+    #[error("PDF Error: {0}")]
+    Pdf(String),
     #[error("Query Parsing Error: {0}")]
     QueryParse(String),
     #[error("No url for: `{0}`")]
@@ -42,22 +44,67 @@ pub enum Error {
     Utf8Conversion(#[from] std::string::FromUtf8Error),
     #[error("Tokio join error: {0}")]
     JoinHandle(#[from] tokio::task::JoinError),
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
     #[error("Zip-rs error: {0}")]
     ZipError(#[from] ZipError),
+    #[error("No migration from version {0} to {1}")]
+    Migration(String, String),
+    #[error("monkd is already running: {0}")]
+    AlreadyRunning(String),
+    #[error("refusing to listen on {0} without `daemon.token`")]
+    NoToken(String),
     #[error("Custom: {0}")]
     Custom(String),
 }
 
 impl Error {
+    /// Whether the error was caused by the request, e.g. a bad url or query,
+    /// rather than by monkd. These are sent back to the client instead of
+    /// stopping the daemon.
     pub fn is_client_error(&self) -> bool {
         match self {
             Error::IdNotFound(_)
             | Error::AlreadyExists(_)
             | Error::TooManyMetas(_, _)
+            | Error::TooManyIds(_, _)
+            | Error::UrlParse(_)
+            | Error::QueryParse(_)
             | Error::NoUrl(_) => true,
             _ => false,
         }
     }
+
+    /// Whether the operation might succeed if tried again later, e.g. after
+    /// a timeout or a server error.
+    pub fn is_transient(&self) -> bool {
+        use std::io::ErrorKind;
+
+        match self {
+            Error::Reqwest(e) => {
+                e.is_timeout()
+                    || e.is_connect()
+                    || e.status()
+                        .map(|s| s.is_server_error() || s.as_u16() == 429)
+                        .unwrap_or(false)
+            }
+            Error::TokioIO(e) => matches!(
+                e.kind(),
+                ErrorKind::TimedOut
+                    | ErrorKind::ConnectionRefused
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::Interrupted
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl From<lopdf::Error> for Error {
+    fn from(e: lopdf::Error) -> Self {
+        Error::Pdf(e.to_string())
+    }
 }
 
 impl From<tantivy::TantivyError> for Error {
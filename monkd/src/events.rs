@@ -0,0 +1,28 @@
+// Events the daemon emits as items change.
+//
+// The daemon publishes on a broadcast channel and the server streams every
+// event to clients subscribed to `/events` as server-sent events, so clients
+// can wait for a download or index run instead of polling.
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+/// Events buffered per subscriber before slow ones start missing events.
+pub const EVENT_BUFFER: usize = 256;
+
+pub type EventSender = broadcast::Sender<Event>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Event {
+    ItemAdded { id: String },
+    ItemUpdated { id: String },
+    DownloadStarted { id: String },
+    DownloadFinished { id: String },
+    DownloadFailed { id: String, error: String },
+    IndexFinished { id: String },
+    IndexFailed { id: String, error: String },
+}
+
+pub fn channel() -> EventSender {
+    broadcast::channel(EVENT_BUFFER).0
+}
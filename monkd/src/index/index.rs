@@ -1,39 +1,132 @@
+use async_channel::Sender;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tantivy::{
-    collector::TopDocs, directory::*, query::QueryParser, DocAddress, Document, Index as TIndex,
-    IndexWriter, Opstamp, SnippetGenerator, Term,
+    collector::TopDocs, directory::*, query::QueryParser, schema::Field, DocAddress, Document,
+    Index as TIndex, IndexWriter, Opstamp, SnippetGenerator, Term,
 };
 
 use crate::error::Error;
+use crate::index::pipeline::IndexDoc;
 use crate::index::schema::*;
 use crate::index::settings::IndexSettings;
 use crate::metadata::Meta;
+use crate::server::{request::Request, response::Response};
+use tokio::sync::{oneshot, RwLock};
+
+const SCHEMA_VERSION_FILE: &str = "schema_version";
+
+// Fields searched when a query doesn't name one. Looked up by name, since an
+// out of date index searched during a rebuild may not have all of them.
+const DEFAULT_FIELDS: &[&str] = &[
+    "id",
+    "name",
+    "url",
+    "comment",
+    "body",
+    "title",
+    "extra",
+    "description",
+    "author",
+    "site_name",
+];
+
+/// Progress of a rebuild after a schema change, reported through `Request::Status`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RebuildProgress {
+    pub from_version: String,
+    pub total: usize,
+    pub done: usize,
+}
 
 pub struct Index {
+    // The index being written to
     index: TIndex,
+    // The out of date index, which is still searched until a rebuild is
+    // swapped in
+    live: Option<TIndex>,
+    // Where the index lives once it is up to date
+    path: PathBuf,
+    // The directory currently being written to. Differs from `path` while a
+    // rebuild is in progress.
     folder: PathBuf,
-    writer: IndexWriter,
+    // Only `None` for the moment a finished rebuild is swapped in
+    writer: Option<IndexWriter>,
+    rebuild: Option<RebuildProgress>,
+    // Ids deleted while a rebuild runs, which it mustn't add back
+    deleted: HashSet<String>,
+    // Commit as soon as this many operations are queued
+    batch: usize,
+    // Operations added to the writer since the last commit
+    queued: usize,
+    // Ids inserted since the last commit
+    pending: Vec<String>,
+    // Ids committed since the last call to `take_committed`
+    committed: Vec<String>,
 }
 
 impl Index {
     pub fn new(settings: &IndexSettings) -> Result<Self, Error> {
         let path = &settings.path;
+        recover_swap(path)?;
         std::fs::create_dir_all(&path)?;
 
         tracing::info!("Schema Version: {}", SCHEMA_VERSION);
-        let schema = current_schema();
-        let mmap_dir = MmapDirectory::open(path).map_err(|e| Error::Tantivy(e.to_string()))?;
-        let index =
-            TIndex::open_or_create(mmap_dir, schema).map_err(|e| Error::Tantivy(e.to_string()))?;
 
-        let writer = index
-            .writer(50_000_000)
-            .map_err(|e| Error::Tantivy(e.to_string()))?;
+        // The fields are laid out by id, so an index written with another
+        // schema can't be opened. It is rebuilt next to the old one and only
+        // swapped in once complete, see `finish_rebuild`.
+        let (folder, rebuild, live) = match stored_schema_version(path)? {
+            Some(version) if version != SCHEMA_VERSION => {
+                tracing::info!(
+                    "Index schema {} is out of date, rebuilding with {}",
+                    version,
+                    SCHEMA_VERSION
+                );
+
+                // Anything left here is from a rebuild that was interrupted
+                let staging = sibling(path, ".rebuild");
+                if staging.exists() {
+                    std::fs::remove_dir_all(&staging)?;
+                }
+                std::fs::create_dir_all(&staging)?;
+
+                let progress = RebuildProgress {
+                    from_version: version,
+                    total: 0,
+                    done: 0,
+                };
+
+                // Tantivy reads the old schema from the index itself
+                let live = TIndex::open_in_dir(path)
+                    .map_err(|e| tracing::warn!("Can't search the old index: {}", e))
+                    .ok();
+
+                (staging, Some(progress), live)
+            }
+            _ => (path.clone(), None, None),
+        };
+
+        let (index, writer) = open(&folder)?;
+
+        if rebuild.is_none() {
+            std::fs::write(path.join(SCHEMA_VERSION_FILE), SCHEMA_VERSION)?;
+        }
 
         Ok(Index {
             index,
-            folder: settings.path.clone(),
-            writer,
+            live,
+            path: path.clone(),
+            folder,
+            writer: Some(writer),
+            rebuild,
+            deleted: HashSet::new(),
+            batch: std::cmp::max(settings.commit_batch(), 1),
+            queued: 0,
+            pending: Vec::new(),
+            committed: Vec::new(),
         })
     }
 
@@ -41,13 +134,149 @@ impl Index {
         &self.folder
     }
 
+    /// Whether this index is being rebuilt for a new schema. Everything in
+    /// the stores has to be indexed again before `finish_rebuild` is called.
+    pub fn is_rebuilding(&self) -> bool {
+        self.rebuild.is_some()
+    }
+
+    pub fn rebuild_progress(&self) -> Option<&RebuildProgress> {
+        self.rebuild.as_ref()
+    }
+
+    pub fn set_rebuild_total(&mut self, total: usize) {
+        if let Some(progress) = self.rebuild.as_mut() {
+            progress.total = total;
+        }
+    }
+
+    pub fn rebuild_step(&mut self) {
+        if let Some(progress) = self.rebuild.as_mut() {
+            progress.done += 1;
+        }
+    }
+
+    /// Whether `id` was deleted since the rebuild started, so the rebuild
+    /// shouldn't index it again.
+    pub fn deleted_during_rebuild(&self, id: &str) -> bool {
+        self.deleted.contains(id)
+    }
+
+    /// Replace the out of date index with the rebuilt one. The rebuilt index
+    /// is complete before the old one is moved aside, so a crash between the
+    /// two renames is finished on the next start, see `recover_swap`.
+    pub fn finish_rebuild(&mut self) -> Result<(), Error> {
+        if self.rebuild.is_none() {
+            return Ok(());
+        }
+
+        // The writer holds a lock inside the staging directory
+        self.commit()?;
+        self.writer.take();
+        self.live.take();
+
+        std::fs::write(self.folder.join(SCHEMA_VERSION_FILE), SCHEMA_VERSION)?;
+
+        let old = sibling(&self.path, ".old");
+        if old.exists() {
+            std::fs::remove_dir_all(&old)?;
+        }
+
+        std::fs::rename(&self.path, &old)?;
+        std::fs::rename(&self.folder, &self.path)?;
+        std::fs::remove_dir_all(&old)?;
+
+        let (index, writer) = open(&self.path)?;
+        self.index = index;
+        self.writer = Some(writer);
+        self.folder = self.path.clone();
+        self.deleted.clear();
+
+        if let Some(progress) = self.rebuild.take() {
+            tracing::info!(
+                "Rebuilt index from schema {} ({} items)",
+                progress.from_version,
+                progress.done
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Commit every queued insert and delete. Documents are only visible to
+    /// searches after this.
+    pub fn commit(&mut self) -> Result<(), Error> {
+        if self.queued == 0 {
+            return Ok(());
+        }
+
+        self.writer()?.commit()?;
+
+        tracing::info!("[index] committed {} operation(s)", self.queued);
+        self.queued = 0;
+        self.committed.append(&mut self.pending);
+
+        Ok(())
+    }
+
+    /// Ids of the documents committed since the last call.
+    pub fn take_committed(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.committed)
+    }
+
+    pub async fn commit_loop(
+        handle: Arc<RwLock<Index>>,
+        sender: Sender<(Request, Option<oneshot::Sender<Response>>)>,
+        delay: std::time::Duration,
+    ) -> Result<(), Error> {
+        tracing::info!("Index Commit Delay: {:3.1} s.", delay.as_secs_f32());
+        loop {
+            tokio::time::sleep(delay).await;
+
+            let committed = {
+                let mut index = handle.write().await;
+                let _ = index
+                    .commit()
+                    .map_err(|e| tracing::error!("Index: {}", e));
+                index.take_committed()
+            };
+
+            if !committed.is_empty() {
+                let _ = sender.send((Request::IndexCommitted(committed), None)).await;
+            }
+        }
+    }
+
+    // Commit early if enough operations have piled up
+    fn queue(&mut self) -> Result<(), Error> {
+        self.queued += 1;
+        if self.queued >= self.batch {
+            self.commit()?;
+        }
+
+        Ok(())
+    }
+
+    // The index searches go to
+    fn searched(&self) -> &TIndex {
+        self.live.as_ref().unwrap_or(&self.index)
+    }
+
+    fn writer(&mut self) -> Result<&mut IndexWriter, Error> {
+        self.writer
+            .as_mut()
+            .ok_or_else(|| Error::Tantivy("index writer is closed".to_string()))
+    }
+
     pub fn count_indexed_items(&self) -> Result<usize, Error> {
         use tantivy::collector::Count;
 
-        let reader = self.index.reader()?;
+        let index = self.searched();
+        let reader = index.reader()?;
         let searcher = reader.searcher();
 
-        let query_parser = QueryParser::for_index(&self.index, vec![ID]);
+        let id = field(index, "id")?;
+        let query_parser = QueryParser::for_index(index, vec![id]);
         let query = query_parser.parse_query("*")?;
         let count = searcher.search(&query, &Count)?;
 
@@ -61,23 +290,34 @@ impl Index {
     ) -> Result<Vec<(String, tantivy::Snippet)>, Error> {
         tracing::info!("[search] Query: {:?}", query);
 
-        let reader = self.index.reader()?;
+        let index = self.searched();
+        let reader = index.reader()?;
         let searcher = reader.searcher();
 
         // tracing::info!("Got reader and searcher");
 
-        let query_parser = QueryParser::for_index(
-            &self.index,
-            vec![ID, NAME, URL, COMMENT, BODY, TITLE, EXTRA],
-        );
+        let schema = index.schema();
+        let fields = DEFAULT_FIELDS
+            .iter()
+            .filter_map(|name| schema.get_field(name))
+            .collect();
+        let query_parser = QueryParser::for_index(index, fields);
 
         let query = query_parser.parse_query(&query)?;
 
         let resulting_docs: Vec<(f32, DocAddress)> =
             searcher.search(&query, &TopDocs::with_limit(count))?;
 
-        let mut snippet_generator = SnippetGenerator::create(&searcher, &*query, BODY)?;
-        snippet_generator.set_max_num_chars(120);
+        // An old index may have no body to quote from
+        let snippet_generator = match schema.get_field("body") {
+            Some(body) => {
+                let mut generator = SnippetGenerator::create(&searcher, &*query, body)?;
+                generator.set_max_num_chars(120);
+                Some(generator)
+            }
+            None => None,
+        };
+        let id = field(index, "id")?;
 
         let docs: Result<Vec<_>, _> = resulting_docs
             .into_iter()
@@ -88,11 +328,14 @@ impl Index {
 
         let results: Vec<(_, _)> = docs
             .iter()
-            .map(|doc| {
-                (
-                    doc.get_first(ID).unwrap().text().unwrap().to_string(),
-                    snippet_generator.snippet_from_doc(&doc),
-                )
+            .filter_map(|doc| {
+                let id = doc.get_first(id)?.text()?.to_string();
+                let snippet = match snippet_generator.as_ref() {
+                    Some(generator) => generator.snippet_from_doc(doc),
+                    None => tantivy::Snippet::empty(),
+                };
+
+                Some((id, snippet))
             })
             .collect();
 
@@ -131,7 +374,37 @@ impl Index {
 
         doc.add_date(FOUND, meta.found());
 
-        if let Some(title) = title {
+        let page = meta.page();
+
+        // Pages are often saved through a tracking or shortened link
+        if let Some(canonical) = &page.canonical_url {
+            if Some(canonical) != meta.url() {
+                doc.add_text(URL, &canonical.to_string());
+            }
+        }
+
+        if let Some(description) = &page.description {
+            doc.add_text(DESCRIPTION, description);
+        }
+
+        if let Some(author) = &page.author {
+            doc.add_text(AUTHOR, author);
+        }
+
+        if let Some(site_name) = &page.site_name {
+            doc.add_text(SITE_NAME, site_name);
+        }
+
+        if let Some(language) = &page.language {
+            doc.add_text(LANGUAGE, &language.to_lowercase());
+        }
+
+        if let Some(published) = &page.published {
+            doc.add_date(PUBLISHED, published);
+        }
+
+        // Items that weren't downloaded still have the title the page gave
+        if let Some(title) = title.or_else(|| page.title.as_deref()) {
             doc.add_text(TITLE, title);
         }
 
@@ -143,24 +416,232 @@ impl Index {
             doc.add_text(EXTRA, extra);
         }
 
-        self.writer.add_document(doc);
-        self.writer
-            .commit()
-            .map_err(|e| Error::Tantivy(e.to_string()))
+        let opstamp = self.writer()?.add_document(doc);
+        self.pending.push(meta.id().to_string());
+        self.queue()?;
+
+        Ok(opstamp)
+    }
+
+    /// Replace whatever is indexed for `meta` with just its metadata.
+    pub fn replace_meta(&mut self, meta: &Meta) -> Result<Opstamp, Error> {
+        self.delete_term(meta.id())?;
+        self.insert_meta(meta)
+    }
+
+    /// Replace whatever is indexed for `meta` with the extracted document.
+    pub fn insert_doc(&mut self, meta: &Meta, doc: &IndexDoc) -> Result<Opstamp, Error> {
+        self.delete_term(meta.id())?;
+        self.insert_meta_with_data(
+            meta,
+            doc.title.as_deref(),
+            doc.body.as_deref(),
+            doc.extra.as_deref(),
+        )
     }
 
     pub fn delete(&mut self, id: impl AsRef<str>) -> Result<Opstamp, Error> {
         tracing::info!("[index] [delete] {}", id.as_ref());
 
+        if self.rebuild.is_some() {
+            self.deleted.insert(id.as_ref().to_string());
+        }
+
+        self.delete_term(id)
+    }
+
+    fn delete_term(&mut self, id: impl AsRef<str>) -> Result<Opstamp, Error> {
         let term = Term::from_field_text(ID, id.as_ref());
 
-        self.writer.delete_term(term);
-        self.writer.commit().map_err(Into::into)
+        let opstamp = self.writer()?.delete_term(term);
+        self.queue()?;
+
+        Ok(opstamp)
     }
 }
 
 impl Drop for Index {
     fn drop(&mut self) {
-        let _ = self.writer.commit();
+        if let Some(writer) = self.writer.as_mut() {
+            let _ = writer.commit();
+        }
+    }
+}
+
+// A field of the index by name. The constants in `schema` only hold for an
+// index with the current schema, not one that is still being rebuilt.
+fn field(index: &TIndex, name: &str) -> Result<Field, Error> {
+    index
+        .schema()
+        .get_field(name)
+        .ok_or_else(|| Error::Tantivy(format!("the index has no `{}` field", name)))
+}
+
+fn open(folder: &Path) -> Result<(TIndex, IndexWriter), Error> {
+    let schema = current_schema();
+    let mmap_dir = MmapDirectory::open(folder).map_err(|e| Error::Tantivy(e.to_string()))?;
+    let index =
+        TIndex::open_or_create(mmap_dir, schema).map_err(|e| Error::Tantivy(e.to_string()))?;
+
+    let writer = index
+        .writer(50_000_000)
+        .map_err(|e| Error::Tantivy(e.to_string()))?;
+
+    Ok((index, writer))
+}
+
+// Finish a swap that `finish_rebuild` was interrupted in. The rebuilt index
+// is only moved in after the old one is moved out to `.old`, and is complete
+// by then.
+fn recover_swap(path: &Path) -> Result<(), Error> {
+    let old = sibling(path, ".old");
+    if !old.exists() {
+        return Ok(());
+    }
+
+    if !path.exists() {
+        let staging = sibling(path, ".rebuild");
+
+        if staging.exists() {
+            tracing::info!("Finishing an interrupted index swap");
+            std::fs::rename(&staging, path)?;
+        } else {
+            // Nothing to swap in, so keep the old index and rebuild again
+            std::fs::rename(&old, path)?;
+            return Ok(());
+        }
+    }
+
+    std::fs::remove_dir_all(&old)?;
+
+    Ok(())
+}
+
+// e.g. `index` -> `index.rebuild`
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut sibling = path.as_os_str().to_owned();
+    sibling.push(suffix);
+    sibling.into()
+}
+
+// The schema version an existing index was created with. Indexes from before
+// the version was recorded all used the `0.0.0` schema.
+fn stored_schema_version(path: &Path) -> Result<Option<String>, Error> {
+    match std::fs::read_to_string(path.join(SCHEMA_VERSION_FILE)) {
+        Ok(version) => Ok(Some(version.trim().to_string())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            if path.join("meta.json").exists() {
+                Ok(Some("0.0.0".to_string()))
+            } else {
+                Ok(None)
+            }
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tantivy::schema::{Schema, STORED, STRING, TEXT};
+    use tempfile::TempDir;
+
+    #[test]
+    fn new_index_has_no_schema_version() {
+        let dir = TempDir::new().unwrap();
+
+        assert_eq!(stored_schema_version(dir.path()).unwrap(), None);
+    }
+
+    #[test]
+    fn unversioned_index_is_the_first_schema() {
+        let dir = TempDir::new().unwrap();
+        std::fs::write(dir.path().join("meta.json"), "{}").unwrap();
+
+        assert_eq!(
+            stored_schema_version(dir.path()).unwrap().as_deref(),
+            Some("0.0.0")
+        );
+    }
+
+    #[test]
+    fn schema_version_is_read_from_the_index() {
+        let dir = TempDir::new().unwrap();
+        std::fs::write(dir.path().join("meta.json"), "{}").unwrap();
+        std::fs::write(dir.path().join(SCHEMA_VERSION_FILE), "0.2.0\n").unwrap();
+
+        assert_eq!(
+            stored_schema_version(dir.path()).unwrap().as_deref(),
+            Some("0.2.0")
+        );
+    }
+
+    // An index from before the current schema, with `body` and `id` laid out
+    // the other way around
+    fn old_index(path: &Path) {
+        let mut builder = Schema::builder();
+        let body = builder.add_text_field("body", STORED | TEXT);
+        let id = builder.add_text_field("id", STORED | STRING);
+
+        std::fs::create_dir_all(path).unwrap();
+        let index = TIndex::create_in_dir(path, builder.build()).unwrap();
+        let mut writer = index.writer(10_000_000).unwrap();
+
+        let mut doc = Document::new();
+        doc.add_text(id, "a1");
+        doc.add_text(body, "In search of an understandable consensus algorithm");
+        writer.add_document(doc);
+        writer.commit().unwrap();
+
+        std::fs::write(path.join(SCHEMA_VERSION_FILE), "0.0.9").unwrap();
+    }
+
+    fn settings(path: &Path) -> IndexSettings {
+        IndexSettings {
+            path: path.to_path_buf(),
+            ..IndexSettings::default()
+        }
+    }
+
+    #[test]
+    fn old_index_is_searched_by_field_name() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("index");
+        old_index(&path);
+
+        let index = Index::new(&settings(&path)).unwrap();
+        assert!(index.is_rebuilding());
+        assert_eq!(index.count_indexed_items().unwrap(), 1);
+
+        let results = index.search("consensus".to_string(), 10).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0, "a1");
+        assert!(results[0].1.fragments().contains("consensus"));
+    }
+
+    #[test]
+    fn rebuild_does_not_duplicate_items() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("index");
+        old_index(&path);
+
+        let mut index = Index::new(&settings(&path)).unwrap();
+        let meta = Meta::builder()
+            .id("a1".to_string())
+            .name("raft paper")
+            .build();
+
+        // The pipeline indexes the item while the rebuild gets to it
+        let doc = IndexDoc {
+            body: Some("In search of an understandable consensus algorithm".to_string()),
+            ..IndexDoc::default()
+        };
+        index.insert_doc(&meta, &doc).unwrap();
+        index.replace_meta(&meta).unwrap();
+        index.finish_rebuild().unwrap();
+
+        assert!(!index.is_rebuilding());
+        assert_eq!(index.count_indexed_items().unwrap(), 1);
+        assert_eq!(index.search("raft".to_string(), 10).unwrap().len(), 1);
     }
 }
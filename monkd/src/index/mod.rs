@@ -1,6 +1,8 @@
 pub mod index;
+pub mod pipeline;
 pub mod query;
 pub mod schema;
 pub mod settings;
 
-pub use index::Index;
+pub use index::{Index, RebuildProgress};
+pub use pipeline::{IndexDoc, Pipeline};
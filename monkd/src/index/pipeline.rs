@@ -0,0 +1,348 @@
+// The indexing pipeline.
+//
+// Items to index are queued on a bounded channel, so producers wait once the
+// queue is full. Each submission gets its own ticket, and only the latest one
+// for an item is indexed or reported: re-indexing an item that is still in
+// the pipeline supersedes the earlier run instead of racing it. A pool of workers pulls from the queue and runs the adapter's
+// extractor on a blocking thread, turning the offline copy into an `IndexDoc`.
+// Extracted documents go to a single writer task, the only place documents
+// are added to the index. The index commits them in batches, see
+// `Index::commit_loop`.
+
+use async_channel::{Receiver, Sender};
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+use tokio::sync::{oneshot, RwLock};
+
+use crate::adapter::{AdapterType, Extractor};
+use crate::error::Error;
+use crate::index::{settings::IndexSettings, Index};
+use crate::metadata::{offline_store::OfflineData, Meta};
+use crate::server::{request::Request, response::Response};
+
+/// The text pulled out of an item's offline copy.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IndexDoc {
+    pub title: Option<String>,
+    pub body: Option<String>,
+    pub extra: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct IndexJob {
+    pub meta: Meta,
+    pub offline: Option<OfflineData>,
+    /// The adapter the extractor belongs to
+    pub adapter: AdapterType,
+    pub extractor: Extractor,
+}
+
+// What to do with a job once a worker or the writer gets to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Index,
+    Cancelled,
+    // A later submission for the same item will report instead
+    Superseded,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    in_progress: AtomicUsize,
+    next_ticket: AtomicU64,
+    // The latest ticket of every item queued or being extracted
+    active: Mutex<BTreeMap<String, u64>>,
+    cancelled: Mutex<BTreeSet<u64>>,
+}
+
+impl State {
+    fn register(&self, id: &str) -> u64 {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        self.active.lock().unwrap().insert(id.to_string(), ticket);
+        ticket
+    }
+
+    fn cancel(&self, id: &str) -> bool {
+        match self.active.lock().unwrap().get(id) {
+            Some(ticket) => self.cancelled.lock().unwrap().insert(*ticket),
+            None => false,
+        }
+    }
+
+    fn cancel_all(&self) {
+        let active = self.active.lock().unwrap();
+        self.cancelled.lock().unwrap().extend(active.values());
+    }
+
+    fn outcome(&self, id: &str, ticket: u64) -> Outcome {
+        if self.active.lock().unwrap().get(id) != Some(&ticket) {
+            Outcome::Superseded
+        } else if self.cancelled.lock().unwrap().contains(&ticket) {
+            Outcome::Cancelled
+        } else {
+            Outcome::Index
+        }
+    }
+
+    // Called once a job leaves the pipeline, whether it was indexed or not
+    fn finish(&self, id: &str, ticket: u64) {
+        let mut active = self.active.lock().unwrap();
+        if active.get(id) == Some(&ticket) {
+            active.remove(id);
+        }
+
+        self.cancelled.lock().unwrap().remove(&ticket);
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    jobs: Sender<(u64, IndexJob)>,
+    queue: Receiver<(u64, IndexJob)>,
+    state: Arc<State>,
+}
+
+impl Pipeline {
+    pub fn spawn(
+        index: Arc<RwLock<Index>>,
+        sender: Sender<(Request, Option<oneshot::Sender<Response>>)>,
+        settings: &IndexSettings,
+    ) -> Self {
+        let (jobs, queue) = async_channel::bounded(settings.queue_size());
+        let (docs, writer_queue) = async_channel::bounded(settings.workers());
+        let state = Arc::new(State::default());
+
+        tracing::info!("[pipeline] starting {} worker(s)", settings.workers());
+
+        for _ in 0..settings.workers() {
+            tokio::spawn(worker(
+                queue.clone(),
+                docs.clone(),
+                sender.clone(),
+                state.clone(),
+            ));
+        }
+
+        tokio::spawn(writer(writer_queue, index, sender, state.clone()));
+
+        Self { jobs, queue, state }
+    }
+
+    /// Queue an item for indexing. The job is in the pipeline, and can be
+    /// cancelled, as soon as this returns; the future waits for room in the
+    /// queue, so it shouldn't be awaited where that would hold up requests.
+    pub fn submit(&self, job: IndexJob) -> impl Future<Output = Result<(), Error>> {
+        let ticket = self.state.register(job.meta.id());
+        let jobs = self.jobs.clone();
+
+        async move {
+            jobs.send((ticket, job))
+                .await
+                .map_err(|_| Error::Custom("index pipeline is closed".to_string()))
+        }
+    }
+
+    /// Drop the item if it is still waiting to be indexed. Returns `false`
+    /// if it isn't in the pipeline. The pipeline sends `IndexCancelled` once
+    /// the item is dropped.
+    pub fn cancel(&self, id: &str) -> bool {
+        self.state.cancel(id)
+    }
+
+    /// Drop everything that hasn't been indexed yet.
+    pub fn cancel_all(&self) {
+        self.state.cancel_all();
+    }
+
+    /// Items waiting for a worker.
+    pub fn queue_depth(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Items being extracted or written.
+    pub fn in_progress(&self) -> usize {
+        self.state.in_progress.load(Ordering::Relaxed)
+    }
+}
+
+/// Run an extractor on a blocking thread. `None` means there was nothing
+/// for it to extract.
+pub async fn extract(
+    extractor: Extractor,
+    meta: Meta,
+    offline: Option<OfflineData>,
+) -> Option<Result<IndexDoc, Error>> {
+    let offline = offline?;
+
+    match tokio::task::spawn_blocking(move || extractor(&meta, &offline)).await {
+        Ok(result) => result,
+        Err(e) => Some(Err(e.into())),
+    }
+}
+
+async fn worker(
+    queue: Receiver<(u64, IndexJob)>,
+    docs: Sender<(u64, Meta, Result<IndexDoc, String>)>,
+    sender: Sender<(Request, Option<oneshot::Sender<Response>>)>,
+    state: Arc<State>,
+) {
+    while let Ok((ticket, job)) = queue.recv().await {
+        let id = job.meta.id().to_string();
+
+        match state.outcome(&id, ticket) {
+            Outcome::Index => {}
+            outcome => {
+                if outcome == Outcome::Cancelled {
+                    report_cancelled(&sender, &id).await;
+                }
+                state.finish(&id, ticket);
+                continue;
+            }
+        }
+
+        state.in_progress.fetch_add(1, Ordering::Relaxed);
+
+        let doc = match extract(job.extractor, job.meta.clone(), job.offline).await {
+            Some(Ok(doc)) => Ok(doc),
+            Some(Err(e)) => Err(e.to_string()),
+            None => Err("nothing to index".to_string()),
+        };
+
+        if docs.send((ticket, job.meta, doc)).await.is_err() {
+            state.in_progress.fetch_sub(1, Ordering::Relaxed);
+            break;
+        }
+    }
+}
+
+async fn writer(
+    docs: Receiver<(u64, Meta, Result<IndexDoc, String>)>,
+    index: Arc<RwLock<Index>>,
+    sender: Sender<(Request, Option<oneshot::Sender<Response>>)>,
+    state: Arc<State>,
+) {
+    while let Ok((ticket, meta, doc)) = docs.recv().await {
+        let id = meta.id().to_string();
+
+        let result = match (state.outcome(&id, ticket), doc) {
+            (Outcome::Superseded, _) => Ok(()),
+            (Outcome::Cancelled, _) => {
+                report_cancelled(&sender, &id).await;
+                Ok(())
+            }
+            (Outcome::Index, Ok(doc)) => index
+                .write()
+                .await
+                .insert_doc(&meta, &doc)
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            (Outcome::Index, Err(e)) => Err(e),
+        };
+
+        // Successes are reported once the index commits them
+        if let Err(error) = result {
+            tracing::error!("[pipeline] [{}] failed to index: {}", id, error);
+            let _ = sender
+                .send((
+                    Request::IndexFailed {
+                        id: id.clone(),
+                        error,
+                    },
+                    None,
+                ))
+                .await;
+        }
+
+        state.finish(&id, ticket);
+        state.in_progress.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Whoever cancelled the item may not be around to reset its index status,
+// e.g. after `Request::Delete`
+async fn report_cancelled(sender: &Sender<(Request, Option<oneshot::Sender<Response>>)>, id: &str) {
+    tracing::info!("[pipeline] [{}] cancelled", id);
+
+    let _ = sender
+        .send((Request::IndexCancelled { id: id.to_string() }, None))
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn resubmitting_supersedes_the_earlier_job() {
+        let state = State::default();
+
+        let first = state.register("a1");
+        let second = state.register("a1");
+        assert_eq!(state.outcome("a1", first), Outcome::Superseded);
+        assert_eq!(state.outcome("a1", second), Outcome::Index);
+
+        // The earlier job leaving doesn't take the later one with it
+        state.finish("a1", first);
+        assert!(state.cancel("a1"));
+        assert_eq!(state.outcome("a1", second), Outcome::Cancelled);
+
+        state.finish("a1", second);
+        assert!(!state.cancel("a1"));
+    }
+
+    #[test]
+    fn cancelling_spares_later_jobs() {
+        let state = State::default();
+
+        let first = state.register("a1");
+        let other = state.register("b1");
+        state.cancel_all();
+
+        let second = state.register("a1");
+        assert_eq!(state.outcome("a1", first), Outcome::Superseded);
+        assert_eq!(state.outcome("a1", second), Outcome::Index);
+        assert_eq!(state.outcome("b1", other), Outcome::Cancelled);
+    }
+
+    fn job(id: &str) -> IndexJob {
+        IndexJob {
+            meta: Meta::builder().id(id.to_string()).build(),
+            offline: None,
+            adapter: AdapterType::Http,
+            extractor: |_, _| None,
+        }
+    }
+
+    #[tokio::test]
+    async fn only_the_latest_job_is_reported() {
+        let dir = TempDir::new().unwrap();
+        let settings = IndexSettings {
+            path: dir.path().to_path_buf(),
+            workers: 1,
+            ..IndexSettings::default()
+        };
+        let index = Arc::new(RwLock::new(Index::new(&settings).unwrap()));
+        let (sender, receiver) = async_channel::unbounded();
+        let pipeline = Pipeline::spawn(index, sender, &settings);
+
+        // Jobs can be cancelled before they reach the queue
+        let first = pipeline.submit(job("a1"));
+        assert!(pipeline.cancel("a1"));
+        let second = pipeline.submit(job("a1"));
+        first.await.unwrap();
+        second.await.unwrap();
+
+        // The first job is dropped quietly, the second one has no offline
+        // copy to index
+        match receiver.recv().await.unwrap() {
+            (Request::IndexFailed { id, .. }, None) => assert_eq!(id, "a1"),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(receiver.is_empty());
+    }
+}
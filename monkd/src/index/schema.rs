@@ -1,6 +1,6 @@
 use tantivy::schema::*;
 
-pub static SCHEMA_VERSION: &str = "0.0.0";
+pub static SCHEMA_VERSION: &str = "0.1.0";
 
 pub const ID: Field = Field::from_field_id(0);
 pub const NAME: Field = Field::from_field_id(1);
@@ -10,6 +10,11 @@ pub const BODY: Field = Field::from_field_id(4);
 pub const TITLE: Field = Field::from_field_id(5);
 pub const EXTRA: Field = Field::from_field_id(6);
 pub const FOUND: Field = Field::from_field_id(7);
+pub const DESCRIPTION: Field = Field::from_field_id(8);
+pub const AUTHOR: Field = Field::from_field_id(9);
+pub const SITE_NAME: Field = Field::from_field_id(10);
+pub const LANGUAGE: Field = Field::from_field_id(11);
+pub const PUBLISHED: Field = Field::from_field_id(12);
 
 pub fn current_schema() -> Schema {
     let mut builder = Schema::builder();
@@ -22,6 +27,11 @@ pub fn current_schema() -> Schema {
     let _ = builder.add_text_field("title", TEXT);
     let _ = builder.add_text_field("extra", TEXT);
     let _ = builder.add_date_field("found", FAST | INDEXED);
+    let _ = builder.add_text_field("description", TEXT);
+    let _ = builder.add_text_field("author", TEXT);
+    let _ = builder.add_text_field("site_name", TEXT);
+    let _ = builder.add_text_field("language", STRING);
+    let _ = builder.add_date_field("published", FAST | INDEXED);
 
     builder.build()
 }
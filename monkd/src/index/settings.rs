@@ -1,22 +1,74 @@
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct IndexSettings {
     pub(crate) path: PathBuf,
-    // commit_interval: usize
+    /// Milliseconds between commits of queued documents
+    #[serde(default = "default_commit_interval")]
+    pub(crate) commit_interval: u64,
+    /// Commit early once this many documents are queued
+    #[serde(default = "default_commit_batch")]
+    pub(crate) commit_batch: usize,
+    /// Number of items extracted in parallel
+    #[serde(default = "default_workers")]
+    pub(crate) workers: usize,
+    /// Items that can wait for a worker before new jobs wait for room
+    #[serde(default = "default_queue_size")]
+    pub(crate) queue_size: usize,
+}
+
+impl IndexSettings {
+    pub fn commit_interval(&self) -> Duration {
+        Duration::from_millis(self.commit_interval)
+    }
+
+    pub fn commit_batch(&self) -> usize {
+        self.commit_batch
+    }
+
+    pub fn workers(&self) -> usize {
+        std::cmp::max(self.workers, 1)
+    }
+
+    pub fn queue_size(&self) -> usize {
+        std::cmp::max(self.queue_size, 1)
+    }
 }
 
 impl Default for IndexSettings {
     fn default() -> Self {
-        if let Some(dirs) = crate::get_dirs() {
-            Self {
-                path: dirs.data_dir().join("index"),
-            }
+        let path = if let Some(dirs) = crate::get_dirs() {
+            dirs.data_dir().join("index")
         } else {
-            Self {
-                path: "./index".into(),
-            }
+            "./index".into()
+        };
+
+        Self {
+            path,
+            commit_interval: default_commit_interval(),
+            commit_batch: default_commit_batch(),
+            workers: default_workers(),
+            queue_size: default_queue_size(),
         }
     }
 }
+
+fn default_commit_interval() -> u64 {
+    2000
+}
+
+fn default_commit_batch() -> usize {
+    100
+}
+
+fn default_workers() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(2)
+}
+
+fn default_queue_size() -> usize {
+    256
+}
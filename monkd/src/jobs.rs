@@ -0,0 +1,588 @@
+// Persistent record of downloads and index runs.
+//
+// Every download or index request creates a `Job` (or reuses the item's last
+// job of that kind) which moves from `Queued` to `Running` to `Done` or
+// `Failed`. The queue is saved to `jobs.json` like the offline store: mutations
+// are journaled and the file is rewritten atomically on commit. When the
+// daemon starts, jobs that were running are queued again and dispatched, so
+// work interrupted by a crash or restart picks up where it stopped.
+//
+// How a job ended is reported through events and the offline store, so
+// finished jobs are dropped on the next start, and only the latest
+// `FINISHED_JOBS_KEPT` are kept in the meantime.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::adapter::AdapterType;
+use crate::error::Error;
+use crate::metadata::journal::{write_json_atomic, Journal};
+
+pub const CURRENT_JOB_QUEUE_VERSION: &str = "0.1.0";
+
+const FINISHED_JOBS_KEPT: usize = 100;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum JobKind {
+    Download,
+    Index,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobState {
+    Queued,
+    Running,
+    Failed(String),
+    Cancelled,
+    Done,
+}
+
+impl JobState {
+    /// Whether the job still has work to do.
+    pub fn is_pending(&self) -> bool {
+        matches!(self, JobState::Queued | JobState::Running)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    /// The id of the item this job works on
+    pub item: String,
+    pub kind: JobKind,
+    pub state: JobState,
+    /// The adapter doing the work, once the job is running
+    #[serde(default)]
+    pub adapter: Option<AdapterType>,
+    pub created: DateTime<Utc>,
+    #[serde(default)]
+    pub started: Option<DateTime<Utc>>,
+    pub updated: DateTime<Utc>,
+}
+
+/// A job as reported by `Request::Jobs`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobInfo {
+    pub id: String,
+    pub item: String,
+    pub kind: JobKind,
+    pub state: JobState,
+    pub adapter: Option<AdapterType>,
+    pub started: Option<DateTime<Utc>>,
+    /// Size of the item's offline copy so far, for downloads
+    pub bytes_downloaded: Option<u64>,
+}
+
+impl JobInfo {
+    pub fn new(job: &Job, bytes_downloaded: Option<u64>) -> Self {
+        Self {
+            id: job.id.clone(),
+            item: job.item.clone(),
+            kind: job.kind,
+            state: job.state.clone(),
+            adapter: job.adapter,
+            started: job.started,
+            bytes_downloaded,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobQueue {
+    version: String,
+    jobs: Vec<Job>,
+    #[serde(skip)]
+    file: PathBuf,
+    #[serde(skip)]
+    dirty: bool,
+    #[serde(skip)]
+    journal: Journal,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum JournalEntry {
+    Put(Job),
+    Delete(String),
+}
+
+impl Default for JobQueue {
+    fn default() -> Self {
+        Self {
+            version: CURRENT_JOB_QUEUE_VERSION.to_string(),
+            jobs: Vec::new(),
+            file: PathBuf::new(),
+            dirty: false,
+            journal: Journal::default(),
+        }
+    }
+}
+
+impl JobQueue {
+    pub fn file(&self) -> &Path {
+        &self.file
+    }
+
+    pub fn jobs(&self) -> &[Job] {
+        &self.jobs
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Job> {
+        self.jobs.iter().find(|j| j.id == id)
+    }
+
+    /// Find a job by a prefix of its id.
+    pub fn find_id(&self, description: &str) -> Result<&Job, Error> {
+        let ids: Vec<usize> = self
+            .jobs
+            .iter()
+            .enumerate()
+            .filter(|(_, j)| j.id.starts_with(description))
+            .map(|(i, _)| i)
+            .collect();
+
+        match ids.as_slice() {
+            [idx] => Ok(&self.jobs[*idx]),
+            [] => Err(Error::IdNotFound(description.into())),
+            _ => Err(Error::TooManyIds(description.into(), ids)),
+        }
+    }
+
+    /// The latest job of `kind` for `item`.
+    pub fn find(&self, item: &str, kind: JobKind) -> Option<&Job> {
+        self.jobs.iter().find(|j| j.item == item && j.kind == kind)
+    }
+
+    /// Whether a job is pending and has changed within `stale_after`. A job
+    /// whose task went away without reporting back, e.g. because it was
+    /// aborted, stops counting once it goes stale.
+    pub fn has_pending(&self, stale_after: chrono::Duration) -> bool {
+        let now = Utc::now();
+
+        self.jobs
+            .iter()
+            .any(|j| j.state.is_pending() && now - j.updated < stale_after)
+    }
+
+    /// Queue a job of `kind` for `item` and return its id. An item has at
+    /// most one job of each kind: a pending one is returned as is, and a
+    /// finished one is queued again.
+    pub fn push(&mut self, item: &str, kind: JobKind) -> String {
+        let now = Utc::now();
+
+        if let Some(idx) = self
+            .jobs
+            .iter()
+            .position(|j| j.item == item && j.kind == kind)
+        {
+            if !self.jobs[idx].state.is_pending() {
+                self.jobs[idx].state = JobState::Queued;
+                self.jobs[idx].updated = now;
+                self.changed(idx);
+            }
+
+            return self.jobs[idx].id.clone();
+        }
+
+        let job = Job {
+            id: crate::generate_id(),
+            item: item.to_string(),
+            kind,
+            state: JobState::Queued,
+            adapter: None,
+            created: now,
+            started: None,
+            updated: now,
+        };
+        let id = job.id.clone();
+
+        self.jobs.push(job);
+        self.changed(self.jobs.len() - 1);
+
+        id
+    }
+
+    pub fn set_state(&mut self, id: &str, state: JobState) -> Result<(), Error> {
+        let idx = self.position(id)?;
+
+        tracing::info!("[jobs] [{}] {:?} -> {:?}", id, self.jobs[idx].state, state);
+
+        let finished = !state.is_pending();
+
+        self.jobs[idx].state = state;
+        self.jobs[idx].updated = Utc::now();
+        self.changed(idx);
+
+        if finished {
+            self.prune();
+        }
+
+        Ok(())
+    }
+
+    /// Mark a job as running on `adapter`.
+    pub fn start(&mut self, id: &str, adapter: AdapterType) -> Result<(), Error> {
+        let idx = self.position(id)?;
+        let now = Utc::now();
+
+        tracing::info!("[jobs] [{}] running on {:?}", id, adapter);
+
+        let job = &mut self.jobs[idx];
+        job.state = JobState::Running;
+        job.adapter = Some(adapter);
+        job.started = Some(now);
+        job.updated = now;
+        self.changed(idx);
+
+        Ok(())
+    }
+
+    /// Finish the pending job of `kind` for `item`, if there is one.
+    pub fn finish(&mut self, item: &str, kind: JobKind, state: JobState) {
+        let id = match self.find(item, kind) {
+            Some(job) if job.state.is_pending() => job.id.clone(),
+            _ => return,
+        };
+
+        let _ = self.set_state(&id, state);
+    }
+
+    /// Drop every job for `item`, e.g. after it was deleted.
+    pub fn remove_item(&mut self, item: &str) {
+        let removed: Vec<String> = self
+            .jobs
+            .iter()
+            .filter(|j| j.item == item)
+            .map(|j| j.id.clone())
+            .collect();
+
+        for id in removed {
+            self.delete(id);
+        }
+    }
+
+    /// Prepare the queue after a restart. Finished jobs, whether they were
+    /// done, failed or cancelled, are dropped and jobs that were running are
+    /// queued again. Returns every queued job.
+    pub fn recover(&mut self) -> Vec<Job> {
+        let finished: Vec<String> = self
+            .jobs
+            .iter()
+            .filter(|j| !j.state.is_pending())
+            .map(|j| j.id.clone())
+            .collect();
+
+        for id in finished {
+            self.delete(id);
+        }
+
+        self.interrupt();
+
+        self.jobs
+            .iter()
+            .filter(|j| j.state == JobState::Queued)
+            .cloned()
+            .collect()
+    }
+
+    /// Queue every running job again, so it's resumed on the next start.
+    /// Returns how many were running.
+    pub fn interrupt(&mut self) -> usize {
+        let running: Vec<String> = self
+            .jobs
+            .iter()
+            .filter(|j| j.state == JobState::Running)
+            .map(|j| j.id.clone())
+            .collect();
+
+        for id in &running {
+            tracing::info!("[jobs] [{}] was interrupted", id);
+            let _ = self.set_state(id, JobState::Queued);
+        }
+
+        running.len()
+    }
+
+    // Drop the oldest finished jobs beyond `FINISHED_JOBS_KEPT`
+    fn prune(&mut self) {
+        let mut finished: Vec<(DateTime<Utc>, String)> = self
+            .jobs
+            .iter()
+            .filter(|j| !j.state.is_pending())
+            .map(|j| (j.updated, j.id.clone()))
+            .collect();
+
+        if finished.len() <= FINISHED_JOBS_KEPT {
+            return;
+        }
+
+        finished.sort_by_key(|(updated, _)| *updated);
+        let excess = finished.len() - FINISHED_JOBS_KEPT;
+
+        for (_, id) in finished.into_iter().take(excess) {
+            self.delete(id);
+        }
+    }
+
+    fn delete(&mut self, id: String) {
+        self.jobs.retain(|j| j.id != id);
+        self.log(JournalEntry::Delete(id));
+        self.dirty = true;
+    }
+
+    fn position(&self, id: &str) -> Result<usize, Error> {
+        self.jobs
+            .iter()
+            .position(|j| j.id == id)
+            .ok_or_else(|| Error::IdNotFound(id.to_string()))
+    }
+
+    fn changed(&mut self, idx: usize) {
+        self.dirty = true;
+        self.log(JournalEntry::Put(self.jobs[idx].clone()));
+    }
+
+    fn replay(&mut self, entry: JournalEntry) {
+        let id = match &entry {
+            JournalEntry::Put(job) => &job.id,
+            JournalEntry::Delete(id) => id,
+        };
+
+        let existing = self.jobs.iter().position(|j| &j.id == id);
+
+        match (entry, existing) {
+            (JournalEntry::Put(job), Some(idx)) => self.jobs[idx] = job,
+            (JournalEntry::Put(job), None) => self.jobs.push(job),
+            (JournalEntry::Delete(_), Some(idx)) => {
+                self.jobs.remove(idx);
+            }
+            (JournalEntry::Delete(_), None) => {}
+        }
+
+        self.dirty = true;
+    }
+
+    fn log(&self, entry: JournalEntry) {
+        if let Err(e) = self.journal.append(&entry) {
+            tracing::error!("JobQueue journal: {}", e);
+        }
+    }
+
+    pub fn read_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut queue = if path.exists() {
+            let reader = BufReader::new(File::open(path)?);
+            serde_json::from_reader(reader)?
+        } else {
+            JobQueue::default()
+        };
+        queue.file = path.into();
+
+        let journal = Journal::for_store(path);
+        let entries: Vec<JournalEntry> = journal.replay()?;
+        if !entries.is_empty() {
+            tracing::info!("Replaying {} journal entries", entries.len());
+            for entry in entries {
+                queue.replay(entry);
+            }
+        }
+        queue.journal = journal;
+
+        Ok(queue)
+    }
+
+    pub fn write_file(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        write_json_atomic(path, self)
+    }
+
+    pub fn commit(&mut self) -> Result<(), Error> {
+        if self.dirty {
+            self.write_file(&self.file)?;
+            self.dirty = false;
+
+            if let Err(e) = self.journal.clear() {
+                tracing::error!("JobQueue journal: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn commit_loop(
+        handle: Arc<RwLock<JobQueue>>,
+        delay: std::time::Duration,
+    ) -> Result<(), Error> {
+        loop {
+            tokio::time::sleep(delay).await;
+
+            let _ = handle
+                .write()
+                .await
+                .commit()
+                .map_err(|e| tracing::error!("JobQueue: {}", e));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobSettings {
+    pub(crate) file: PathBuf,
+    /// How long, in milliseconds, a pending job that hasn't changed keeps
+    /// monkd from exiting on `daemon.timeout`
+    #[serde(default = "default_stale_after")]
+    pub(crate) stale_after: u64,
+}
+
+impl JobSettings {
+    pub fn stale_after(&self) -> chrono::Duration {
+        chrono::Duration::milliseconds(self.stale_after as i64)
+    }
+}
+
+fn default_stale_after() -> u64 {
+    1_800_000
+}
+
+impl Default for JobSettings {
+    fn default() -> Self {
+        if let Some(dirs) = crate::get_dirs() {
+            Self {
+                file: dirs.data_dir().join("jobs.json"),
+                stale_after: default_stale_after(),
+            }
+        } else {
+            Self {
+                file: "jobs.json".into(),
+                stale_after: default_stale_after(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn state(queue: &JobQueue, id: &str) -> JobState {
+        queue.get(id).unwrap().state.clone()
+    }
+
+    #[test]
+    fn jobs_survive_a_reload() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("jobs.json");
+
+        let mut queue = JobQueue::read_file(&path).unwrap();
+        let download = queue.push("a1", JobKind::Download);
+        let index = queue.push("a1", JobKind::Index);
+        queue.start(&download, AdapterType::Http).unwrap();
+        queue.commit().unwrap();
+
+        // Only in the journal
+        queue.set_state(&index, JobState::Done).unwrap();
+        let queued = queue.push("b1", JobKind::Index);
+        drop(queue);
+
+        let mut queue = JobQueue::read_file(&path).unwrap();
+        assert_eq!(queue.jobs().len(), 3);
+        assert_eq!(state(&queue, &download), JobState::Running);
+        assert_eq!(
+            queue.get(&download).unwrap().adapter,
+            Some(AdapterType::Http)
+        );
+        assert_eq!(state(&queue, &index), JobState::Done);
+        assert_eq!(state(&queue, &queued), JobState::Queued);
+
+        // The interrupted download is queued again, and the finished index
+        // run dropped
+        let resumed: Vec<String> = queue.recover().into_iter().map(|j| j.id).collect();
+        assert_eq!(resumed, vec![download.clone(), queued.clone()]);
+        assert!(queue.get(&index).is_none());
+        queue.commit().unwrap();
+        drop(queue);
+
+        let queue = JobQueue::read_file(&path).unwrap();
+        let ids: Vec<&str> = queue.jobs().iter().map(|j| j.id.as_str()).collect();
+        assert_eq!(ids, vec![download.as_str(), queued.as_str()]);
+    }
+
+    #[test]
+    fn cancelled_and_failed_jobs_are_not_resumed() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("jobs.json");
+
+        let mut queue = JobQueue::read_file(&path).unwrap();
+        let cancelled = queue.push("a1", JobKind::Download);
+        let failed = queue.push("b1", JobKind::Download);
+        queue.start(&cancelled, AdapterType::Youtube).unwrap();
+        queue.set_state(&cancelled, JobState::Cancelled).unwrap();
+        queue
+            .set_state(&failed, JobState::Failed("404".to_string()))
+            .unwrap();
+
+        assert!(!queue.has_pending(chrono::Duration::minutes(30)));
+        queue.commit().unwrap();
+        drop(queue);
+
+        let mut queue = JobQueue::read_file(&path).unwrap();
+        assert_eq!(state(&queue, &cancelled), JobState::Cancelled);
+        assert!(queue.recover().is_empty());
+        assert!(queue.jobs().is_empty());
+
+        // Downloading the item again starts a new job
+        let again = queue.push("a1", JobKind::Download);
+        assert_ne!(again, cancelled);
+        assert_eq!(state(&queue, &again), JobState::Queued);
+    }
+
+    #[test]
+    fn finished_jobs_are_capped() {
+        let mut queue = JobQueue::default();
+
+        for i in 0..FINISHED_JOBS_KEPT + 10 {
+            let id = queue.push(&format!("item{}", i), JobKind::Download);
+            queue
+                .set_state(&id, JobState::Failed("404".to_string()))
+                .unwrap();
+        }
+        let pending = queue.push("pending", JobKind::Download);
+
+        assert_eq!(queue.jobs().len(), FINISHED_JOBS_KEPT + 1);
+        assert!(queue.find("item0", JobKind::Download).is_none());
+        assert!(queue.find("item109", JobKind::Download).is_some());
+        assert_eq!(state(&queue, &pending), JobState::Queued);
+    }
+
+    #[test]
+    fn jobs_are_found_by_prefix() {
+        let mut queue = JobQueue::default();
+        queue.push("a1", JobKind::Download);
+        queue.push("a2", JobKind::Download);
+        queue.push("a3", JobKind::Download);
+        queue.jobs[0].id = "f00d".to_string();
+        queue.jobs[1].id = "beef".to_string();
+        queue.jobs[2].id = "f00ba4".to_string();
+
+        assert_eq!(queue.find_id("be").unwrap().item, "a2");
+        assert_eq!(queue.find_id("f00b").unwrap().item, "a3");
+
+        match queue.find_id("f00") {
+            Err(Error::TooManyIds(description, ids)) => {
+                assert_eq!(description, "f00");
+                assert_eq!(ids, vec![0, 2]);
+            }
+            other => panic!("expected TooManyIds, got {:?}", other),
+        }
+
+        assert!(matches!(queue.find_id("c"), Err(Error::IdNotFound(_))));
+    }
+}
@@ -1,16 +1,26 @@
 pub mod adapter;
 pub mod daemon;
 pub mod error;
+pub mod events;
 pub mod index;
+pub mod jobs;
+pub mod lock;
 pub mod metadata;
+pub mod migration;
 pub mod server;
+pub mod service;
 pub mod settings;
 pub mod status;
 
 use anyhow::Result;
 
-use crate::adapter::{http::HttpAdapter, youtube::YoutubeAdapter, Adapter, AdapterType};
+use crate::adapter::{
+    file::FileAdapter, http::HttpAdapter, note::NoteAdapter, pdf::PdfAdapter,
+    youtube::YoutubeAdapter, Adapter, AdapterType,
+};
 use crate::daemon::Daemon;
+use crate::error::Error;
+use crate::lock::DaemonLock;
 use crate::server::{request::Request, response::Response, Server};
 use crate::settings::Settings;
 
@@ -23,23 +33,41 @@ use tokio::time::timeout;
 use std::time::{Duration, Instant};
 use tokio::sync::oneshot;
 
+/// Run the daemon until it's stopped. In the `foreground` it runs until then,
+/// otherwise it also exits after `daemon.timeout` ms without a request.
 #[tracing::instrument(skip(settings))]
-pub async fn run(settings: Settings) -> Result<()> {
+pub async fn run(settings: Settings, foreground: bool) -> Result<()> {
     let start_time = Instant::now();
 
+    let addr = SocketAddr::new(settings.daemon().address, settings.daemon().port);
+
+    // The web interface and the API would be open to anyone who can reach
+    // the address
+    if settings.daemon().tcp && settings.daemon().token.is_none() && !addr.ip().is_loopback() {
+        return Err(Error::NoToken(addr.to_string()).into());
+    }
+
+    // Held until monkd exits
+    let _lock = DaemonLock::acquire(&settings)?;
+
     let (sender, receiver) = async_channel::unbounded();
     let (shutdown, signal) = oneshot::channel::<()>();
 
-    let addr = SocketAddr::new(settings.daemon().address, settings.daemon().port);
-
     tracing::info!("monkd listend on port: {:?}", addr);
 
-    tokio::spawn(Server::spawn(addr, sender.clone(), signal));
+    let events = events::channel();
+
+    tokio::spawn(Server::spawn(
+        settings.clone(),
+        sender.clone(),
+        events.clone(),
+        signal,
+    ));
     let timeout_duration = Duration::from_millis(settings.daemon().timeout as u64);
 
     let adapters = create_adapters(&settings, sender.clone());
 
-    let mut daemon = match Daemon::new(&settings, sender.clone(), adapters.clone()) {
+    let mut daemon = match Daemon::new(&settings, sender.clone(), adapters.clone(), events) {
         Ok(d) => d,
         Err(e) => {
             tracing::error!("error creating daemon: {}", e);
@@ -47,11 +75,30 @@ pub async fn run(settings: Settings) -> Result<()> {
         }
     };
 
+    let signal = service::shutdown_signal();
+    tokio::pin!(signal);
+
+    service::notify_ready();
+
     'main: loop {
         // let mut timeout = tokio::time::delay_for(timeout_duration).boxed().fuse();
-        let request_future = timeout(timeout_duration, receiver.recv());
+        let request_future = async {
+            if foreground {
+                Ok(receiver.recv().await)
+            } else {
+                timeout(timeout_duration, receiver.recv()).await
+            }
+        };
 
-        if let Ok(request) = request_future.await {
+        let request = tokio::select! {
+            request = request_future => request,
+            _ = &mut signal => {
+                tracing::info!("Shutdown signal received");
+                break 'main;
+            }
+        };
+
+        if let Ok(request) = request {
             let (request, response) = request?;
 
             if let Request::Stop = request {
@@ -65,27 +112,44 @@ pub async fn run(settings: Settings) -> Result<()> {
                 break 'main;
             }
 
+            if let Request::ForceShutdown = request {
+                tracing::warn!("Force Shutdown Request Received");
+
+                if let Some(response) = response {
+                    let _ = response.send(Response::Ok);
+                }
+
+                service::notify_stopping();
+                let _ = shutdown.send(());
+                daemon.force_shutdown().await?;
+
+                tracing::info!(
+                    "Forced shutdown after {:3.4} s.",
+                    start_time.elapsed().as_secs_f32()
+                );
+
+                return Ok(());
+            }
+
             tracing::trace!("Recieved request: {:?}", request);
 
-            let res = match daemon.handle_request(request).await {
-                r @ Ok(_) => r,
-                Err(e) if e.is_client_error() => Ok(Response::from(e)),
-                Err(e) => Err(e),
-            }?;
+            let res = daemon.handle_request(request).await;
 
             tracing::trace!("Processed Result: {:?}", res);
             tracing::trace!("Result requested: {}", response.is_some());
 
-            // Only send the respone if it was requested
-            if let Some(response) = response {
-                let _ = response.send(res);
-            }
+            respond(res, response);
+        } else if daemon.has_pending_jobs().await {
+            // Don't exit in the middle of a download or index run
+            tracing::info!("Timeout Reached, waiting for pending jobs");
         } else {
             tracing::info!("Timeout Reached");
             break;
         }
     }
 
+    service::notify_stopping();
+
     // Shutdown all adapters first, making sure
     // all messages are sent. This is weird since
     // we're calling "handle" request on the adapters
@@ -106,16 +170,7 @@ pub async fn run(settings: Settings) -> Result<()> {
 
     while !receiver.is_empty() {
         if let Ok((request, response)) = receiver.recv().await {
-            let res = match daemon.handle_request(request).await {
-                r @ Ok(_) => r,
-                Err(e) if e.is_client_error() => Ok(Response::from(e)),
-                Err(e) => Err(e),
-            }?;
-
-            // Only send the respone if it was requested
-            if let Some(response) = response {
-                let _ = response.send(res);
-            }
+            respond(daemon.handle_request(request).await, response);
         }
     }
 
@@ -131,6 +186,25 @@ pub async fn run(settings: Settings) -> Result<()> {
     Ok(())
 }
 
+// Send the result of a request to whoever asked for it. Internal errors are
+// only logged, and dropping `response` tells the client the request failed,
+// so one bad request doesn't take the whole daemon down.
+pub(crate) fn respond(res: Result<Response, Error>, response: Option<oneshot::Sender<Response>>) {
+    let res = match res {
+        Ok(res) => res,
+        Err(e) if e.is_client_error() => Response::from(e),
+        Err(e) => {
+            tracing::error!("failed to handle request: {}", e);
+            return;
+        }
+    };
+
+    // Only send the respone if it was requested
+    if let Some(response) = response {
+        let _ = response.send(res);
+    }
+}
+
 pub fn generate_id() -> String {
     use uuid::Uuid;
     Uuid::new_v4().to_string()
@@ -146,6 +220,9 @@ fn create_adapters(
 ) -> Vec<Lock<Box<dyn Adapter>>> {
     let mut adapters: Vec<Lock<Box<dyn Adapter>>> = Vec::new();
 
+    // Notes are written by monkd itself, so they can always be indexed
+    adapters.push(Lock::new(Box::new(NoteAdapter)));
+
     for a_type in settings.adapters() {
         match a_type {
             AdapterType::Youtube => {
@@ -161,6 +238,15 @@ fn create_adapters(
                 settings.offline().data_folder.clone(),
                 sender.clone(),
             )))),
+            AdapterType::Pdf => adapters.push(Lock::new(Box::new(PdfAdapter::new(
+                settings.offline().data_folder.clone(),
+                sender.clone(),
+            )))),
+            AdapterType::File => adapters.push(Lock::new(Box::new(FileAdapter::new(
+                settings.offline().data_folder.clone(),
+                sender.clone(),
+            )))),
+            AdapterType::Note => {}
         }
     }
 
@@ -0,0 +1,218 @@
+// The daemon's lock file.
+//
+// monkd holds an exclusive lock on `monkd.lock`, next to the store, for as
+// long as it runs, so two daemons can't share a data directory. The file
+// records where the daemon listens, which is how `monk` finds it. The lock is
+// released by the OS when the daemon exits, even if it crashed, so a lock file
+// that nobody holds is stale and is taken over.
+
+use chrono::{DateTime, Utc};
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use crate::error::Error;
+use crate::settings::{Settings, TlsSettings};
+
+pub const LOCK_FILE: &str = "monkd.lock";
+
+/// What a running daemon writes to its lock file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockInfo {
+    pub pid: u32,
+    /// `None` if the daemon doesn't listen on TCP
+    pub address: Option<SocketAddr>,
+    pub tls: Option<TlsSettings>,
+    pub socket: Option<PathBuf>,
+    pub config: PathBuf,
+    pub started: DateTime<Utc>,
+}
+
+impl LockInfo {
+    fn new(settings: &Settings) -> Self {
+        let daemon = settings.daemon();
+        let address = if daemon.tcp {
+            Some(SocketAddr::new(daemon.address, daemon.port))
+        } else {
+            None
+        };
+
+        Self {
+            pid: std::process::id(),
+            address,
+            tls: daemon.tls.clone(),
+            socket: daemon.socket.clone(),
+            config: settings.config_path().clone(),
+            started: Utc::now(),
+        }
+    }
+}
+
+/// The daemon's hold on its data directory, released when dropped.
+#[derive(Debug)]
+pub struct DaemonLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl DaemonLock {
+    /// Lock the data directory, failing with `Error::AlreadyRunning` if
+    /// another daemon has it.
+    pub fn acquire(settings: &Settings) -> Result<Self, Error> {
+        Self::acquire_at(path(settings), &LockInfo::new(settings))
+    }
+
+    fn acquire_at(path: PathBuf, info: &LockInfo) -> Result<Self, Error> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            // Only emptied once the lock is ours, it may be a live daemon's
+            .truncate(false)
+            .open(&path)?;
+
+        if file.try_lock_exclusive().is_err() {
+            let holder = match read_info(&mut file) {
+                Some(info) => format!("pid {}", info.pid),
+                None => "another process".to_string(),
+            };
+
+            return Err(Error::AlreadyRunning(format!(
+                "{} is locked by {}",
+                path.display(),
+                holder
+            )));
+        }
+
+        // Whatever is in the file was left by a daemon that is gone
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        serde_json::to_writer_pretty(&mut file, info)?;
+        file.flush()?;
+        file.sync_all()?;
+
+        tracing::info!("locked {}", path.display());
+
+        Ok(Self { file, path })
+    }
+}
+
+impl Drop for DaemonLock {
+    fn drop(&mut self) {
+        // Remove the file while still holding the lock, so it never looks
+        // like a live daemon's
+        let _ = std::fs::remove_file(&self.path);
+        let _ = self.file.unlock();
+    }
+}
+
+/// The lock file for the data directory in `settings`.
+pub fn path(settings: &Settings) -> PathBuf {
+    settings.store().path.with_file_name(LOCK_FILE)
+}
+
+/// The running daemon for the data directory in `settings`, or `None` if
+/// there is none.
+pub fn read(settings: &Settings) -> Option<LockInfo> {
+    read_at(&path(settings))
+}
+
+fn read_at(path: &Path) -> Option<LockInfo> {
+    let mut file = File::open(path).ok()?;
+
+    // Nobody holds the lock, so the file is stale
+    if file.try_lock_shared().is_ok() {
+        let _ = file.unlock();
+        return None;
+    }
+
+    read_info(&mut file)
+}
+
+fn read_info(file: &mut File) -> Option<LockInfo> {
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut contents).ok()?;
+
+    serde_json::from_str(&contents).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn info() -> LockInfo {
+        LockInfo {
+            pid: 4242,
+            address: Some("127.0.0.1:41562".parse().unwrap()),
+            tls: None,
+            socket: Some(PathBuf::from("/run/monkd.sock")),
+            config: PathBuf::from("/etc/monkd.yaml"),
+            started: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn lock_file_describes_the_daemon() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("data").join(LOCK_FILE);
+        let info = info();
+
+        let lock = DaemonLock::acquire_at(path.clone(), &info).unwrap();
+        let read = read_at(&path).unwrap();
+
+        assert_eq!(read.pid, info.pid);
+        assert_eq!(read.address, info.address);
+        assert_eq!(read.socket, info.socket);
+        assert_eq!(read.config, info.config);
+        assert_eq!(read.started, info.started);
+
+        drop(lock);
+        assert!(!path.exists());
+        assert!(read_at(&path).is_none());
+    }
+
+    #[test]
+    fn second_daemon_is_refused() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join(LOCK_FILE);
+
+        let _lock = DaemonLock::acquire_at(path.clone(), &info()).unwrap();
+
+        match DaemonLock::acquire_at(path.clone(), &info()) {
+            Err(Error::AlreadyRunning(message)) => assert!(message.contains("pid 4242")),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // The refused daemon leaves the holder's file alone
+        assert_eq!(read_at(&path).unwrap().pid, 4242);
+    }
+
+    #[test]
+    fn stale_lock_is_taken_over() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join(LOCK_FILE);
+
+        // Left by a daemon that crashed, with more in it than the next one
+        // writes
+        let mut stale = info();
+        stale.pid = 1;
+        stale.config = PathBuf::from("/a/much/longer/path/to/an/old/monkd.yaml");
+        std::fs::write(&path, serde_json::to_string_pretty(&stale).unwrap()).unwrap();
+        assert!(read_at(&path).is_none());
+
+        let _lock = DaemonLock::acquire_at(path.clone(), &info()).unwrap();
+        let read = read_at(&path).unwrap();
+
+        assert_eq!(read.pid, 4242);
+        assert_eq!(read.config, PathBuf::from("/etc/monkd.yaml"));
+    }
+}
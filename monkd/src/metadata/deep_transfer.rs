@@ -4,7 +4,7 @@
 // offline.json ---> need to also set all indexed status to not done
 // offline/
 
-use super::{offline_store::OfflineStore, FileStore};
+use super::{offline_store::OfflineStore, MetaStore};
 use crate::error::Error;
 use crate::settings::Settings;
 use std::fs::File;
@@ -17,7 +17,7 @@ use zip::write::FileOptions;
 
 pub fn import_deep_copy(
     file: impl AsRef<Path>,
-    filestore: &mut FileStore,
+    filestore: &mut dyn MetaStore,
     offline_store: &mut OfflineStore,
 ) -> Result<(), Error> {
     let f = std::fs::File::open(file)?;
@@ -31,7 +31,7 @@ pub fn import_deep_copy(
     let offline_data = settings.offline().data_folder.clone();
     let import_offline_data = tmp_dir.path().join("offline");
 
-    filestore.import_file(meta_file.to_str().unwrap_or_default().to_string())?;
+    filestore.import_file(&meta_file)?;
     offline_store.import(import_offline_store)?;
     if let Ok(entries) = std::fs::read_dir(import_offline_data) {
         for entry in entries {
@@ -45,11 +45,15 @@ pub fn import_deep_copy(
     Ok(())
 }
 
-pub fn export_deep_copy(file: PathBuf) -> Result<(), Error> {
+pub fn export_deep_copy(file: PathBuf, store: &dyn MetaStore) -> Result<(), Error> {
     let out_file = std::fs::File::create(file).unwrap();
     let mut zip = zip::ZipWriter::new(BufWriter::with_capacity(65536, out_file));
     let settings = Settings::get_settings(None)?;
-    let meta_file = settings.store().path.clone();
+
+    // The store may not be a json file, so always export a fresh store.json
+    let tmp_dir = TempDir::new()?;
+    let meta_file = tmp_dir.path().join("store.json");
+    store.write_file(&meta_file)?;
     let offline_store = settings.offline().store_file.clone();
     let offline_data = settings.offline().data_folder.clone();
     let options = FileOptions::default()
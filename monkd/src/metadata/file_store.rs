@@ -5,7 +5,7 @@
 
 use std::collections::{BTreeMap, BTreeSet};
 use std::fs::File;
-use std::io::{BufReader, BufWriter};
+use std::io::BufReader;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
@@ -15,10 +15,32 @@ use tracing::{debug, info, instrument};
 
 use crate::server::request::Edit;
 
-use super::Meta;
+use super::{
+    journal::{write_json_atomic, Journal},
+    store::MetaStore,
+    Meta,
+};
 use crate::error::Error;
-
-pub const CURRENT_FILE_STORE_VERSION: &str = "0.1.0";
+use crate::migration::{self, Migration};
+
+pub const CURRENT_FILE_STORE_VERSION: &str = "0.2.0";
+
+/// Upgrade steps for `store.json`, run on the raw json before it is
+/// deserialized. Stores written before versioning are treated as `0.0.0`.
+const MIGRATIONS: &[Migration<serde_json::Value>] = &[
+    Migration {
+        from: "0.0.0",
+        to: "0.1.0",
+        description: "add `tags` and `last_updated` to every meta",
+        run: add_tags_and_last_updated,
+    },
+    Migration {
+        from: "0.1.0",
+        to: "0.2.0",
+        description: "rebuild the tag -> id map from each meta's tags",
+        run: rebuild_tag_map,
+    },
+];
 
 #[derive(Default, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct FileStore {
@@ -30,6 +52,15 @@ pub struct FileStore {
     dirty: bool,
     #[serde(default)]
     tags: BTreeMap<String, BTreeSet<String>>, // tag -> id
+    #[serde(skip)]
+    journal: Journal,
+}
+
+// Mutations are journaled as the resulting meta so replaying is idempotent.
+#[derive(Debug, Serialize, Deserialize)]
+enum JournalEntry {
+    Put(Meta),
+    Delete(String),
 }
 
 impl FileStore {
@@ -40,23 +71,26 @@ impl FileStore {
             file: PathBuf::new(),
             dirty: false,
             tags: BTreeMap::new(),
+            journal: Journal::default(),
+        }
+    }
+
+    /// Builds an in-memory store holding `metas`, mostly used to export other
+    /// stores in the `store.json` format.
+    pub fn from_metas(metas: Vec<Meta>) -> Self {
+        let mut store = Self::empty();
+        for meta in metas {
+            store.push(meta);
         }
+        store.dirty = false;
+        store
     }
 
     #[instrument(level = "debug", skip(self))]
     pub fn push(&mut self, meta: Meta) {
         self.dirty = true;
-        if !meta.tags.is_empty() {
-            for tag in meta.tags.iter() {
-                if let Some(ids) = self.tags.get_mut(tag) {
-                    ids.insert(meta.id.clone());
-                } else {
-                    let mut id_set = BTreeSet::new();
-                    id_set.insert(meta.id.clone());
-                    self.tags.insert(tag.clone(), id_set);
-                }
-            }
-        }
+        self.tag(&meta.id, meta.tags.iter());
+        self.log(JournalEntry::Put(meta.clone()));
         self.metadata.push(meta);
     }
 
@@ -74,30 +108,90 @@ impl FileStore {
 
     #[instrument(level = "debug", fields(path))]
     pub fn read_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let (mut store, upgraded_from) = Self::parse_file(&path)?;
+
+        if let Some(version) = upgraded_from {
+            // Keep the original around in case an upgrade step goes wrong
+            let mut backup = path.as_ref().as_os_str().to_owned();
+            backup.push(format!(".v{}.bak", version));
+            std::fs::copy(&path, &backup)?;
+
+            info!(
+                "Upgraded FileStore {} -> {}, backup in {:?}",
+                version, CURRENT_FILE_STORE_VERSION, backup
+            );
+            store.write_file(&path)?;
+        }
+
+        // Replay anything that happened after the last commit. The journal
+        // is only attached afterwards so replaying doesn't journal again.
+        let journal = Journal::for_store(&path);
+        store.replay_journal(&journal)?;
+        store.journal = journal;
+
+        // This is more me just playing around with tracing
+        tracing::Span::current().record("path", &format!("{:?}", store.file).as_str());
+
+        Ok(store)
+    }
+
+    /// Read the store at `path` without writing to it or its journal. An
+    /// older format is only upgraded in memory. Used to import a
+    /// `store.json` into another backend.
+    #[instrument(level = "debug", fields(path))]
+    pub fn load_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let (mut store, _) = Self::parse_file(&path)?;
+        store.replay_journal(&Journal::for_store(&path))?;
+
+        // Replaying marks the store dirty, and it would be written back when
+        // dropped
+        store.dirty = false;
+
+        Ok(store)
+    }
+
+    // Deserialize the store at `path`, upgrading it to the current version.
+    // Returns the version it was upgraded from, if it was.
+    fn parse_file(path: impl AsRef<Path>) -> Result<(Self, Option<String>), Error> {
         check_path(&path)?;
         debug!("Reading FileStore file");
 
         let file = File::open(&path)?;
         let reader = BufReader::new(file);
 
-        let mut store: FileStore = serde_json::from_reader(reader)?;
+        let mut value: serde_json::Value = serde_json::from_reader(reader)?;
+        let version = value
+            .get("version")
+            .and_then(|v| v.as_str())
+            .filter(|v| !v.is_empty())
+            .unwrap_or("0.0.0")
+            .to_string();
+
+        let migrated =
+            migration::run(&mut value, &version, CURRENT_FILE_STORE_VERSION, MIGRATIONS)?;
+        value["version"] = CURRENT_FILE_STORE_VERSION.into();
+
+        let mut store: FileStore = serde_json::from_value(value)?;
         store.file = path.as_ref().into();
 
-        // This is more me just playing around with tracing
-        tracing::Span::current().record("path", &format!("{:?}", store.file).as_str());
+        Ok((store, Some(version).filter(|_| migrated)))
+    }
 
-        Ok(store)
+    fn replay_journal(&mut self, journal: &Journal) -> Result<(), Error> {
+        let entries: Vec<JournalEntry> = journal.replay()?;
+        if !entries.is_empty() {
+            info!("Replaying {} journal entries", entries.len());
+            for entry in entries {
+                self.replay(entry);
+            }
+        }
+
+        Ok(())
     }
 
     #[instrument(level = "debug", skip(self, path))]
     pub fn write_file(&self, path: impl AsRef<Path>) -> Result<(), Error> {
-        dbg!(path.as_ref());
-        let file = File::create(path)?;
-        let writer = BufWriter::new(file);
-
-        serde_json::to_writer_pretty(writer, self)?;
-
-        Ok(())
+        write_json_atomic(path, self)
     }
 
     #[instrument(level = "debug", skip(self), fields(description = description.as_ref()))]
@@ -190,7 +284,15 @@ impl FileStore {
         }
 
         let meta = self.get_mut(&id)?;
-        *meta = data;
+        let old = std::mem::replace(meta, data.clone());
+
+        // Keep the tag -> id map in step with the new tags
+        self.untag(&old.id, old.tags.difference(&data.tags));
+        self.tag(&data.id, data.tags.difference(&old.tags));
+
+        // Only once the item is known to exist, or replaying the journal
+        // would bring a deleted item back
+        self.log(JournalEntry::Put(data));
 
         self.dirty = true;
 
@@ -213,22 +315,17 @@ impl FileStore {
         if let Some(c) = edit.comment.as_ref() {
             self.metadata[id].comment = Some(c.clone());
         }
+        let meta_id = self.metadata[id].id.clone();
         for tag in edit.add_tags.iter() {
-            if let Some(ids) = self.tags.get_mut(tag) {
-                ids.insert(self.metadata[id].id.clone());
-            } else {
-                let mut id_set = BTreeSet::new();
-                id_set.insert(self.metadata[id].id.clone());
-                self.tags.insert(tag.clone(), id_set);
-            }
             self.metadata[id].tags.insert(tag.clone());
         }
         for tag in edit.remove_tags.iter() {
-            if let Some(ids) = self.tags.get_mut(tag) {
-                ids.remove(&self.metadata[id].id);
-            }
             self.metadata[id].tags.remove(tag);
         }
+        self.tag(&meta_id, edit.add_tags.iter());
+        self.untag(&meta_id, edit.remove_tags.iter());
+
+        self.log(JournalEntry::Put(self.metadata[id].clone()));
 
         Ok(self.metadata[id].clone())
     }
@@ -238,18 +335,65 @@ impl FileStore {
         let id = self.find_id(&description)?;
 
         tracing::info!("Deleting: `{}`", description.as_ref());
+
+        let removed = self.remove_at(id);
+        self.log(JournalEntry::Delete(removed.id.clone()));
+
+        Ok(removed)
+    }
+
+    fn remove_at(&mut self, idx: usize) -> Meta {
         self.dirty = true;
 
-        let removed = self.metadata.swap_remove(id);
+        let removed = self.metadata.swap_remove(idx);
 
         // House keeping for tag -> metadata data structure
-        for tag in removed.tags.iter() {
-            if let Some(set) = self.tags.get_mut(tag) {
-                set.remove(&removed.id);
+        self.untag(&removed.id, removed.tags.iter());
+
+        removed
+    }
+
+    fn tag<'t>(&mut self, id: &str, tags: impl Iterator<Item = &'t String>) {
+        for tag in tags {
+            self.tags
+                .entry(tag.clone())
+                .or_default()
+                .insert(id.to_string());
+        }
+    }
+
+    // Tags no item has anymore are dropped from the map
+    fn untag<'t>(&mut self, id: &str, tags: impl Iterator<Item = &'t String>) {
+        for tag in tags {
+            if let Some(ids) = self.tags.get_mut(tag) {
+                ids.remove(id);
+
+                if ids.is_empty() {
+                    self.tags.remove(tag);
+                }
             }
         }
+    }
 
-        Ok(removed)
+    fn replay(&mut self, entry: JournalEntry) {
+        let id = match &entry {
+            JournalEntry::Put(meta) => &meta.id,
+            JournalEntry::Delete(id) => id,
+        };
+
+        if let Some(idx) = self.metadata.iter().position(|m| &m.id == id) {
+            self.remove_at(idx);
+        }
+
+        if let JournalEntry::Put(meta) = entry {
+            self.push(meta);
+        }
+    }
+
+    fn log(&self, entry: JournalEntry) {
+        if let Err(e) = self.journal.append(&entry) {
+            tracing::error!("FileStore journal: {}", e);
+        }
     }
 
     pub fn data(&self) -> &[Meta] {
@@ -263,6 +407,10 @@ impl FileStore {
 
             self.write_file(self.file())?;
             self.dirty = false;
+
+            if let Err(e) = self.journal.clear() {
+                tracing::error!("FileStore journal: {}", e);
+            }
         } else {
             info!("FileStore clean: {}", self.file().display());
         }
@@ -323,19 +471,6 @@ impl FileStore {
         Ok(ids[0])
     }
 
-    #[instrument(level = "debug", skip(self))]
-    pub fn import_file(&mut self, file: String) -> Result<(), Error> {
-        tracing::info!("Importing file {}", file);
-        let fp = Path::new(&file);
-        match FileStore::read_file(fp) {
-            Ok(store) => self.import(store),
-            Err(e) => {
-                tracing::warn!("Import error: {:?}", e);
-                Err(e)
-            }
-        }
-    }
-
     // This does book keeping for the tag -> id store.
     // I can't wait to change monk to use a relational database
     #[instrument(level = "debug", skip(self))]
@@ -347,17 +482,12 @@ impl FileStore {
             .cloned()
             .collect();
 
-        for tag in new_tags {
-            if let Some(ids) = self.tags.get_mut(&tag) {
-                ids.insert(current_meta_id.clone());
-            } else {
-                let mut id_set = BTreeSet::new();
-                id_set.insert(current_meta_id.clone());
-                self.tags.insert(tag.clone(), id_set);
-            }
-        }
+        self.tag(&current_meta_id, new_tags.iter());
         let current_meta = self.get_mut(&current_meta_id).unwrap();
         current_meta.union(&incoming_meta);
+
+        let current_meta = current_meta.clone();
+        self.log(JournalEntry::Put(current_meta));
         Ok(())
     }
     // TODO: This is a target for when monk gets a relational database
@@ -386,6 +516,69 @@ impl FileStore {
     }
 }
 
+impl MetaStore for FileStore {
+    fn file(&self) -> &Path {
+        FileStore::file(self)
+    }
+
+    fn version(&self) -> &str {
+        FileStore::version(self)
+    }
+
+    fn data(&self) -> &[Meta] {
+        FileStore::data(self)
+    }
+
+    fn push(&mut self, meta: Meta) -> Result<(), Error> {
+        FileStore::push(self, meta);
+        Ok(())
+    }
+
+    fn get(&self, description: &str) -> Result<&Meta, Error> {
+        FileStore::get(self, description)
+    }
+
+    fn get_mut(&mut self, description: &str) -> Result<&mut Meta, Error> {
+        FileStore::get_mut(self, &description)
+    }
+
+    fn update(&mut self, id: &str, data: Meta) -> Result<(), Error> {
+        FileStore::update(self, &id, data)
+    }
+
+    fn edit(&mut self, description: &str, edit: &Edit) -> Result<Meta, Error> {
+        FileStore::edit(self, &description, edit)
+    }
+
+    fn delete(&mut self, description: &str) -> Result<Meta, Error> {
+        FileStore::delete(self, description)
+    }
+
+    fn get_union_tags(&self, tags: &[String]) -> Vec<Meta> {
+        FileStore::get_union_tags(self, tags)
+    }
+
+    fn get_intersection_tags(&self, tags: &[String]) -> Vec<Meta> {
+        FileStore::get_intersection_tags(self, tags)
+    }
+
+    fn import(&mut self, store: FileStore) -> Result<(), Error> {
+        FileStore::import(self, store)
+    }
+
+    fn write_file(&self, path: &Path) -> Result<(), Error> {
+        FileStore::write_file(self, path)
+    }
+
+    fn commit(&mut self) -> Result<(), Error> {
+        FileStore::commit(self)
+    }
+
+    fn is_dirty(&self) -> bool {
+        FileStore::is_dirty(self)
+    }
+}
+
 impl std::ops::Index<usize> for FileStore {
     type Output = Meta;
 
@@ -422,21 +615,272 @@ fn check_path(path: impl AsRef<Path>) -> Result<(), Error> {
     Ok(())
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct StoreSettings {
-    pub(crate) path: PathBuf,
+fn add_tags_and_last_updated(store: &mut serde_json::Value) -> Result<(), Error> {
+    let now = serde_json::to_value(chrono::Utc::now())?;
+
+    if let Some(metas) = store.get_mut("metadata").and_then(|m| m.as_array_mut()) {
+        for meta in metas.iter_mut().filter_map(|m| m.as_object_mut()) {
+            meta.entry("tags").or_insert_with(|| serde_json::json!([]));
+            meta.entry("last_updated").or_insert_with(|| now.clone());
+        }
+    }
+
+    if let Some(store) = store.as_object_mut() {
+        store.entry("tags").or_insert_with(|| serde_json::json!({}));
+    }
+
+    Ok(())
 }
 
-impl Default for StoreSettings {
-    fn default() -> Self {
-        if let Some(dirs) = crate::get_dirs() {
-            StoreSettings {
-                path: dirs.data_dir().join("store.json"),
-            }
-        } else {
-            StoreSettings {
-                path: "./store.json".into(),
+// Older versions didn't keep the tag map in sync on `update`, so derive it
+// again from the metas themselves.
+fn rebuild_tag_map(store: &mut serde_json::Value) -> Result<(), Error> {
+    let mut tags: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+
+    if let Some(metas) = store.get("metadata").and_then(|m| m.as_array()) {
+        for meta in metas {
+            let id = match meta.get("id").and_then(|id| id.as_str()) {
+                Some(id) => id,
+                None => continue,
+            };
+
+            let meta_tags = meta.get("tags").and_then(|t| t.as_array());
+            for tag in meta_tags.into_iter().flatten().filter_map(|t| t.as_str()) {
+                tags.entry(tag.to_string())
+                    .or_default()
+                    .insert(id.to_string());
             }
         }
     }
+
+    store["tags"] = serde_json::to_value(tags)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn meta(id: &str, name: &str, tags: &[&str]) -> Meta {
+        Meta::builder()
+            .id(id.to_string())
+            .name(name)
+            .tags(tags.iter().map(|tag| tag.to_string()).collect())
+            .build()
+    }
+
+    fn names(store: &FileStore) -> Vec<String> {
+        let mut names: Vec<String> = store
+            .data()
+            .iter()
+            .filter_map(|meta| meta.name().map(String::from))
+            .collect();
+        names.sort();
+        names
+    }
+
+    // Reopen the store at `path` as if monkd had crashed: the store isn't
+    // dropped, so nothing is committed
+    fn crash_and_reopen(store: FileStore, path: &Path) -> FileStore {
+        std::mem::forget(store);
+        FileStore::read_file(path).unwrap()
+    }
+
+    #[test]
+    fn uncommitted_changes_are_replayed() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("store.json");
+
+        let mut store = FileStore::read_file(&path).unwrap();
+        store.push(meta("a1", "rust book", &["rust"]));
+        store.push(meta("b1", "tokio", &["async"]));
+        store.commit().unwrap();
+
+        store.push(meta("c1", "serde", &["rust"]));
+        let edit = Edit {
+            name: Some("tokio docs".to_string()),
+            url: None,
+            comment: None,
+            add_tags: vec!["rust".to_string()],
+            remove_tags: Vec::new(),
+        };
+        store.edit(&"b1", &edit).unwrap();
+        store.delete("a1").unwrap();
+
+        let store = crash_and_reopen(store, &path);
+
+        assert_eq!(names(&store), vec!["serde", "tokio docs"]);
+        assert_eq!(
+            store.tags()["rust"].iter().collect::<Vec<_>>(),
+            vec!["b1", "c1"]
+        );
+        assert!(store.tags()["async"].contains("b1"));
+    }
+
+    #[test]
+    fn tag_map_follows_edits() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("store.json");
+
+        let mut store = FileStore::read_file(&path).unwrap();
+        store.push(meta("a1", "rust book", &["rust", "books"]));
+        store.push(meta("b1", "tokio", &["rust"]));
+
+        let mut updated = store.get("a1").unwrap().clone();
+        updated.tags = ["books", "paper"].iter().map(|t| t.to_string()).collect();
+        store.update(&"a1", updated).unwrap();
+
+        let edit = Edit {
+            name: None,
+            url: None,
+            comment: None,
+            add_tags: vec!["async".to_string()],
+            remove_tags: vec!["rust".to_string()],
+        };
+        store.edit(&"b1", &edit).unwrap();
+
+        let tags: Vec<&String> = store.tags().keys().collect();
+        assert_eq!(tags, vec!["async", "books", "paper"]);
+        assert!(store.get_union_tags(&["rust"]).is_empty());
+        assert_eq!(
+            store.get_intersection_tags(&["books", "paper"])[0].id(),
+            "a1"
+        );
+        assert_eq!(store.get_union_tags(&["async"])[0].id(), "b1");
+
+        store.delete("a1").unwrap();
+        let tags: Vec<&String> = store.tags().keys().collect();
+        assert_eq!(tags, vec!["async"]);
+
+        store.commit().unwrap();
+        let store = crash_and_reopen(store, &path);
+        let tags: Vec<&String> = store.tags().keys().collect();
+        assert_eq!(tags, vec!["async"]);
+        assert_eq!(store.get_union_tags(&["async"])[0].id(), "b1");
+    }
+
+    #[test]
+    fn commit_compacts_the_journal() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("store.json");
+        let journal = Journal::for_store(&path);
+
+        let mut store = FileStore::read_file(&path).unwrap();
+        store.push(meta("a1", "rust book", &[]));
+        assert_eq!(journal.replay::<JournalEntry>().unwrap().len(), 1);
+
+        store.commit().unwrap();
+        assert!(journal.replay::<JournalEntry>().unwrap().is_empty());
+
+        let store = crash_and_reopen(store, &path);
+        assert_eq!(names(&store), vec!["rust book"]);
+    }
+
+    #[test]
+    fn failed_update_is_not_journaled() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("store.json");
+
+        let mut store = FileStore::read_file(&path).unwrap();
+        store.push(meta("a1", "rust book", &[]));
+        let deleted = store.delete("a1").unwrap();
+        assert!(store.update(&"a1", deleted).is_err());
+
+        let store = crash_and_reopen(store, &path);
+        assert!(store.data().is_empty());
+    }
+
+    #[test]
+    fn load_file_does_not_write() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("store.json");
+
+        let mut store = FileStore::read_file(&path).unwrap();
+        store.push(meta("a1", "rust book", &[]));
+        std::mem::forget(store);
+        let committed = std::fs::read(&path).unwrap();
+
+        let loaded = FileStore::load_file(&path).unwrap();
+        assert_eq!(names(&loaded), vec!["rust book"]);
+        drop(loaded);
+
+        assert_eq!(std::fs::read(&path).unwrap(), committed);
+        let journal = Journal::for_store(&path);
+        assert_eq!(journal.replay::<JournalEntry>().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn unversioned_store_is_upgraded() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("store.json");
+        let legacy = serde_json::json!({
+            "metadata": [{
+                "id": "a1",
+                "name": "rust book",
+                "url": "https://doc.rust-lang.org/book/",
+                "comment": null,
+                "found": 0,
+                "last_read": null,
+                "index_status": null
+            }]
+        });
+        std::fs::write(&path, legacy.to_string()).unwrap();
+
+        let store = FileStore::read_file(&path).unwrap();
+        assert_eq!(store.version(), CURRENT_FILE_STORE_VERSION);
+        assert_eq!(names(&store), vec!["rust book"]);
+        assert!(store.data()[0].tags().is_empty());
+
+        // The original is kept, and the upgraded store is written back
+        let backup = dir.path().join("store.json.v0.0.0.bak");
+        let backup: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(backup).unwrap()).unwrap();
+        assert_eq!(backup, legacy);
+
+        std::mem::forget(store);
+        let written: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(written["version"], CURRENT_FILE_STORE_VERSION);
+    }
+
+    #[test]
+    fn tag_map_is_rebuilt_from_metas() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("store.json");
+
+        let mut metas = serde_json::to_value(vec![
+            meta("a1", "rust book", &["rust"]),
+            meta("b1", "tokio", &["async", "rust"]),
+        ])
+        .unwrap();
+        let stale = serde_json::json!({
+            "version": "0.1.0",
+            "metadata": metas.take(),
+            "tags": { "rust": ["a1"], "gone": ["a1"] }
+        });
+        std::fs::write(&path, stale.to_string()).unwrap();
+
+        let store = FileStore::read_file(&path).unwrap();
+        assert_eq!(
+            store.tags()["rust"].iter().collect::<Vec<_>>(),
+            vec!["a1", "b1"]
+        );
+        assert!(store.tags()["async"].contains("b1"));
+        assert!(!store.tags().contains_key("gone"));
+    }
+
+    #[test]
+    fn newer_store_is_refused() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("store.json");
+        let newer = serde_json::json!({ "version": "9.9.9", "metadata": [] });
+        std::fs::write(&path, newer.to_string()).unwrap();
+
+        assert!(matches!(
+            FileStore::read_file(&path),
+            Err(Error::Migration(_, _))
+        ));
+    }
 }
@@ -0,0 +1,227 @@
+// Crash safety for the json backed stores.
+//
+// Store files are never written in place. `write_json_atomic` writes to a
+// temporary file in the same directory, fsyncs it and renames it over the
+// old file, so a reader only ever sees the old or the new version.
+//
+// Mutations made between commits are appended to a `Journal` next to the
+// store file. On startup the journal is replayed on top of the last
+// committed file, and it is cleared after every successful commit.
+
+use serde::{de::DeserializeOwned, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use tempfile::NamedTempFile;
+
+use crate::error::Error;
+
+pub fn write_json_atomic<T: Serialize>(path: impl AsRef<Path>, value: &T) -> Result<(), Error> {
+    let path = path.as_ref();
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    let mut temp = NamedTempFile::new_in(dir)?;
+    {
+        let mut writer = BufWriter::new(temp.as_file_mut());
+        serde_json::to_writer_pretty(&mut writer, value)?;
+        writer.flush()?;
+    }
+    temp.as_file().sync_all()?;
+    temp.persist(path).map_err(|e| e.error)?;
+
+    // Make the rename itself durable. Directories can't be opened on every
+    // platform, so this is best effort.
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+
+    Ok(())
+}
+
+/// An append-only log of store mutations, one json entry per line. A journal
+/// with an empty path is disabled and ignores appends.
+#[derive(Default, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Journal {
+    path: PathBuf,
+}
+
+impl Journal {
+    /// The journal for the store file at `store`, e.g. `store.json.journal`.
+    pub fn for_store(store: impl AsRef<Path>) -> Self {
+        let mut path = store.as_ref().as_os_str().to_owned();
+        path.push(".journal");
+
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.path.as_os_str().is_empty()
+    }
+
+    /// Append an entry and fsync it before returning.
+    pub fn append<E: Serialize>(&self, entry: &E) -> Result<(), Error> {
+        if self.is_disabled() {
+            return Ok(());
+        }
+
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(&line)?;
+        file.sync_data()?;
+
+        Ok(())
+    }
+
+    /// Every entry written since the last `clear`. A torn final line from a
+    /// crash mid-append is dropped.
+    pub fn replay<E: DeserializeOwned>(&self) -> Result<Vec<E>, Error> {
+        if self.is_disabled() || !self.path.exists() {
+            return Ok(Vec::new());
+        }
+
+        let reader = BufReader::new(File::open(&self.path)?);
+        let mut entries = Vec::new();
+
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match serde_json::from_str(&line) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => {
+                    tracing::warn!(
+                        "Dropping torn journal entry in {}: {}",
+                        self.path.display(),
+                        e
+                    );
+                    break;
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    pub fn clear(&self) -> Result<(), Error> {
+        if self.is_disabled() || !self.path.exists() {
+            return Ok(());
+        }
+
+        let file = OpenOptions::new().write(true).open(&self.path)?;
+        file.set_len(0)?;
+        file.sync_all()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn journal(dir: &TempDir) -> Journal {
+        Journal::for_store(dir.path().join("store.json"))
+    }
+
+    #[test]
+    fn journal_sits_next_to_the_store() {
+        let journal = Journal::for_store("/data/store.json");
+
+        assert_eq!(journal.path(), Path::new("/data/store.json.journal"));
+    }
+
+    #[test]
+    fn replay_returns_entries_in_order() {
+        let dir = TempDir::new().unwrap();
+        let journal = journal(&dir);
+
+        journal.append(&("put", 1)).unwrap();
+        journal.append(&("delete", 2)).unwrap();
+
+        let entries: Vec<(String, u32)> = journal.replay().unwrap();
+        assert_eq!(
+            entries,
+            vec![("put".to_string(), 1), ("delete".to_string(), 2)]
+        );
+    }
+
+    #[test]
+    fn replay_without_a_journal_is_empty() {
+        let dir = TempDir::new().unwrap();
+
+        let entries: Vec<(String, u32)> = journal(&dir).replay().unwrap();
+        assert!(entries.is_empty());
+    }
+
+    #[test]
+    fn replay_drops_a_torn_last_line() {
+        let dir = TempDir::new().unwrap();
+        let journal = journal(&dir);
+
+        journal.append(&("put", 1)).unwrap();
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(journal.path())
+            .unwrap();
+        file.write_all(b"[\"put\",").unwrap();
+
+        let entries: Vec<(String, u32)> = journal.replay().unwrap();
+        assert_eq!(entries, vec![("put".to_string(), 1)]);
+    }
+
+    #[test]
+    fn clear_forgets_every_entry() {
+        let dir = TempDir::new().unwrap();
+        let journal = journal(&dir);
+
+        journal.append(&("put", 1)).unwrap();
+        journal.clear().unwrap();
+        journal.append(&("put", 2)).unwrap();
+
+        let entries: Vec<(String, u32)> = journal.replay().unwrap();
+        assert_eq!(entries, vec![("put".to_string(), 2)]);
+    }
+
+    #[test]
+    fn disabled_journal_ignores_appends() {
+        let journal = Journal::default();
+
+        journal.append(&("put", 1)).unwrap();
+        journal.clear().unwrap();
+
+        let entries: Vec<(String, u32)> = journal.replay().unwrap();
+        assert!(journal.is_disabled());
+        assert!(entries.is_empty());
+    }
+
+    #[test]
+    fn write_json_atomic_replaces_the_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("store.json");
+
+        write_json_atomic(&path, &vec![1, 2]).unwrap();
+        write_json_atomic(&path, &vec![3]).unwrap();
+
+        let value: Vec<u32> = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(value, vec![3]);
+
+        // No temporary files are left behind
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
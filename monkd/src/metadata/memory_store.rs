@@ -0,0 +1,338 @@
+use std::path::Path;
+
+use super::{store::MetaStore, FileStore, Meta};
+use crate::error::Error;
+use crate::server::request::Edit;
+
+pub const CURRENT_MEMORY_STORE_VERSION: &str = "0.1.0";
+
+/// A `MetaStore` that only lives in memory, for `store.backend: Memory`.
+/// Nothing is ever written to disk, which also makes it the backend of the
+/// store tests.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct MemoryStore {
+    metadata: Vec<Meta>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_metas(metadata: Vec<Meta>) -> Self {
+        Self { metadata }
+    }
+
+    fn find_id(&self, description: &str) -> Result<usize, Error> {
+        let ids: Vec<usize> = self
+            .metadata
+            .iter()
+            .enumerate()
+            .filter(|(_, meta)| {
+                meta.id().starts_with(description)
+                    || meta
+                        .name()
+                        .map(|name| name.starts_with(description))
+                        .unwrap_or_default()
+            })
+            .map(|(i, _)| i)
+            .collect();
+
+        if ids.len() > 1 {
+            return Err(Error::TooManyMetas(
+                description.into(),
+                ids.into_iter().map(|i| self.metadata[i].clone()).collect(),
+            ));
+        } else if ids.is_empty() {
+            return Err(Error::IdNotFound(description.into()));
+        }
+        Ok(ids[0])
+    }
+}
+
+impl MetaStore for MemoryStore {
+    fn file(&self) -> &Path {
+        Path::new("")
+    }
+
+    fn version(&self) -> &str {
+        CURRENT_MEMORY_STORE_VERSION
+    }
+
+    fn data(&self) -> &[Meta] {
+        &self.metadata
+    }
+
+    fn push(&mut self, meta: Meta) -> Result<(), Error> {
+        self.metadata.push(meta);
+        Ok(())
+    }
+
+    fn get(&self, description: &str) -> Result<&Meta, Error> {
+        let id = self.find_id(description)?;
+        Ok(&self.metadata[id])
+    }
+
+    fn get_mut(&mut self, description: &str) -> Result<&mut Meta, Error> {
+        let id = self.find_id(description)?;
+        Ok(&mut self.metadata[id])
+    }
+
+    fn update(&mut self, id: &str, data: Meta) -> Result<(), Error> {
+        if id != data.id {
+            return Err(Error::UnequalIds);
+        }
+
+        *self.get_mut(id)? = data;
+
+        Ok(())
+    }
+
+    fn edit(&mut self, description: &str, edit: &Edit) -> Result<Meta, Error> {
+        let meta = self.get_mut(description)?;
+
+        if let Some(u) = edit.url.as_ref() {
+            meta.url = Some(url::Url::parse(&u)?)
+        }
+        if let Some(n) = edit.name.as_ref() {
+            meta.name = Some(n.clone());
+        }
+        if let Some(c) = edit.comment.as_ref() {
+            meta.comment = Some(c.clone());
+        }
+        for tag in edit.add_tags.iter() {
+            meta.tags.insert(tag.clone());
+        }
+        for tag in edit.remove_tags.iter() {
+            meta.tags.remove(tag);
+        }
+
+        Ok(meta.clone())
+    }
+
+    fn delete(&mut self, description: &str) -> Result<Meta, Error> {
+        let id = self.find_id(description)?;
+        Ok(self.metadata.swap_remove(id))
+    }
+
+    fn get_union_tags(&self, tags: &[String]) -> Vec<Meta> {
+        self.metadata
+            .iter()
+            .filter(|m| tags.iter().any(|t| m.tags.contains(t)))
+            .cloned()
+            .collect()
+    }
+
+    fn get_intersection_tags(&self, tags: &[String]) -> Vec<Meta> {
+        self.metadata
+            .iter()
+            .filter(|m| tags.iter().all(|t| m.tags.contains(t)))
+            .cloned()
+            .collect()
+    }
+
+    fn import(&mut self, store: FileStore) -> Result<(), Error> {
+        for meta in store.data().iter().cloned() {
+            let existing = self
+                .metadata
+                .iter_mut()
+                .find(|m| m.url().is_some() && m.url() == meta.url());
+
+            match existing {
+                Some(current) => current.union(&meta),
+                None => self.metadata.push(meta),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn is_dirty(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(id: &str, name: &str, url: Option<&str>, tags: &[&str]) -> Meta {
+        let mut builder = Meta::builder()
+            .id(id.to_string())
+            .name(name)
+            .tags(tags.iter().map(|tag| tag.to_string()).collect());
+
+        if let Some(url) = url {
+            builder = builder.url(url::Url::parse(url).unwrap());
+        }
+
+        builder.build()
+    }
+
+    fn store() -> MemoryStore {
+        MemoryStore::with_metas(vec![
+            meta(
+                "a1",
+                "rust book",
+                Some("https://doc.rust-lang.org/book/"),
+                &["rust"],
+            ),
+            meta(
+                "a2",
+                "rust blog",
+                Some("https://blog.rust-lang.org/"),
+                &["rust", "news"],
+            ),
+            meta("b1", "tokio", None, &["async"]),
+        ])
+    }
+
+    fn ids(metas: Vec<Meta>) -> Vec<String> {
+        let mut ids: Vec<String> = metas.into_iter().map(|meta| meta.id).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn get_by_id_or_name_prefix() {
+        let store = store();
+
+        assert_eq!(store.get("b").unwrap().id(), "b1");
+        assert_eq!(store.get("a2").unwrap().id(), "a2");
+        assert_eq!(store.get("tok").unwrap().id(), "b1");
+        assert_eq!(store.get("rust bo").unwrap().id(), "a1");
+    }
+
+    #[test]
+    fn get_ambiguous_or_missing() {
+        let store = store();
+
+        match store.get("rust") {
+            Err(Error::TooManyMetas(description, metas)) => {
+                assert_eq!(description, "rust");
+                assert_eq!(ids(metas), vec!["a1", "a2"]);
+            }
+            other => panic!("expected TooManyMetas, got {:?}", other),
+        }
+
+        assert!(matches!(store.get("zzz"), Err(Error::IdNotFound(_))));
+    }
+
+    #[test]
+    fn update_replaces_the_meta() {
+        let mut store = store();
+
+        let mut updated = store.get("b1").unwrap().clone();
+        updated.comment = Some("runtime".to_string());
+        store.update("b1", updated).unwrap();
+
+        assert_eq!(store.get("b1").unwrap().comment(), Some("runtime"));
+    }
+
+    #[test]
+    fn update_rejects_unequal_or_missing_ids() {
+        let mut store = store();
+
+        let other = store.get("a1").unwrap().clone();
+        assert!(matches!(store.update("b1", other), Err(Error::UnequalIds)));
+
+        let missing = meta("c1", "missing", None, &[]);
+        assert!(matches!(
+            store.update("c1", missing),
+            Err(Error::IdNotFound(_))
+        ));
+        assert_eq!(store.data().len(), 3);
+    }
+
+    #[test]
+    fn edit_fields_and_tags() {
+        let mut store = store();
+
+        let edit = Edit {
+            name: Some("tokio docs".to_string()),
+            url: Some("https://tokio.rs/".to_string()),
+            comment: None,
+            add_tags: vec!["rust".to_string()],
+            remove_tags: vec!["async".to_string()],
+        };
+        let edited = store.edit("tok", &edit).unwrap();
+
+        assert_eq!(edited.name(), Some("tokio docs"));
+        assert_eq!(
+            edited.url().map(|url| url.as_str()),
+            Some("https://tokio.rs/")
+        );
+        assert_eq!(edited.tags().iter().collect::<Vec<_>>(), vec!["rust"]);
+        assert_eq!(store.get("b1").unwrap(), &edited);
+    }
+
+    #[test]
+    fn edit_with_a_bad_url_fails() {
+        let mut store = store();
+
+        let edit = Edit {
+            name: None,
+            url: Some("not a url".to_string()),
+            comment: None,
+            add_tags: Vec::new(),
+            remove_tags: Vec::new(),
+        };
+
+        assert!(matches!(store.edit("b1", &edit), Err(Error::UrlParse(_))));
+    }
+
+    #[test]
+    fn delete_removes_the_meta() {
+        let mut store = store();
+
+        assert_eq!(store.delete("a2").unwrap().id(), "a2");
+        assert!(store.get("a2").is_err());
+        assert!(store.delete("a2").is_err());
+        assert_eq!(store.data().len(), 2);
+    }
+
+    #[test]
+    fn tag_queries() {
+        let store = store();
+        let tags = |tags: &[&str]| -> Vec<String> { tags.iter().map(|t| t.to_string()).collect() };
+
+        assert_eq!(
+            ids(store.get_union_tags(&tags(&["news", "async"]))),
+            vec!["a2", "b1"]
+        );
+        assert_eq!(
+            ids(store.get_intersection_tags(&tags(&["rust", "news"]))),
+            vec!["a2"]
+        );
+        assert!(store
+            .get_intersection_tags(&tags(&["rust", "async"]))
+            .is_empty());
+    }
+
+    #[test]
+    fn import_unions_metas_with_the_same_url() {
+        let mut store = store();
+
+        let duplicate = meta(
+            "x1",
+            "the book",
+            Some("https://doc.rust-lang.org/book/"),
+            &["book"],
+        );
+        let new = meta("x2", "serde", Some("https://serde.rs/"), &[]);
+        store
+            .import(FileStore::from_metas(vec![duplicate, new]))
+            .unwrap();
+
+        assert_eq!(store.data().len(), 4);
+        assert!(store.get("x1").is_err());
+        assert_eq!(store.get("x2").unwrap().name(), Some("serde"));
+
+        let book = store.get("a1").unwrap();
+        assert_eq!(book.tags().iter().collect::<Vec<_>>(), vec!["book", "rust"]);
+    }
+}
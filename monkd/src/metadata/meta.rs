@@ -4,6 +4,8 @@ use std::collections::BTreeSet;
 use std::fmt;
 use url::Url;
 
+use crate::metadata::page::PageMeta;
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct Meta {
     pub(crate) id: String,
@@ -18,6 +20,9 @@ pub struct Meta {
     pub(crate) index_status: Option<IndexStatus>,
     #[serde(default)]
     pub(crate) tags: BTreeSet<String>,
+    /// What the page at `url` says about itself, filled in after it's added
+    #[serde(default, skip_serializing_if = "PageMeta::is_empty")]
+    pub(crate) page: PageMeta,
 }
 
 impl Meta {
@@ -49,6 +54,10 @@ impl Meta {
         &self.tags
     }
 
+    pub fn page(&self) -> &PageMeta {
+        &self.page
+    }
+
     pub fn builder() -> MetaBuilder {
         MetaBuilder::new()
     }
@@ -68,6 +77,9 @@ impl Meta {
                 self.last_read = other.last_read;
             }
             self.found = other.found;
+            if !other.page.is_empty() {
+                self.page = other.page.clone();
+            }
         } else {
             if self.name == None {
                 self.name = other.name.clone();
@@ -78,6 +90,9 @@ impl Meta {
             if self.last_read == None {
                 self.last_read = other.last_read;
             }
+            if self.page.is_empty() {
+                self.page = other.page.clone();
+            }
         }
 
         self.tags = self.tags.union(&other.tags).cloned().collect();
@@ -208,6 +223,7 @@ impl MetaBuilder {
             last_updated: Utc::now(),
             index_status: None,
             tags,
+            page: PageMeta::default(),
         }
     }
 }
@@ -245,6 +261,14 @@ impl fmt::Display for Meta {
             write!(f, "\n\t{}", comment)?;
         }
 
+        if let Some(author) = &self.page.author {
+            write!(f, "\n\tby {}", author)?;
+        }
+
+        if let Some(description) = &self.page.description {
+            write!(f, "\n\t{}", description)?;
+        }
+
         Ok(())
     }
 }
@@ -1,10 +1,16 @@
 pub mod deep_transfer;
 pub mod file_store;
+pub mod journal;
+pub mod memory_store;
 pub mod meta;
 pub mod monolith;
 pub mod offline_store;
+pub mod page;
+pub mod sqlite_store;
+pub mod store;
 
 pub use self::file_store::FileStore;
 pub use self::meta::Meta;
+pub use self::store::MetaStore;
 
 // pub use self::offline_store::{OfflineData, OfflineStore};
@@ -11,11 +11,17 @@ use crate::error::Error;
 use crate::metadata::Meta;
 
 /// From monolith/src/args.rs
-const DEFAULT_USER_AGENT: &str =
+pub(crate) const DEFAULT_USER_AGENT: &str =
     "Mozilla/5.0 (X11; Ubuntu; Linux x86_64; rv:73.0) Gecko/20100101 Firefox/73.0";
 
-#[tracing::instrument(skip(meta, store))]
-pub fn download_meta(meta: &Meta, store: impl AsRef<Path>) -> Result<PathBuf, Error> {
+/// Save the page at `meta`'s url, with its assets embedded, into `store`.
+/// Nothing is left behind if `cancelled` says so once the page is saved.
+#[tracing::instrument(skip(meta, store, cancelled))]
+pub fn download_meta(
+    meta: &Meta,
+    store: impl AsRef<Path>,
+    cancelled: impl Fn() -> bool,
+) -> Result<PathBuf, Error> {
     fs::create_dir_all(&store)?;
 
     let filename = format!("{}.html", meta.id());
@@ -89,7 +95,17 @@ pub fn download_meta(meta: &Meta, store: impl AsRef<Path>) -> Result<PathBuf, Er
             file_path.display()
         );
 
-        fs::write(&file_path, html)?;
+        // Written next to the page and only moved in place if the download
+        // is still wanted, so a cancelled one can't replace a newer copy
+        let partial = store.as_ref().join(format!("{}.html.part", meta.id()));
+        fs::write(&partial, html)?;
+
+        if cancelled() {
+            let _ = fs::remove_file(&partial);
+            return Err(Error::Custom(format!("[{}] download cancelled", meta.id())));
+        }
+
+        fs::rename(&partial, &file_path)?;
 
         tracing::info!("Successfully extracted asset: {}", meta.id());
 
@@ -100,3 +116,39 @@ pub fn download_meta(meta: &Meta, store: impl AsRef<Path>) -> Result<PathBuf, Er
         Err(Error::NoUrl(meta.id().to_string()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use url::Url;
+
+    fn page(dir: &Path) -> Meta {
+        let source = dir.join("page.html");
+        fs::write(&source, "<html><body><p>saved</p></body></html>").unwrap();
+
+        Meta::builder()
+            .id("a1".to_string())
+            .url(Url::from_file_path(&source).unwrap())
+            .build()
+    }
+
+    #[test]
+    fn saves_the_page() {
+        let dir = TempDir::new().unwrap();
+        let store = dir.path().join("offline");
+
+        let file = download_meta(&page(dir.path()), &store, || false).unwrap();
+        assert_eq!(file, store.join("a1.html"));
+        assert!(fs::read_to_string(&file).unwrap().contains("saved"));
+    }
+
+    #[test]
+    fn cancelled_download_leaves_nothing_behind() {
+        let dir = TempDir::new().unwrap();
+        let store = dir.path().join("offline");
+
+        assert!(download_meta(&page(dir.path()), &store, || true).is_err());
+        assert_eq!(fs::read_dir(&store).unwrap().count(), 0);
+    }
+}
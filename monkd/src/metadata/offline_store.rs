@@ -1,6 +1,7 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::fs::File;
-use std::io::{BufReader, BufWriter};
+use std::io::BufReader;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tokio::sync::RwLock;
@@ -8,6 +9,7 @@ use url::Url;
 
 use crate::adapter::AdapterType;
 use crate::error::Error;
+use crate::metadata::journal::{write_json_atomic, Journal};
 use crate::server::request::Edit;
 
 #[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -15,6 +17,15 @@ pub struct OfflineStore {
     data: Vec<OfflineData>,
     pub file: PathBuf,
     dirty: bool,
+    #[serde(skip)]
+    journal: Journal,
+}
+
+// Mutations are journaled as the resulting data so replaying is idempotent.
+#[derive(Debug, Serialize, Deserialize)]
+enum JournalEntry {
+    Put(OfflineData),
+    Delete(String),
 }
 
 impl OfflineStore {
@@ -30,6 +41,7 @@ impl OfflineStore {
         self.dirty = true;
 
         if self.get(data.id()).is_err() {
+            self.log(JournalEntry::Put(data.clone()));
             self.data.push(data);
         }
 
@@ -51,7 +63,10 @@ impl OfflineStore {
         }
 
         match self.get_mut(&id) {
-            Ok(d) => *d = data,
+            Ok(d) => {
+                *d = data.clone();
+                self.log(JournalEntry::Put(data));
+            }
             Err(_) => self.push(data)?,
         }
 
@@ -90,6 +105,9 @@ impl OfflineStore {
         if let Some(n) = edit.name.as_ref() {
             self.data[id].name = Some(n.clone());
         }
+
+        self.log(JournalEntry::Put(self.data[id].clone()));
+
         Ok(self.data[id].clone())
     }
 
@@ -99,6 +117,7 @@ impl OfflineStore {
         tracing::info!("Deleting: `{}`", description.as_ref());
         self.dirty = true;
         let removed = self.data.swap_remove(id);
+        self.log(JournalEntry::Delete(removed.id.clone()));
 
         if let Some(file) = &removed.file {
             let _ = std::fs::remove_file(file);
@@ -107,6 +126,32 @@ impl OfflineStore {
         Ok(removed)
     }
 
+    fn replay(&mut self, entry: JournalEntry) {
+        let id = match &entry {
+            JournalEntry::Put(data) => &data.id,
+            JournalEntry::Delete(id) => id,
+        };
+
+        let existing = self.data.iter().position(|d| &d.id == id);
+
+        match (entry, existing) {
+            (JournalEntry::Put(data), Some(idx)) => self.data[idx] = data,
+            (JournalEntry::Put(data), None) => self.data.push(data),
+            (JournalEntry::Delete(_), Some(idx)) => {
+                self.data.swap_remove(idx);
+            }
+            (JournalEntry::Delete(_), None) => {}
+        }
+
+        self.dirty = true;
+    }
+
+    fn log(&self, entry: JournalEntry) {
+        if let Err(e) = self.journal.append(&entry) {
+            tracing::error!("OfflineStore journal: {}", e);
+        }
+    }
+
     pub fn read_file(path: impl AsRef<Path>) -> Result<Self, Error> {
         check_path(&path)?;
 
@@ -115,18 +160,26 @@ impl OfflineStore {
 
         let mut store: OfflineStore = serde_json::from_reader(reader)?;
         store.file = path.as_ref().parent().unwrap().into();
+
+        // Replay anything that happened after the last commit. The journal
+        // is only attached afterwards so replaying doesn't journal again.
+        let journal = Journal::for_store(store.file().join("offline.json"));
+        let entries: Vec<JournalEntry> = journal.replay()?;
+        if !entries.is_empty() {
+            tracing::info!("Replaying {} journal entries", entries.len());
+            for entry in entries {
+                store.replay(entry);
+            }
+        }
+        store.journal = journal;
+
         store.data.sort_by(|l, r| l.id().cmp(r.id()));
 
         Ok(store)
     }
 
     pub fn write_file(&self, path: impl AsRef<Path>) -> Result<(), Error> {
-        let file = File::create(path)?;
-        let writer = BufWriter::new(file);
-
-        serde_json::to_writer_pretty(writer, self)?;
-
-        Ok(())
+        write_json_atomic(path, self)
     }
 
     #[tracing::instrument(skip(self))]
@@ -136,6 +189,10 @@ impl OfflineStore {
 
             self.write_file(self.file().join("offline.json"))?;
             self.dirty = false;
+
+            if let Err(e) = self.journal.clear() {
+                tracing::error!("OfflineStore journal: {}", e);
+            }
         } else {
             tracing::info!("OfflineStore clean: {}", self.file().display());
         }
@@ -191,6 +248,7 @@ impl OfflineStore {
         self.dirty = true;
         let other = OfflineStore::read_file(file)?;
         for data in other.data.into_iter() {
+            self.log(JournalEntry::Put(data.clone()));
             self.data.push(data);
         }
         Ok(())
@@ -208,6 +266,11 @@ pub struct OfflineData {
     pub file: Option<PathBuf>,
     pub adapter: AdapterType,
     pub status: Status,
+    #[serde(default)]
+    pub retry: RetryInfo,
+    /// Where a local file was copied from
+    #[serde(default)]
+    pub source: Option<PathBuf>,
 }
 
 impl OfflineData {
@@ -223,9 +286,48 @@ impl OfflineData {
         self.file.as_deref()
     }
 
+    pub fn source(&self) -> Option<&Path> {
+        self.source.as_deref()
+    }
+
     pub fn adapter(&self) -> AdapterType {
         self.adapter
     }
+
+    /// Mark the download as failed with `error`, counting the attempt.
+    pub fn failed(&mut self, error: &Error) {
+        self.status = Status::Error(error.to_string());
+        self.retry.attempts += 1;
+        self.retry.last_error = Some(error.to_string());
+        self.retry.transient = error.is_transient();
+        self.retry.next_attempt = None;
+    }
+
+    /// Mark a failed download as running again. The failed attempts are kept
+    /// so the backoff keeps growing if it fails again.
+    pub fn retrying(&mut self) {
+        self.status = Status::Downloading;
+        self.retry.next_attempt = None;
+    }
+
+    /// Mark the download as finished, forgetting earlier failures.
+    pub fn ready(&mut self, file: PathBuf) {
+        self.status = Status::Ready;
+        self.file = Some(file);
+        self.retry = RetryInfo::default();
+    }
+}
+
+/// Bookkeeping for failed downloads.
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetryInfo {
+    /// Failed attempts since the last successful download
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    /// Whether the last error is worth retrying, e.g. a timeout
+    pub transient: bool,
+    /// When the next automatic retry is scheduled, if any
+    pub next_attempt: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -272,6 +374,49 @@ fn check_path(path: impl AsRef<Path>) -> Result<(), Error> {
 pub struct OfflineSettings {
     pub(crate) data_folder: PathBuf,
     pub(crate) store_file: PathBuf,
+    #[serde(default)]
+    pub(crate) retry: RetrySettings,
+}
+
+impl OfflineSettings {
+    pub fn retry(&self) -> &RetrySettings {
+        &self.retry
+    }
+}
+
+/// How failed downloads are retried. Only transient errors are retried
+/// automatically, waiting `base_delay` milliseconds after the first failure
+/// and twice as long after each one after that, up to `max_delay`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetrySettings {
+    pub(crate) max_attempts: u32,
+    pub(crate) base_delay: u64,
+    pub(crate) max_delay: u64,
+}
+
+impl RetrySettings {
+    /// The delay before retrying an item that failed `attempts` times, or
+    /// `None` if it shouldn't be retried again.
+    pub fn backoff(&self, attempts: u32) -> Option<std::time::Duration> {
+        if attempts == 0 || attempts >= self.max_attempts {
+            return None;
+        }
+
+        let factor = 2u64.saturating_pow(attempts - 1);
+        let delay = self.base_delay.saturating_mul(factor).min(self.max_delay);
+
+        Some(std::time::Duration::from_millis(delay))
+    }
+}
+
+impl Default for RetrySettings {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay: 30_000,
+            max_delay: 3_600_000,
+        }
+    }
 }
 
 impl Default for OfflineSettings {
@@ -284,11 +429,13 @@ impl Default for OfflineSettings {
             OfflineSettings {
                 data_folder,
                 store_file,
+                retry: RetrySettings::default(),
             }
         } else {
             OfflineSettings {
                 data_folder: "./offline".into(),
                 store_file: "offline.json".into(),
+                retry: RetrySettings::default(),
             }
         }
     }
@@ -303,6 +450,83 @@ impl Default for OfflineData {
             url: None,
             file: None,
             status: Status::Error("default".to_string()),
+            retry: RetryInfo::default(),
+            source: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn retry(max_attempts: u32) -> RetrySettings {
+        RetrySettings {
+            max_attempts,
+            base_delay: 1_000,
+            max_delay: 10_000,
         }
     }
+
+    #[test]
+    fn backoff_doubles_after_each_failure() {
+        let retry = retry(5);
+
+        assert_eq!(retry.backoff(1), Some(Duration::from_millis(1_000)));
+        assert_eq!(retry.backoff(2), Some(Duration::from_millis(2_000)));
+        assert_eq!(retry.backoff(3), Some(Duration::from_millis(4_000)));
+        assert_eq!(retry.backoff(4), Some(Duration::from_millis(8_000)));
+    }
+
+    #[test]
+    fn backoff_is_capped_at_max_delay() {
+        let retry = retry(u32::MAX);
+
+        assert_eq!(retry.backoff(5), Some(Duration::from_millis(10_000)));
+        // Large enough to overflow without saturating
+        assert_eq!(retry.backoff(100), Some(Duration::from_millis(10_000)));
+    }
+
+    #[test]
+    fn no_backoff_without_failures_or_attempts_left() {
+        let retry = retry(3);
+
+        assert_eq!(retry.backoff(0), None);
+        assert!(retry.backoff(2).is_some());
+        assert_eq!(retry.backoff(3), None);
+        assert_eq!(retry.backoff(4), None);
+    }
+
+    #[test]
+    fn failures_are_counted_until_ready() {
+        let mut data = OfflineData::default();
+        let timeout = std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out");
+
+        data.failed(&Error::from(timeout));
+        data.retry.next_attempt = Some(Utc::now());
+        data.failed(&Error::Custom("bad page".to_string()));
+
+        assert!(data.status.is_error());
+        assert_eq!(data.retry.attempts, 2);
+        assert_eq!(data.retry.last_error.as_deref(), Some("Custom: bad page"));
+        assert!(!data.retry.transient);
+        assert_eq!(data.retry.next_attempt, None);
+
+        data.ready(PathBuf::from("a1.html"));
+
+        assert_eq!(data.status, Status::Ready);
+        assert_eq!(data.retry, RetryInfo::default());
+    }
+
+    #[test]
+    fn timeouts_are_transient() {
+        let mut data = OfflineData::default();
+        let timeout = std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out");
+
+        data.failed(&Error::from(timeout));
+
+        assert_eq!(data.retry.attempts, 1);
+        assert!(data.retry.transient);
+    }
 }
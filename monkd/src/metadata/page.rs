@@ -0,0 +1,427 @@
+// Metadata a web page publishes about itself.
+//
+// When an item with a url is added, the daemon fetches the page and fills in
+// a `PageMeta` from its OpenGraph and Twitter card tags, JSON-LD `Article`
+// data, `<link rel="canonical">`, `<html lang>` and plain `<meta>` tags. When
+// several sources have the same field, JSON-LD wins over OpenGraph, which
+// wins over Twitter cards and plain tags.
+
+use chrono::{DateTime, NaiveDate, Utc};
+use scraper::{Html, Selector};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::Duration;
+use url::Url;
+
+use crate::adapter::file::{self, FileKind};
+use crate::error::Error;
+use crate::metadata::monolith::DEFAULT_USER_AGENT;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+// JSON-LD types describing the page itself
+const ARTICLE_TYPES: &[&str] = &[
+    "Article",
+    "NewsArticle",
+    "BlogPosting",
+    "TechArticle",
+    "ScholarlyArticle",
+    "Report",
+    "WebPage",
+];
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct PageMeta {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub author: Option<String>,
+    pub published: Option<DateTime<Utc>>,
+    /// e.g. `en` or `en-US`
+    pub language: Option<String>,
+    pub canonical_url: Option<Url>,
+    pub site_name: Option<String>,
+    pub image: Option<Url>,
+    /// What the url was served as, e.g. `text/html` or `application/pdf`
+    pub content_type: Option<String>,
+}
+
+impl PageMeta {
+    pub fn is_empty(&self) -> bool {
+        *self == PageMeta::default()
+    }
+
+    /// Fetch the page at `url` and read its metadata. Anything that isn't
+    /// HTML only has its content type, and isn't downloaded.
+    pub async fn fetch(url: &Url) -> Result<Self, Error> {
+        if url.scheme() == "file" {
+            return PageMeta::read(url).await;
+        }
+
+        let client = reqwest::Client::builder()
+            .timeout(FETCH_TIMEOUT)
+            .user_agent(DEFAULT_USER_AGENT)
+            .build()?;
+
+        let response = client.get(url.clone()).send().await?.error_for_status()?;
+
+        // Without its parameters, e.g. `; charset=utf-8`
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_lowercase())
+            .filter(|value| !value.is_empty());
+
+        let is_html = content_type
+            .as_ref()
+            .map(|value| value.contains("html"))
+            .unwrap_or(true);
+
+        if !is_html {
+            return Ok(PageMeta {
+                content_type,
+                ..PageMeta::default()
+            });
+        }
+
+        // Relative links are relative to wherever redirects ended up
+        let base = response.url().clone();
+        let html = response.text().await?;
+
+        Ok(PageMeta {
+            content_type,
+            ..PageMeta::parse(&html, &base)
+        })
+    }
+
+    /// Read the metadata of a local file, which only HTML files have.
+    pub async fn read(url: &Url) -> Result<Self, Error> {
+        let path = file::local_path(Some(url))
+            .ok_or_else(|| Error::Custom(format!("{} is not a local path", url)))?;
+
+        let kind = file::detect(&path);
+        let content_type = kind.map(|kind| kind.content_type().to_string());
+
+        if kind != Some(FileKind::Html) {
+            return Ok(PageMeta {
+                content_type,
+                ..PageMeta::default()
+            });
+        }
+
+        let html = tokio::fs::read(&path).await?;
+
+        Ok(PageMeta {
+            content_type,
+            ..PageMeta::parse(&String::from_utf8_lossy(&html), url)
+        })
+    }
+
+    /// Read the metadata of the HTML page at `base`.
+    pub fn parse(html: &str, base: &Url) -> Self {
+        let document = Html::parse_document(html);
+        let tags = meta_tags(&document);
+        let article = json_ld(&document);
+
+        let tag = |keys: &[&str]| {
+            keys.iter()
+                .find_map(|key| tags.get(*key))
+                .map(|value| value.to_string())
+        };
+        let ld = |key: &str| article.as_ref().and_then(|a| a.get(key));
+
+        let title = ld("headline")
+            .and_then(text)
+            .or_else(|| tag(&["og:title", "twitter:title"]))
+            .or_else(|| select_text(&document, "title"));
+
+        let description = ld("description")
+            .and_then(text)
+            .or_else(|| tag(&["og:description", "twitter:description", "description"]));
+
+        let author = ld("author")
+            .and_then(name)
+            .or_else(|| tag(&["author"]))
+            // `article:author` is often a link to the author's profile
+            .or_else(|| tag(&["article:author"]).filter(|author| Url::parse(author).is_err()))
+            .or_else(|| tag(&["twitter:creator"]));
+
+        let published = ld("datePublished")
+            .and_then(text)
+            .or_else(|| tag(&["article:published_time", "date", "dc.date"]))
+            .and_then(|date| parse_date(&date));
+
+        let language = select_attr(&document, "html[lang]", "lang")
+            .or_else(|| tag(&["og:locale", "content-language"]))
+            .map(|lang| lang.replace('_', "-"));
+
+        let canonical_url = select_attr(&document, "link[rel~=canonical][href]", "href")
+            .or_else(|| tag(&["og:url"]))
+            .and_then(|href| base.join(&href).ok());
+
+        let site_name = tag(&["og:site_name"]).or_else(|| ld("publisher").and_then(name));
+
+        let image = tag(&["og:image", "og:image:url", "twitter:image"])
+            .and_then(|src| base.join(&src).ok());
+
+        PageMeta {
+            title,
+            description,
+            author,
+            published,
+            language,
+            canonical_url,
+            site_name,
+            image,
+            content_type: None,
+        }
+    }
+}
+
+// `<meta>` tags by their lowercased `property`, `name` or `http-equiv`. The
+// first tag with a key wins.
+fn meta_tags(document: &Html) -> HashMap<String, String> {
+    let selector = Selector::parse("meta[content]").unwrap();
+    let mut tags = HashMap::new();
+
+    for element in document.select(&selector) {
+        let element = element.value();
+        let key = element
+            .attr("property")
+            .or_else(|| element.attr("name"))
+            .or_else(|| element.attr("http-equiv"));
+
+        let value = normalize(element.attr("content").unwrap_or_default());
+
+        if let Some(key) = key {
+            if !value.is_empty() {
+                tags.entry(key.trim().to_lowercase()).or_insert(value);
+            }
+        }
+    }
+
+    tags
+}
+
+// The first JSON-LD object describing the page. Objects can be nested in
+// arrays and `@graph`s.
+fn json_ld(document: &Html) -> Option<Value> {
+    let selector = Selector::parse(r#"script[type="application/ld+json"]"#).unwrap();
+
+    document
+        .select(&selector)
+        .filter_map(|script| serde_json::from_str(&script.text().collect::<String>()).ok())
+        .find_map(find_article)
+}
+
+fn find_article(value: Value) -> Option<Value> {
+    match value {
+        Value::Array(values) => values.into_iter().find_map(find_article),
+        Value::Object(mut object) => {
+            if let Some(graph) = object.remove("@graph") {
+                return find_article(graph);
+            }
+
+            let is_article = match object.get("@type") {
+                Some(Value::String(kind)) => ARTICLE_TYPES.contains(&kind.as_str()),
+                Some(Value::Array(kinds)) => kinds
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .any(|kind| ARTICLE_TYPES.contains(&kind)),
+                _ => false,
+            };
+
+            if is_article {
+                Some(Value::Object(object))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+fn text(value: &Value) -> Option<String> {
+    value.as_str().map(normalize).filter(|s| !s.is_empty())
+}
+
+// A person or organization: a string, an object with a `name`, or a list of
+// them
+fn name(value: &Value) -> Option<String> {
+    match value {
+        Value::String(_) => text(value),
+        Value::Object(object) => object.get("name").and_then(text),
+        Value::Array(values) => {
+            let names: Vec<String> = values.iter().filter_map(name).collect();
+            if names.is_empty() {
+                None
+            } else {
+                Some(names.join(", "))
+            }
+        }
+        _ => None,
+    }
+}
+
+// Dates are RFC 3339, or sometimes just the day
+fn parse_date(date: &str) -> Option<DateTime<Utc>> {
+    let date = date.trim();
+
+    DateTime::parse_from_rfc3339(date)
+        .map(|date| date.with_timezone(&Utc))
+        .ok()
+        .or_else(|| {
+            let day = date.get(..10)?;
+            let day = NaiveDate::parse_from_str(day, "%Y-%m-%d").ok()?;
+            Some(DateTime::from_naive_utc_and_offset(
+                day.and_hms_opt(0, 0, 0)?,
+                Utc,
+            ))
+        })
+}
+
+fn select_text(document: &Html, selector: &str) -> Option<String> {
+    let selector = Selector::parse(selector).unwrap();
+
+    document
+        .select(&selector)
+        .next()
+        .map(|element| normalize(&element.text().collect::<String>()))
+        .filter(|text| !text.is_empty())
+}
+
+fn select_attr(document: &Html, selector: &str, attr: &str) -> Option<String> {
+    let selector = Selector::parse(selector).unwrap();
+
+    document
+        .select(&selector)
+        .find_map(|element| element.value().attr(attr))
+        .map(normalize)
+        .filter(|value| !value.is_empty())
+}
+
+fn normalize(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> Url {
+        Url::parse("https://example.com/posts/rust").unwrap()
+    }
+
+    fn date(rfc3339: &str) -> Option<DateTime<Utc>> {
+        Some(
+            DateTime::parse_from_rfc3339(rfc3339)
+                .unwrap()
+                .with_timezone(&Utc),
+        )
+    }
+
+    #[test]
+    fn json_ld_wins_over_open_graph() {
+        let html = r#"<html lang="en-GB"><head>
+            <title>Site title</title>
+            <meta property="og:title" content="OG title">
+            <meta property="og:description" content="OG description">
+            <meta property="og:site_name" content="Example">
+            <meta name="author" content="Tag Author">
+            <script type="application/ld+json">{
+                "@context": "https://schema.org",
+                "@graph": [
+                    { "@type": "WebSite", "name": "Not the page" },
+                    {
+                        "@type": ["NewsArticle"],
+                        "headline": "  Rust   1.0 ",
+                        "description": "Stable at last",
+                        "author": [{ "name": "Ada" }, "Grace"],
+                        "datePublished": "2015-05-15T10:00:00+02:00",
+                        "publisher": { "name": "The Rust Blog" }
+                    }
+                ]
+            }</script>
+        </head><body></body></html>"#;
+
+        let page = PageMeta::parse(html, &base());
+
+        assert_eq!(page.title.as_deref(), Some("Rust 1.0"));
+        assert_eq!(page.description.as_deref(), Some("Stable at last"));
+        assert_eq!(page.author.as_deref(), Some("Ada, Grace"));
+        assert_eq!(page.published, date("2015-05-15T08:00:00Z"));
+        assert_eq!(page.language.as_deref(), Some("en-GB"));
+        // OpenGraph still wins for the site name
+        assert_eq!(page.site_name.as_deref(), Some("Example"));
+    }
+
+    #[test]
+    fn open_graph_and_twitter_tags() {
+        let html = r#"<html><head>
+            <title>Site title</title>
+            <meta name="twitter:title" content="Twitter title">
+            <meta property="og:title" content="OG title">
+            <meta name="twitter:description" content="Twitter description">
+            <meta property="og:locale" content="en_US">
+            <meta property="og:url" content="/posts/rust?ref=og">
+            <meta property="og:image" content="../images/ferris.png">
+            <meta property="article:author" content="https://example.com/ada">
+            <meta name="twitter:creator" content="@ada">
+            <meta property="article:published_time" content="2015-05-15">
+        </head><body></body></html>"#;
+
+        let page = PageMeta::parse(html, &base());
+
+        assert_eq!(page.title.as_deref(), Some("OG title"));
+        assert_eq!(page.description.as_deref(), Some("Twitter description"));
+        assert_eq!(page.language.as_deref(), Some("en-US"));
+        assert_eq!(
+            page.canonical_url.map(String::from).as_deref(),
+            Some("https://example.com/posts/rust?ref=og")
+        );
+        assert_eq!(
+            page.image.map(String::from).as_deref(),
+            Some("https://example.com/images/ferris.png")
+        );
+        // `article:author` is a link, so the Twitter handle is used instead
+        assert_eq!(page.author.as_deref(), Some("@ada"));
+        assert_eq!(page.published, date("2015-05-15T00:00:00Z"));
+    }
+
+    #[test]
+    fn plain_tags() {
+        let html = r#"<html><head>
+            <title>
+                Plain   title
+            </title>
+            <meta name="Description" content="Plain description">
+            <meta name="author" content="Ada">
+            <meta http-equiv="content-language" content="fr">
+            <link rel="alternate canonical" href="https://example.com/canonical">
+            <meta property="og:url" content="https://example.com/og">
+        </head><body></body></html>"#;
+
+        let page = PageMeta::parse(html, &base());
+
+        assert_eq!(page.title.as_deref(), Some("Plain title"));
+        assert_eq!(page.description.as_deref(), Some("Plain description"));
+        assert_eq!(page.author.as_deref(), Some("Ada"));
+        assert_eq!(page.language.as_deref(), Some("fr"));
+        assert_eq!(
+            page.canonical_url.map(String::from).as_deref(),
+            Some("https://example.com/canonical")
+        );
+    }
+
+    #[test]
+    fn page_without_metadata_is_empty() {
+        let html = r#"<html><head>
+            <meta name="description" content="   ">
+            <script type="application/ld+json">not json</script>
+        </head><body><p>Just text</p></body></html>"#;
+
+        assert!(PageMeta::parse(html, &base()).is_empty());
+    }
+}
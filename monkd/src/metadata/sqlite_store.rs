@@ -0,0 +1,677 @@
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Transaction};
+use tracing::{debug, info, instrument};
+
+use crate::server::request::Edit;
+
+use super::{store::MetaStore, FileStore, Meta};
+use crate::error::Error;
+
+pub const CURRENT_SQLITE_STORE_VERSION: &str = "0.1.0";
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS info (
+        key   TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS metas (
+        id   TEXT PRIMARY KEY,
+        url  TEXT,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS metas_url ON metas (url);
+    CREATE TABLE IF NOT EXISTS tags (
+        tag     TEXT NOT NULL,
+        meta_id TEXT NOT NULL REFERENCES metas (id) ON DELETE CASCADE,
+        PRIMARY KEY (tag, meta_id)
+    );
+    CREATE INDEX IF NOT EXISTS tags_meta_id ON tags (meta_id);
+";
+
+/// A `Meta` store backed by a SQLite database.
+///
+/// Every mutation is written through to the database as its own row, so
+/// unlike the `FileStore` nothing is rewritten wholesale on commit. The
+/// metas are also kept in memory so that prefix lookups by id or name
+/// behave exactly like the `FileStore`.
+#[derive(Debug)]
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+    metadata: Vec<Meta>,
+    file: PathBuf,
+    version: String,
+    // Ids handed out through `get_mut`, flushed on the next commit.
+    dirty: BTreeSet<String>,
+}
+
+impl SqliteStore {
+    /// Open (or create) the database at `path`. If the database is new and
+    /// a `store.json` exists at `legacy`, its contents are migrated over.
+    #[instrument(level = "debug", skip(path, legacy))]
+    pub fn open(path: impl AsRef<Path>, legacy: impl AsRef<Path>) -> Result<Self, Error> {
+        if let Some(parent) = path.as_ref().parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let conn = Connection::open(&path)?;
+        conn.execute_batch("PRAGMA foreign_keys = ON; PRAGMA journal_mode = WAL;")?;
+        conn.execute_batch(SCHEMA)?;
+
+        let version: Option<String> = conn
+            .query_row("SELECT value FROM info WHERE key = 'version'", [], |row| {
+                row.get(0)
+            })
+            .optional()?;
+
+        let fresh = version.is_none();
+
+        let mut store = Self {
+            conn: Mutex::new(conn),
+            metadata: Vec::new(),
+            file: path.as_ref().into(),
+            version: version.unwrap_or_else(|| CURRENT_SQLITE_STORE_VERSION.to_string()),
+            dirty: BTreeSet::new(),
+        };
+
+        if fresh {
+            store.initialize(legacy)?;
+        }
+
+        store.metadata = store.load()?;
+
+        Ok(store)
+    }
+
+    /// Mark a new database with the current version, first copying in an
+    /// existing `store.json`. Both happen in one transaction, so an import
+    /// that fails is tried again on the next start instead of leaving an
+    /// empty store behind. The json file isn't written to, and stays as a
+    /// backup.
+    #[instrument(level = "debug", skip(self, legacy))]
+    fn initialize(&mut self, legacy: impl AsRef<Path>) -> Result<(), Error> {
+        let legacy = legacy.as_ref();
+
+        let file_store = if legacy.exists() && std::fs::metadata(legacy)?.len() != 0 {
+            info!(
+                "Migrating {} into {}",
+                legacy.display(),
+                self.file.display()
+            );
+            Some(FileStore::load_file(legacy)?)
+        } else {
+            None
+        };
+
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        if let Some(file_store) = &file_store {
+            for meta in file_store.data() {
+                write_meta(&tx, meta)?;
+            }
+
+            tx.execute(
+                "INSERT OR REPLACE INTO info (key, value) VALUES ('migrated_from', ?1)",
+                params![legacy.display().to_string()],
+            )?;
+        }
+
+        tx.execute(
+            "INSERT INTO info (key, value) VALUES ('version', ?1)",
+            params![CURRENT_SQLITE_STORE_VERSION],
+        )?;
+        tx.commit()?;
+
+        if let Some(file_store) = file_store {
+            info!("Migrated {} items", file_store.data().len());
+        }
+
+        Ok(())
+    }
+
+    fn load(&self) -> Result<Vec<Meta>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare("SELECT data FROM metas ORDER BY rowid")?;
+
+        let rows = statement.query_map([], |row| row.get::<_, String>(0))?;
+
+        let mut metas = Vec::new();
+        for data in rows {
+            metas.push(serde_json::from_str(&data?)?);
+        }
+
+        Ok(metas)
+    }
+
+    pub fn file(&self) -> &Path {
+        &self.file
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    pub fn data(&self) -> &[Meta] {
+        &self.metadata
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    pub fn push(&mut self, meta: Meta) -> Result<(), Error> {
+        {
+            let mut conn = self.conn.lock().unwrap();
+            let tx = conn.transaction()?;
+            write_meta(&tx, &meta)?;
+            tx.commit()?;
+        }
+
+        self.metadata.push(meta);
+
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self), fields(description = description.as_ref()))]
+    pub fn get(&self, description: impl AsRef<str>) -> Result<&Meta, Error> {
+        let id = self.find_id(&description)?;
+        Ok(&self.metadata[id])
+    }
+
+    /// Changes made through the returned reference are written to the
+    /// database on the next `commit`.
+    #[instrument(level = "debug", skip(self), fields(description = description.as_ref()))]
+    pub fn get_mut(&mut self, description: &impl AsRef<str>) -> Result<&mut Meta, Error> {
+        let id = self.find_id(&description)?;
+
+        self.dirty.insert(self.metadata[id].id.clone());
+
+        Ok(&mut self.metadata[id])
+    }
+
+    // Will not deduplicate if 2 of the same id is passed in
+    #[instrument(level = "debug", skip(self, ids))]
+    pub fn get_list<T>(&self, ids: T) -> Vec<Meta>
+    where
+        T: IntoIterator,
+        T::Item: AsRef<str>,
+    {
+        let mut metas = Vec::new();
+        for id in ids {
+            if let Ok(m) = self.get(id) {
+                metas.push(m.clone())
+            }
+        }
+        metas
+    }
+
+    #[instrument(level = "debug", skip(self, tags))]
+    pub fn get_union_tags<T>(&self, tags: T) -> Vec<Meta>
+    where
+        T: IntoIterator,
+        T::Item: ToString,
+    {
+        let tags: Vec<String> = tags.into_iter().map(|t| t.to_string()).collect();
+
+        match self.tagged_ids(&tags, false) {
+            Ok(ids) => self.get_list(ids),
+            Err(e) => {
+                tracing::error!("SqliteStore: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    #[instrument(level = "debug", skip(self, tags))]
+    pub fn get_intersection_tags<T>(&self, tags: T) -> Vec<Meta>
+    where
+        T: IntoIterator,
+        T::Item: ToString,
+    {
+        debug!("Getting metas with intersection of tags");
+        let tags: Vec<String> = tags.into_iter().map(|t| t.to_string()).collect();
+
+        match self.tagged_ids(&tags, true) {
+            Ok(ids) => self.get_list(ids),
+            Err(e) => {
+                tracing::error!("SqliteStore: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    fn tagged_ids(&self, tags: &[String], all: bool) -> Result<Vec<String>, Error> {
+        if tags.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let placeholders = vec!["?"; tags.len()].join(", ");
+        let query = if all {
+            format!(
+                "SELECT meta_id FROM tags WHERE tag IN ({}) GROUP BY meta_id HAVING COUNT(DISTINCT tag) = {}",
+                placeholders,
+                tags.len()
+            )
+        } else {
+            format!(
+                "SELECT DISTINCT meta_id FROM tags WHERE tag IN ({})",
+                placeholders
+            )
+        };
+
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(&query)?;
+        let rows = statement.query_map(params_from_iter(tags.iter()), |row| row.get(0))?;
+
+        let mut ids = Vec::new();
+        for id in rows {
+            ids.push(id?);
+        }
+
+        Ok(ids)
+    }
+
+    #[instrument(level = "debug", skip(self), fields(id = id.as_ref()))]
+    pub fn update(&mut self, id: &impl AsRef<str>, data: Meta) -> Result<(), Error> {
+        if id.as_ref() != data.id {
+            return Err(Error::UnequalIds);
+        }
+
+        let idx = self.find_id(&id)?;
+        self.write(&data)?;
+        self.metadata[idx] = data;
+
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self), fields(description = description.as_ref()))]
+    pub fn edit(&mut self, description: &impl AsRef<str>, edit: &Edit) -> Result<Meta, Error> {
+        let id: usize = self.find_id(&description)?;
+
+        tracing::info!("Editing: {:?}", edit);
+
+        let mut meta = self.metadata[id].clone();
+
+        if let Some(u) = edit.url.as_ref() {
+            meta.url = Some(url::Url::parse(u)?)
+        }
+        if let Some(n) = edit.name.as_ref() {
+            meta.name = Some(n.clone());
+        }
+        if let Some(c) = edit.comment.as_ref() {
+            meta.comment = Some(c.clone());
+        }
+        for tag in edit.add_tags.iter() {
+            meta.tags.insert(tag.clone());
+        }
+        for tag in edit.remove_tags.iter() {
+            meta.tags.remove(tag);
+        }
+
+        self.write(&meta)?;
+        self.metadata[id] = meta.clone();
+
+        Ok(meta)
+    }
+
+    #[instrument(level = "debug", skip(self), fields(description = description.as_ref()))]
+    pub fn delete(&mut self, description: impl AsRef<str>) -> Result<Meta, Error> {
+        let id = self.find_id(&description)?;
+
+        tracing::info!("Deleting: `{}`", description.as_ref());
+
+        self.conn.lock().unwrap().execute(
+            "DELETE FROM metas WHERE id = ?1",
+            params![self.metadata[id].id],
+        )?;
+
+        // Keep the remaining metas in the order they were added
+        let removed = self.metadata.remove(id);
+        self.dirty.remove(&removed.id);
+
+        Ok(removed)
+    }
+
+    /// Imports every meta in `fs`, merging it with an existing meta with the
+    /// same url if there is one.
+    #[instrument(level = "debug", skip(self, fs))]
+    pub fn import(&mut self, fs: FileStore) -> Result<(), Error> {
+        debug!("Importing Filestore");
+        for meta in fs.data().iter().cloned() {
+            let existing = match meta.url() {
+                Some(url) => self.id_for_url(url.as_str())?,
+                None => None,
+            };
+
+            match existing {
+                Some(id) => {
+                    let idx = self.find_id(&id)?;
+                    let mut current = self.metadata[idx].clone();
+                    current.union(&meta);
+                    self.update(&id, current)?;
+                }
+                None => self.push(meta)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn id_for_url(&self, url: &str) -> Result<Option<String>, Error> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT id FROM metas WHERE url = ?1 LIMIT 1",
+                params![url],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(Into::into)
+    }
+
+    #[instrument(skip(self))]
+    pub fn commit(&mut self) -> Result<(), Error> {
+        if self.dirty.is_empty() {
+            info!("SqliteStore clean: {}", self.file().display());
+            return Ok(());
+        }
+
+        info!("SqliteStore dirty: {}", self.file().display());
+
+        let dirty = std::mem::take(&mut self.dirty);
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        for meta in self.metadata.iter().filter(|m| dirty.contains(&m.id)) {
+            write_meta(&tx, meta)?;
+        }
+
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        !self.dirty.is_empty()
+    }
+
+    fn write(&self, meta: &Meta) -> Result<(), Error> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        write_meta(&tx, meta)?;
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self), fields(description = description.as_ref()))]
+    fn find_id(&self, description: &impl AsRef<str>) -> Result<usize, Error> {
+        let ids: Vec<usize> = self
+            .metadata
+            .iter()
+            .enumerate()
+            .filter_map(|(i, meta)| {
+                if meta.id().starts_with(description.as_ref())
+                    || meta
+                        .name()
+                        .map(|name| name.starts_with(description.as_ref()))
+                        .unwrap_or_default()
+                {
+                    Some(i)
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        if ids.len() > 1 {
+            return Err(Error::TooManyMetas(
+                description.as_ref().into(),
+                ids.into_iter().map(|i| self.metadata[i].clone()).collect(),
+            ));
+        } else if ids.is_empty() {
+            return Err(Error::IdNotFound(description.as_ref().into()));
+        }
+        Ok(ids[0])
+    }
+}
+
+impl MetaStore for SqliteStore {
+    fn file(&self) -> &Path {
+        SqliteStore::file(self)
+    }
+
+    fn version(&self) -> &str {
+        SqliteStore::version(self)
+    }
+
+    fn data(&self) -> &[Meta] {
+        SqliteStore::data(self)
+    }
+
+    fn push(&mut self, meta: Meta) -> Result<(), Error> {
+        SqliteStore::push(self, meta)
+    }
+
+    fn get(&self, description: &str) -> Result<&Meta, Error> {
+        SqliteStore::get(self, description)
+    }
+
+    fn get_mut(&mut self, description: &str) -> Result<&mut Meta, Error> {
+        SqliteStore::get_mut(self, &description)
+    }
+
+    fn update(&mut self, id: &str, data: Meta) -> Result<(), Error> {
+        SqliteStore::update(self, &id, data)
+    }
+
+    fn edit(&mut self, description: &str, edit: &Edit) -> Result<Meta, Error> {
+        SqliteStore::edit(self, &description, edit)
+    }
+
+    fn delete(&mut self, description: &str) -> Result<Meta, Error> {
+        SqliteStore::delete(self, description)
+    }
+
+    fn get_union_tags(&self, tags: &[String]) -> Vec<Meta> {
+        SqliteStore::get_union_tags(self, tags)
+    }
+
+    fn get_intersection_tags(&self, tags: &[String]) -> Vec<Meta> {
+        SqliteStore::get_intersection_tags(self, tags)
+    }
+
+    fn import(&mut self, store: FileStore) -> Result<(), Error> {
+        SqliteStore::import(self, store)
+    }
+
+    fn commit(&mut self) -> Result<(), Error> {
+        SqliteStore::commit(self)
+    }
+
+    fn is_dirty(&self) -> bool {
+        SqliteStore::is_dirty(self)
+    }
+}
+
+impl Drop for SqliteStore {
+    fn drop(&mut self) {
+        let _ = self.commit();
+    }
+}
+
+// Upserts a meta row and replaces its tags.
+fn write_meta(tx: &Transaction<'_>, meta: &Meta) -> Result<(), Error> {
+    let data = serde_json::to_string(meta)?;
+
+    tx.execute(
+        "INSERT INTO metas (id, url, data) VALUES (?1, ?2, ?3)
+         ON CONFLICT (id) DO UPDATE SET url = excluded.url, data = excluded.data",
+        params![meta.id, meta.url.as_ref().map(|u| u.as_str()), data],
+    )?;
+
+    tx.execute("DELETE FROM tags WHERE meta_id = ?1", params![meta.id])?;
+
+    for tag in meta.tags.iter() {
+        tx.execute(
+            "INSERT INTO tags (tag, meta_id) VALUES (?1, ?2)",
+            params![tag, meta.id],
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn meta(id: &str, name: &str, tags: &[&str]) -> Meta {
+        Meta::builder()
+            .id(id.to_string())
+            .name(name)
+            .tags(tags.iter().map(|tag| tag.to_string()).collect())
+            .build()
+    }
+
+    fn ids(metas: &[Meta]) -> Vec<&str> {
+        let mut ids: Vec<&str> = metas.iter().map(|meta| meta.id()).collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    #[test]
+    fn migrates_store_json() {
+        let dir = TempDir::new().unwrap();
+        let json = dir.path().join("store.json");
+        let db = dir.path().join("store.db");
+
+        let mut file_store = FileStore::read_file(&json).unwrap();
+        file_store.push(meta("a1", "rust book", &["rust"]));
+        file_store.push(meta("b1", "tokio", &["rust", "async"]));
+        file_store.commit().unwrap();
+        drop(file_store);
+
+        let store = SqliteStore::open(&db, &json).unwrap();
+        assert_eq!(ids(store.data()), vec!["a1", "b1"]);
+        assert_eq!(ids(&store.get_union_tags(&["async"])), vec!["b1"]);
+        assert_eq!(store.version(), CURRENT_SQLITE_STORE_VERSION);
+        drop(store);
+
+        // The json store is only read, and only migrated once
+        assert!(json.exists());
+        std::fs::remove_file(&json).unwrap();
+
+        let store = SqliteStore::open(&db, &json).unwrap();
+        assert_eq!(ids(store.data()), vec!["a1", "b1"]);
+    }
+
+    #[test]
+    fn tags_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let db = dir.path().join("store.db");
+        let json = dir.path().join("store.json");
+
+        let mut store = SqliteStore::open(&db, &json).unwrap();
+        store
+            .push(meta("a1", "rust book", &["rust", "books"]))
+            .unwrap();
+        store.push(meta("b1", "tokio", &["rust", "async"])).unwrap();
+        store.push(meta("c1", "mio", &["async"])).unwrap();
+
+        let tags = |tags: &[&str]| tags.iter().map(|tag| tag.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(
+            ids(&store.get_union_tags(tags(&["books", "async"]))),
+            vec!["a1", "b1", "c1"]
+        );
+        assert_eq!(
+            ids(&store.get_intersection_tags(tags(&["rust", "async"]))),
+            vec!["b1"]
+        );
+
+        let edit = Edit {
+            name: None,
+            url: None,
+            comment: None,
+            add_tags: vec!["async".to_string()],
+            remove_tags: vec!["rust".to_string()],
+        };
+        store.edit(&"a1", &edit).unwrap();
+
+        assert_eq!(ids(&store.get_union_tags(tags(&["rust"]))), vec!["b1"]);
+        assert_eq!(
+            ids(&store.get_intersection_tags(tags(&["books", "async"]))),
+            vec!["a1"]
+        );
+        drop(store);
+
+        let store = SqliteStore::open(&db, &json).unwrap();
+        let expected: BTreeSet<String> = tags(&["async", "books"]).into_iter().collect();
+        assert_eq!(store.get("a1").unwrap().tags, expected);
+        assert_eq!(ids(&store.get_union_tags(tags(&["rust"]))), vec!["b1"]);
+    }
+
+    #[test]
+    fn delete_keeps_the_order() {
+        let dir = TempDir::new().unwrap();
+        let db = dir.path().join("store.db");
+        let json = dir.path().join("store.json");
+
+        let mut store = SqliteStore::open(&db, &json).unwrap();
+        for (id, name) in &[
+            ("a1", "one"),
+            ("b1", "two"),
+            ("c1", "three"),
+            ("d1", "four"),
+        ] {
+            store.push(meta(id, name, &["tag"])).unwrap();
+        }
+
+        assert_eq!(store.delete("b1").unwrap().id(), "b1");
+        assert!(matches!(store.get("b1"), Err(Error::IdNotFound(_))));
+        assert!(store
+            .get_union_tags(&["tag"])
+            .iter()
+            .all(|meta| meta.id() != "b1"));
+
+        let order: Vec<&str> = store.data().iter().map(|meta| meta.id()).collect();
+        assert_eq!(order, vec!["a1", "c1", "d1"]);
+        drop(store);
+
+        let store = SqliteStore::open(&db, &json).unwrap();
+        let order: Vec<&str> = store.data().iter().map(|meta| meta.id()).collect();
+        assert_eq!(order, vec!["a1", "c1", "d1"]);
+    }
+
+    #[test]
+    fn changes_persist_across_reopens() {
+        let dir = TempDir::new().unwrap();
+        let db = dir.path().join("store.db");
+        let json = dir.path().join("store.json");
+
+        let mut store = SqliteStore::open(&db, &json).unwrap();
+        store.push(meta("a1", "rust book", &["rust"])).unwrap();
+        store.push(meta("b1", "tokio", &["async"])).unwrap();
+
+        let mut renamed = store.get("b1").unwrap().clone();
+        renamed.name = Some("tokio docs".to_string());
+        store.update(&"b1", renamed).unwrap();
+
+        // Changes through `get_mut` are written on commit
+        store.get_mut(&"a1").unwrap().comment = Some("2nd edition".to_string());
+        assert!(store.is_dirty());
+        store.commit().unwrap();
+        assert!(!store.is_dirty());
+        drop(store);
+
+        let store = SqliteStore::open(&db, &json).unwrap();
+        assert_eq!(ids(store.data()), vec!["a1", "b1"]);
+        assert_eq!(store.get("b1").unwrap().name(), Some("tokio docs"));
+        assert_eq!(
+            store.get("a1").unwrap().comment.as_deref(),
+            Some("2nd edition")
+        );
+    }
+}
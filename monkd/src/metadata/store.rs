@@ -0,0 +1,143 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use super::{memory_store::MemoryStore, sqlite_store::SqliteStore, FileStore, Meta};
+use crate::error::Error;
+use crate::server::request::Edit;
+
+/// Storage for `Meta` items. The daemon only talks to its store through this
+/// trait, so backends can be swapped through `StoreSettings::backend`.
+///
+/// Lookups take a "description": a prefix of either an id or a name.
+pub trait MetaStore: std::fmt::Debug + Send + Sync {
+    /// The file or database backing this store.
+    fn file(&self) -> &Path;
+
+    fn version(&self) -> &str;
+
+    fn data(&self) -> &[Meta];
+
+    fn push(&mut self, meta: Meta) -> Result<(), Error>;
+
+    fn get(&self, description: &str) -> Result<&Meta, Error>;
+
+    fn get_mut(&mut self, description: &str) -> Result<&mut Meta, Error>;
+
+    fn update(&mut self, id: &str, data: Meta) -> Result<(), Error>;
+
+    fn edit(&mut self, description: &str, edit: &Edit) -> Result<Meta, Error>;
+
+    fn delete(&mut self, description: &str) -> Result<Meta, Error>;
+
+    /// Metas with at least one of the given tags.
+    fn get_union_tags(&self, tags: &[String]) -> Vec<Meta>;
+
+    /// Metas with every one of the given tags.
+    fn get_intersection_tags(&self, tags: &[String]) -> Vec<Meta>;
+
+    /// Merge another store into this one. Metas with a matching url are
+    /// unioned instead of duplicated.
+    fn import(&mut self, store: FileStore) -> Result<(), Error>;
+
+    fn import_file(&mut self, file: &Path) -> Result<(), Error> {
+        tracing::info!("Importing file {}", file.display());
+        match FileStore::read_file(file) {
+            Ok(store) => self.import(store),
+            Err(e) => {
+                tracing::warn!("Import error: {:?}", e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Export the store in the `store.json` format.
+    fn write_file(&self, path: &Path) -> Result<(), Error> {
+        FileStore::from_metas(self.data().to_vec()).write_file(path)
+    }
+
+    fn commit(&mut self) -> Result<(), Error>;
+
+    fn is_dirty(&self) -> bool;
+}
+
+pub fn open(settings: &StoreSettings) -> Result<Box<dyn MetaStore>, Error> {
+    match settings.backend {
+        StoreBackend::Json => Ok(Box::new(FileStore::read_file(&settings.path)?)),
+        StoreBackend::Sqlite => Ok(Box::new(SqliteStore::open(
+            settings.database(),
+            &settings.path,
+        )?)),
+        StoreBackend::Memory => Ok(Box::new(MemoryStore::new())),
+    }
+}
+
+pub async fn commit_loop(
+    handle: Arc<RwLock<Box<dyn MetaStore>>>,
+    delay: std::time::Duration,
+) -> Result<(), Error> {
+    tracing::info!("Auto Commit Delay: {:3.1} s.", delay.as_secs_f32());
+    loop {
+        tokio::time::sleep(delay).await;
+
+        let _ = handle
+            .write()
+            .await
+            .commit()
+            .map_err(|e| tracing::error!("Store: {}", e));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StoreBackend {
+    /// The original `store.json` file, rewritten on every commit.
+    Json,
+    /// A SQLite database next to `store.json`. An existing `store.json` is
+    /// migrated into it the first time it is opened.
+    Sqlite,
+    /// Nothing is persisted. Useful for tests and throwaway daemons.
+    Memory,
+}
+
+impl Default for StoreBackend {
+    fn default() -> Self {
+        StoreBackend::Sqlite
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoreSettings {
+    pub(crate) path: PathBuf,
+    #[serde(default)]
+    pub(crate) backend: StoreBackend,
+    /// Defaults to `store.db` next to `path`
+    #[serde(default)]
+    pub(crate) database: Option<PathBuf>,
+}
+
+impl StoreSettings {
+    pub fn database(&self) -> PathBuf {
+        self.database
+            .clone()
+            .unwrap_or_else(|| self.path.with_file_name("store.db"))
+    }
+}
+
+impl Default for StoreSettings {
+    fn default() -> Self {
+        if let Some(dirs) = crate::get_dirs() {
+            StoreSettings {
+                path: dirs.data_dir().join("store.json"),
+                backend: StoreBackend::default(),
+                database: None,
+            }
+        } else {
+            StoreSettings {
+                path: "./store.json".into(),
+                backend: StoreBackend::default(),
+                database: None,
+            }
+        }
+    }
+}
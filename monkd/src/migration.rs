@@ -0,0 +1,117 @@
+// Versioned upgrades for data monkd keeps on disk.
+//
+// A component registers a list of `Migration`s, each one upgrading its data
+// from one version to the next. On startup `run` chains the steps from the
+// version found on disk up to the version the code expects. Adding a field
+// means bumping the component's version constant and appending a step.
+
+use crate::error::Error;
+
+pub struct Migration<T: ?Sized> {
+    pub from: &'static str,
+    pub to: &'static str,
+    pub description: &'static str,
+    pub run: fn(&mut T) -> Result<(), Error>,
+}
+
+/// Upgrade `data` from `version` to `target`. Returns `true` if any step
+/// was run, and an error if there is no chain of steps between the two
+/// versions (e.g. the data was written by a newer monkd).
+pub fn run<T: ?Sized>(
+    data: &mut T,
+    version: &str,
+    target: &str,
+    migrations: &[Migration<T>],
+) -> Result<bool, Error> {
+    let mut version = version.to_string();
+    let mut migrated = false;
+
+    while version != target {
+        let step = migrations
+            .iter()
+            .find(|m| m.from == version)
+            .ok_or_else(|| Error::Migration(version.clone(), target.to_string()))?;
+
+        tracing::info!(
+            "Migrating {} -> {}: {}",
+            step.from,
+            step.to,
+            step.description
+        );
+
+        (step.run)(data)?;
+        version = step.to.to_string();
+        migrated = true;
+    }
+
+    Ok(migrated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_one(steps: &mut Vec<u32>) -> Result<(), Error> {
+        steps.push(1);
+        Ok(())
+    }
+
+    fn push_two(steps: &mut Vec<u32>) -> Result<(), Error> {
+        steps.push(2);
+        Ok(())
+    }
+
+    // Out of order on purpose, steps are found by their `from` version
+    const MIGRATIONS: &[Migration<Vec<u32>>] = &[
+        Migration {
+            from: "0.1.0",
+            to: "0.2.0",
+            description: "push 2",
+            run: push_two,
+        },
+        Migration {
+            from: "0.0.0",
+            to: "0.1.0",
+            description: "push 1",
+            run: push_one,
+        },
+    ];
+
+    #[test]
+    fn chains_steps_up_to_the_target() {
+        let mut steps = Vec::new();
+
+        assert!(run(&mut steps, "0.0.0", "0.2.0", MIGRATIONS).unwrap());
+        assert_eq!(steps, vec![1, 2]);
+    }
+
+    #[test]
+    fn starts_from_the_stored_version() {
+        let mut steps = Vec::new();
+
+        assert!(run(&mut steps, "0.1.0", "0.2.0", MIGRATIONS).unwrap());
+        assert_eq!(steps, vec![2]);
+    }
+
+    #[test]
+    fn current_version_runs_nothing() {
+        let mut steps = Vec::new();
+
+        assert!(!run(&mut steps, "0.2.0", "0.2.0", MIGRATIONS).unwrap());
+        assert!(steps.is_empty());
+    }
+
+    #[test]
+    fn unknown_version_is_an_error() {
+        let mut steps = Vec::new();
+
+        match run(&mut steps, "0.3.0", "0.2.0", MIGRATIONS) {
+            Err(Error::Migration(from, to)) => {
+                assert_eq!(from, "0.3.0");
+                assert_eq!(to, "0.2.0");
+            }
+            other => panic!("expected a migration error, got {:?}", other),
+        }
+        assert!(steps.is_empty());
+    }
+}
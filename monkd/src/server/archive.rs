@@ -0,0 +1,322 @@
+// Offline copies served over HTTP.
+//
+// `/archive/{id}` looks the item up in the offline store and redirects to
+// `/archive/{id}/{file}`. That route only serves the item's own offline file
+// and, for videos, the subtitles saved next to it, so nothing else in the
+// data folder can be reached. Files go through `warp::fs`, which sets the
+// content type from the extension and answers range requests, so videos can
+// be seeked.
+//
+// Saved pages share an origin with the API, and the browser sends them the
+// token cookie, so every response is sandboxed to keep their scripts away
+// from it.
+
+use async_channel::Sender;
+use std::path::{Component, Path, PathBuf};
+use tokio::sync::oneshot;
+use url::Url;
+use warp::{http::StatusCode, http::Uri, path::Peek, Filter, Rejection, Reply};
+
+use super::{
+    dispatch,
+    request::Request,
+    response::Response,
+    rest::{error_reply, into_reply},
+};
+use crate::metadata::offline_store::{OfflineData, Status};
+
+pub fn routes(
+    sender: Sender<(Request, Option<oneshot::Sender<Response>>)>,
+    data_folder: PathBuf,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let with_sender = warp::any().map(move || sender.clone());
+    let folder = data_folder.clone();
+    let with_folder = warp::any().map(move || folder.clone());
+
+    let item = warp::get()
+        .and(warp::path!("archive" / String))
+        .and(with_sender.clone())
+        .and(with_folder.clone())
+        .and_then(item);
+
+    // The rest of the path is only peeked at, and left for `warp::fs::dir`
+    // once it's known to be the item's file
+    let files = warp::get()
+        .and(warp::path!("archive" / String / ..))
+        .and(warp::path::peek())
+        .and(with_sender)
+        .and(with_folder)
+        .and_then(check_file)
+        .untuple_one()
+        .and(warp::fs::dir(data_folder));
+
+    // No `allow-same-origin`, so scripts run in an opaque origin
+    item.or(files).with(warp::reply::with::header(
+        "content-security-policy",
+        "sandbox",
+    ))
+}
+
+async fn item(
+    id: String,
+    sender: Sender<(Request, Option<oneshot::Sender<Response>>)>,
+    data_folder: PathBuf,
+) -> Result<warp::reply::Response, Rejection> {
+    let data = match dispatch(&sender, Request::GetOffline { id }).await {
+        Some(Response::Offline(data)) => data,
+        response => return Ok(into_reply(response).into_response()),
+    };
+
+    if data.status != Status::Ready {
+        let message = format!("`{}` is not downloaded: {:?}", data.id(), data.status);
+        return Ok(error_reply(message, StatusCode::NOT_FOUND).into_response());
+    }
+
+    match file_path(&data, &data_folder).and_then(|path| file_uri(data.id(), &path)) {
+        Some(uri) => Ok(warp::redirect::temporary(uri).into_response()),
+        None => {
+            let message = format!("`{}` has no file in the offline folder", data.id());
+            Ok(error_reply(message, StatusCode::NOT_FOUND).into_response())
+        }
+    }
+}
+
+// Let the request through to `warp::fs::dir` only if it asks for a file that
+// belongs to the item `id`
+async fn check_file(
+    id: String,
+    rest: Peek,
+    sender: Sender<(Request, Option<oneshot::Sender<Response>>)>,
+    data_folder: PathBuf,
+) -> Result<(), Rejection> {
+    let data = match dispatch(&sender, Request::GetOffline { id }).await {
+        Some(Response::Offline(data)) if data.status == Status::Ready => data,
+        _ => return Err(warp::reject::not_found()),
+    };
+
+    if serves(&data, &data_folder, rest.as_str()) {
+        Ok(())
+    } else {
+        Err(warp::reject::not_found())
+    }
+}
+
+// Whether `requested`, a percent-encoded path relative to the offline folder,
+// is the item's file or one of the subtitles downloaded with it
+fn serves(data: &OfflineData, data_folder: &Path, requested: &str) -> bool {
+    let path = match file_path(data, data_folder) {
+        Some(path) => path,
+        None => return false,
+    };
+
+    if requested == encode_path(&path) {
+        return true;
+    }
+
+    // `<id>.<language>.vtt`, in the same folder as the file. Plain characters
+    // only, so nothing percent-encoded can turn it into a path
+    let folder: String = path[..path.len() - 1]
+        .iter()
+        .map(|segment| format!("{}/", encode(segment)))
+        .collect();
+
+    let name = match requested.strip_prefix(&folder) {
+        Some(name) => name,
+        None => return false,
+    };
+
+    name.chars()
+        .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+        && name.starts_with(&format!("{}.", data.id()))
+        && name.ends_with(".vtt")
+}
+
+// The item's file relative to the offline folder, one entry per path segment.
+// Files are either directly in the folder, e.g. `<id>.html`, or in the item's
+// own folder, e.g. `<id>/<id>.mkv` for videos.
+fn file_path<'d>(data: &'d OfflineData, data_folder: &Path) -> Option<Vec<&'d str>> {
+    let relative = data.file()?.strip_prefix(data_folder).ok()?;
+
+    let path = relative
+        .components()
+        .map(|component| match component {
+            Component::Normal(segment) => segment.to_str(),
+            _ => None,
+        })
+        .collect::<Option<Vec<&str>>>()?;
+
+    match path.as_slice() {
+        [_] => Some(path),
+        [folder, _] if *folder == data.id() => Some(path),
+        _ => None,
+    }
+}
+
+fn file_uri(id: &str, path: &[&str]) -> Option<Uri> {
+    format!("/archive/{}/{}", encode(id), encode_path(path))
+        .parse()
+        .ok()
+}
+
+fn encode_path(path: &[&str]) -> String {
+    path.iter()
+        .map(|segment| encode(segment))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// Percent-encode a single path segment
+fn encode(segment: &str) -> String {
+    let mut url = match Url::parse("http://localhost/") {
+        Ok(url) => url,
+        Err(_) => return segment.to_string(),
+    };
+
+    if let Ok(mut segments) = url.path_segments_mut() {
+        segments.clear().push(segment);
+    }
+
+    url.path().trim_start_matches('/').to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn ready(id: &str, file: PathBuf) -> OfflineData {
+        OfflineData {
+            id: id.to_string(),
+            file: Some(file),
+            status: Status::Ready,
+            ..OfflineData::default()
+        }
+    }
+
+    #[test]
+    fn serves_pages_and_pdfs() {
+        let folder = Path::new("/data/offline");
+
+        for name in &["a1.html", "a1.pdf"] {
+            let data = ready("a1", folder.join(name));
+
+            assert_eq!(file_path(&data, folder), Some(vec![*name]));
+            assert!(serves(&data, folder, name));
+        }
+
+        let data = ready("a1", folder.join("a1 copy.html"));
+        assert!(serves(&data, folder, "a1%20copy.html"));
+        assert!(!serves(&data, folder, "a1 copy.html"));
+    }
+
+    #[test]
+    fn serves_videos_and_their_subtitles() {
+        let folder = Path::new("/data/offline");
+        let data = ready("a1", folder.join("a1").join("a1.mkv"));
+
+        assert_eq!(file_path(&data, folder), Some(vec!["a1", "a1.mkv"]));
+        assert!(serves(&data, folder, "a1/a1.mkv"));
+        assert!(serves(&data, folder, "a1/a1.en.vtt"));
+        assert!(serves(&data, folder, "a1/a1.pt-BR.vtt"));
+
+        // Subtitles of another item, or outside the item's folder
+        assert!(!serves(&data, folder, "a1/b1.en.vtt"));
+        assert!(!serves(&data, folder, "a1.en.vtt"));
+        assert!(!serves(&data, folder, "a1/a1.en.srt"));
+    }
+
+    #[test]
+    fn rejects_files_outside_the_item() {
+        let folder = Path::new("/data/offline");
+
+        let outside = [
+            PathBuf::from("/etc/passwd"),
+            folder.join("b1").join("a1.mkv"),
+            folder.join("a1").join("b1").join("a1.mkv"),
+            folder.join("a1").join("..").join("store.json"),
+            PathBuf::from("/data/offline-old/a1.html"),
+        ];
+
+        for file in outside.iter() {
+            assert_eq!(file_path(&ready("a1", file.clone()), folder), None);
+        }
+    }
+
+    #[test]
+    fn rejects_traversal() {
+        let folder = Path::new("/data/offline");
+        let page = ready("a1", folder.join("a1.html"));
+        let video = ready("a1", folder.join("a1").join("a1.mkv"));
+
+        for data in &[page, video] {
+            for requested in &[
+                "../store.json",
+                "a1/../store.json",
+                "%2e%2e/store.json",
+                "a1%2F..%2Fstore.json",
+                "a1.%2F..%2Fstore.vtt",
+                "a1/a1.%2e%2e.vtt",
+                "/etc/passwd",
+                "store.json",
+            ] {
+                assert!(!serves(data, folder, requested), "{}", requested);
+            }
+        }
+    }
+
+    // A daemon that knows about `data` and nothing else
+    fn daemon(data: OfflineData) -> Sender<(Request, Option<oneshot::Sender<Response>>)> {
+        let (sender, receiver) =
+            async_channel::unbounded::<(Request, Option<oneshot::Sender<_>>)>();
+
+        tokio::spawn(async move {
+            while let Ok((request, response)) = receiver.recv().await {
+                let reply = match request {
+                    Request::GetOffline { id } if id == data.id => Response::Offline(data.clone()),
+                    Request::GetOffline { id } => Response::NotFound(id),
+                    _ => Response::Unhandled,
+                };
+
+                if let Some(response) = response {
+                    let _ = response.send(reply);
+                }
+            }
+        });
+
+        sender
+    }
+
+    #[tokio::test]
+    async fn archive_responses_are_sandboxed() {
+        let dir = TempDir::new().unwrap();
+        let folder = dir.path().to_path_buf();
+        std::fs::create_dir(folder.join("a1")).unwrap();
+        std::fs::write(folder.join("a1").join("a1.mkv"), "video").unwrap();
+
+        let data = ready("a1", folder.join("a1").join("a1.mkv"));
+        let routes = routes(daemon(data), folder);
+
+        let redirect = warp::test::request()
+            .path("/archive/a1")
+            .reply(&routes)
+            .await;
+        assert_eq!(redirect.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(redirect.headers()["location"], "/archive/a1/a1/a1.mkv");
+        assert_eq!(redirect.headers()["content-security-policy"], "sandbox");
+
+        let file = warp::test::request()
+            .path("/archive/a1/a1/a1.mkv")
+            .reply(&routes)
+            .await;
+        assert_eq!(file.status(), StatusCode::OK);
+        assert_eq!(file.body().as_ref(), b"video");
+        assert_eq!(file.headers()["content-security-policy"], "sandbox");
+
+        let missing = warp::test::request()
+            .path("/archive/b1")
+            .reply(&routes)
+            .await;
+        assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+        assert_eq!(missing.headers()["content-security-policy"], "sandbox");
+    }
+}
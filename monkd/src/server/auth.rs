@@ -0,0 +1,147 @@
+// Bearer token authentication.
+//
+// When `daemon.token` is set, every route except the web interface needs the
+// token, either as `Authorization: Bearer <token>` or in the `monk_token`
+// cookie, which the web interface sets so links and event streams work too.
+// The cookie is percent-encoded, so tokens can contain `;` and the like.
+
+use percent_encoding::percent_decode_str;
+use serde_json::json;
+use warp::{http::StatusCode, reply, Filter, Rejection, Reply};
+
+pub const COOKIE: &str = "monk_token";
+
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl warp::reject::Reject for Unauthorized {}
+
+/// Rejects with `Unauthorized` unless the request carries `token`. Lets
+/// everything through if there is no token.
+pub fn authorize(token: Option<String>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::cookie::optional::<String>(COOKIE))
+        .and_then(move |header: Option<String>, cookie: Option<String>| {
+            let token = token.clone();
+
+            async move {
+                let expected = match token {
+                    Some(token) => token,
+                    None => return Ok(()),
+                };
+
+                let cookie = cookie.and_then(|cookie| {
+                    percent_decode_str(&cookie)
+                        .decode_utf8()
+                        .ok()
+                        .map(|cookie| cookie.into_owned())
+                });
+
+                let given = header
+                    .as_deref()
+                    .and_then(|h| h.strip_prefix("Bearer "))
+                    .or(cookie.as_deref());
+
+                match given {
+                    Some(given) if constant_time_eq(given.as_bytes(), expected.as_bytes()) => {
+                        Ok(())
+                    }
+                    _ => Err(warp::reject::custom(Unauthorized)),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Turns `Unauthorized` into a `401`. Other rejections are passed on.
+pub async fn recover(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    if rejection.find::<Unauthorized>().is_none() {
+        return Err(rejection);
+    }
+
+    let body = reply::json(&json!({ "error": "missing or invalid token" }));
+    let reply = reply::with_status(body, StatusCode::UNAUTHORIZED);
+
+    Ok(reply::with_header(reply, "www-authenticate", "Bearer"))
+}
+
+// Compare without returning early, so the time taken doesn't tell how much of
+// the token was right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::test::RequestBuilder;
+
+    async fn status(token: Option<&str>, request: RequestBuilder) -> StatusCode {
+        let routes = authorize(token.map(String::from))
+            .map(|| "ok")
+            .recover(recover);
+
+        request.reply(&routes).await.status()
+    }
+
+    #[tokio::test]
+    async fn accepts_the_token_in_the_header() {
+        let request = warp::test::request().header("authorization", "Bearer s3cret");
+
+        assert_eq!(status(Some("s3cret"), request).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn accepts_the_token_in_the_cookie() {
+        let request = warp::test::request().header("cookie", "monk_token=s3cret");
+        assert_eq!(status(Some("s3cret"), request).await, StatusCode::OK);
+
+        // As written by the web interface, with `encodeURIComponent`
+        let request = warp::test::request().header("cookie", "monk_token=a%3Bb%3D%20c%25");
+        assert_eq!(status(Some("a;b= c%"), request).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_wrong_token() {
+        let request = warp::test::request().header("authorization", "Bearer s3crets");
+        assert_eq!(
+            status(Some("s3cret"), request).await,
+            StatusCode::UNAUTHORIZED
+        );
+
+        let request = warp::test::request().header("authorization", "s3cret");
+        assert_eq!(
+            status(Some("s3cret"), request).await,
+            StatusCode::UNAUTHORIZED
+        );
+
+        let request = warp::test::request().header("cookie", "monk_token=other");
+        assert_eq!(
+            status(Some("s3cret"), request).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_a_missing_token() {
+        let reply = warp::test::request()
+            .reply(
+                &authorize(Some("s3cret".to_string()))
+                    .map(|| "ok")
+                    .recover(recover),
+            )
+            .await;
+
+        assert_eq!(reply.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(reply.headers()["www-authenticate"], "Bearer");
+    }
+
+    #[tokio::test]
+    async fn lets_everything_through_without_a_token() {
+        assert_eq!(status(None, warp::test::request()).await, StatusCode::OK);
+    }
+}
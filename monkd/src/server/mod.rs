@@ -1,5 +1,9 @@
+pub mod archive;
+pub mod auth;
 pub mod request;
 pub mod response;
+pub mod rest;
+pub mod ui;
 
 use self::request::Request;
 use self::response::Response;
@@ -7,58 +11,247 @@ use self::response::Response;
 use std::net::SocketAddr;
 
 use async_channel::Sender;
+use futures::{FutureExt, StreamExt};
 use tokio::sync::oneshot;
+use tokio_stream::wrappers::BroadcastStream;
 
-use warp::{reply::json, Filter};
+use warp::{reply::json, sse, Filter};
+
+use crate::events::EventSender;
+use crate::settings::Settings;
+
+/// The version of the `Request`/`Response` protocol, sent in
+/// `Response::Hello`. Bump it whenever a change would keep an older `monk`
+/// and a newer monkd, or the other way around, from understanding each other.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Optional capabilities of this monkd, sent in `Response::Hello`
+pub const FEATURES: &[&str] = &[
+    "jobs",
+    "events",
+    "rest",
+    "archive",
+    "ui",
+    "auth",
+    "unix-socket",
+    "notes",
+];
 
 pub struct Server;
 
 impl Server {
     pub async fn spawn(
-        addr: impl Into<SocketAddr> + 'static,
+        settings: Settings,
         sender: Sender<(Request, Option<oneshot::Sender<Response>>)>,
+        events: EventSender,
         shutdown: oneshot::Receiver<()>,
     ) {
-        let sender = warp::any().map(move || sender.clone());
-
-        let route = warp::any()
-            .and(sender)
-            .and(warp::body::json())
-            .and_then(handle)
-            .with(warp::filters::log::log("warp"));
+        let addr = SocketAddr::new(settings.daemon().address, settings.daemon().port);
 
-        let server = warp::serve(route);
+        // Only the user can connect to the socket, and with a token set every
+        // client on TCP has to prove it's theirs
+        let trusted = settings.daemon().token.is_some() || settings.daemon().allow_file_urls;
+        let route = routes(&settings, sender.clone(), events.clone(), trusted);
 
-        let res = server.try_bind_with_graceful_shutdown(addr, async {
+        let shutdown = async move {
             shutdown.await.ok();
-        });
+        }
+        .shared();
+
+        let mut servers = Vec::new();
+        let mut socket: Option<&std::path::Path> = None;
+
+        if settings.daemon().tcp {
+            let server = warp::serve(route.clone());
 
-        match res {
-            Ok((_, server)) => {
-                tracing::info!("Request server bound socket, and starting");
-                server.await;
+            let res = match &settings.daemon().tls {
+                Some(tls) => server
+                    .tls()
+                    .cert_path(&tls.cert)
+                    .key_path(&tls.key)
+                    .try_bind_with_graceful_shutdown(addr, shutdown.clone())
+                    .map(|(_, server)| server.boxed()),
+                None => server
+                    .try_bind_with_graceful_shutdown(addr, shutdown.clone())
+                    .map(|(_, server)| server.boxed()),
+            };
+
+            let scheme = if settings.daemon().tls.is_some() {
+                "https"
+            } else {
+                "http"
+            };
+
+            match res {
+                Ok(server) => {
+                    tracing::info!("Request server bound socket, and starting");
+                    tracing::info!("Web interface at {}://{}/ui", scheme, addr);
+                    servers.push(server);
+                }
+                Err(e) => tracing::error!("{}", e),
             }
-            Err(e) => tracing::error!("{}", e),
+        }
+
+        #[cfg(unix)]
+        if let Some(path) = &settings.daemon().socket {
+            match bind_socket(path) {
+                Ok(listener) => {
+                    tracing::info!("Request server listening on {}", path.display());
+
+                    let incoming = tokio_stream::wrappers::UnixListenerStream::new(listener);
+                    let route = routes(&settings, sender, events, true);
+                    let server = warp::serve(route)
+                        .serve_incoming_with_graceful_shutdown(incoming, shutdown.clone());
+                    servers.push(server.boxed());
+                    socket = Some(path.as_path());
+                }
+                Err(e) => tracing::error!("could not listen on {}: {}", path.display(), e),
+            }
+        }
+
+        if servers.is_empty() {
+            tracing::error!("monkd is not listening for requests");
+        }
+
+        futures::future::join_all(servers).await;
+
+        // Only remove the socket if it's ours, it may belong to another daemon
+        if let Some(path) = socket {
+            let _ = std::fs::remove_file(path);
         }
     }
 }
 
+// Every route, for one transport. `local_files` is whether its clients can
+// add `file://` items, which have monkd read files off its own disk.
+fn routes(
+    settings: &Settings,
+    sender: Sender<(Request, Option<oneshot::Sender<Response>>)>,
+    events: EventSender,
+    local_files: bool,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let rest = rest::routes(sender.clone(), local_files);
+    let archive = archive::routes(sender.clone(), settings.offline().data_folder.clone());
+    let sender = warp::any().map(move || sender.clone());
+
+    let events = warp::path("events")
+        .and(warp::path::end())
+        .and(warp::get())
+        .map(move || sse::reply(sse::keep_alive().stream(event_stream(&events))));
+
+    let requests = warp::any()
+        .and(sender)
+        .and(warp::any().map(move || local_files))
+        .and(warp::body::json())
+        .and_then(handle);
+
+    // `monk` sends everything to `/`, so the JSON-body endpoint goes last
+    let api = events.or(rest).or(archive).or(requests);
+
+    // The web interface itself is static, it asks for the token when the
+    // API turns it away
+    ui::routes()
+        .or(auth::authorize(settings.daemon().token.clone()).and(api))
+        .recover(auth::recover)
+        .with(warp::filters::log::log("warp"))
+}
+
+// Listen on a Unix socket only the current user can connect to.
+#[cfg(unix)]
+fn bind_socket(path: &std::path::Path) -> std::io::Result<tokio::net::UnixListener> {
+    use std::io::{Error, ErrorKind};
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    if let Some(parent) = path.parent() {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(parent)?;
+    }
+
+    // A socket left behind by a daemon that didn't shut down cleanly
+    if path.exists() {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(Error::new(
+                ErrorKind::AddrInUse,
+                "another monkd is listening on it",
+            ));
+        }
+
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+
+    Ok(listener)
+}
+
+// Every event published after the client subscribed, as JSON. A client that
+// falls too far behind skips the events it missed.
+fn event_stream(
+    events: &EventSender,
+) -> impl futures::Stream<Item = Result<sse::Event, serde_json::Error>> + Send + 'static {
+    BroadcastStream::new(events.subscribe()).filter_map(|event| async move {
+        match event {
+            Ok(event) => Some(sse::Event::default().json_data(&event)),
+            Err(e) => {
+                tracing::warn!("[events] {}", e);
+                None
+            }
+        }
+    })
+}
+
 #[tracing::instrument]
 pub async fn handle(
     sender: Sender<(Request, Option<oneshot::Sender<Response>>)>,
-    req: Request,
+    local_files: bool,
+    req: serde_json::Value,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let (send, resp) = oneshot::channel();
-    sender
-        .send((req, Some(send)))
-        .await
-        .map_err(|_| ())
-        .unwrap();
-
-    Ok(match resp.await {
-        Ok(r) => json(&r),
-        Err(_) => json(&Response::Error(
-            "Error receiving oneshot message".to_string(),
+    // Answer requests we don't know, e.g. from a newer `monk`, with a
+    // `Response` instead of a bare 400
+    let req = match serde_json::from_value::<Request>(req) {
+        Ok(req) => req,
+        Err(e) => {
+            tracing::warn!("unknown request: {}", e);
+            return Ok(json(&Response::UnknownRequest(e.to_string())));
+        }
+    };
+
+    if let Some(refused) = refuse_local_file(&req, local_files) {
+        return Ok(json(&refused));
+    }
+
+    Ok(match dispatch(&sender, req).await {
+        Some(r) => json(&r),
+        None => json(&Response::Error(
+            "monkd failed to handle the request".to_string(),
         )),
     })
 }
+
+// The error for a `file://` item from a client that may not add one
+pub(crate) fn refuse_local_file(req: &Request, local_files: bool) -> Option<Response> {
+    if local_files || !req.reads_local_file() {
+        return None;
+    }
+
+    Some(Response::Error(
+        "file:// urls are only accepted over the Unix socket, with a token, \
+         or with `daemon.allow_file_urls` set"
+            .to_string(),
+    ))
+}
+
+// Send a request to the daemon and wait for its response. `None` if the
+// daemon dropped the request without answering.
+pub(crate) async fn dispatch(
+    sender: &Sender<(Request, Option<oneshot::Sender<Response>>)>,
+    req: Request,
+) -> Option<Response> {
+    let (send, resp) = oneshot::channel();
+    sender.send((req, Some(send))).await.ok()?;
+
+    resp.await.ok()
+}
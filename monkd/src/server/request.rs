@@ -2,16 +2,37 @@ use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 use url::Url;
 
-use crate::metadata::{offline_store::OfflineData, Meta};
+use crate::metadata::{offline_store::OfflineData, page::PageMeta, Meta};
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Request {
+    /// Sent by `monk` before anything else, to check that the daemon speaks
+    /// the same protocol
+    Hello {
+        version: String,
+        protocol: u32,
+    },
     Add {
         name: Option<String>,
         url: Option<Url>,
         comment: Option<String>,
         tags: Vec<String>,
     },
+    /// Add a note with a markdown body. Named after its first line if
+    /// `name` isn't set
+    AddNote {
+        name: Option<String>,
+        body: String,
+        tags: Vec<String>,
+    },
+    GetNote {
+        id: String,
+    },
+    /// Replace the body of a note
+    SetNote {
+        id: String,
+        body: String,
+    },
     List {
         count: Option<usize>,
         tags: Vec<String>,
@@ -26,9 +47,21 @@ pub enum Request {
     Get {
         id: String,
     },
+    /// The offline copy of an item
+    GetOffline {
+        id: String,
+    },
     Download {
         id: Option<String>,
     },
+    /// Download every item whose last download failed again
+    RetryFailed,
+    /// List the downloads and index runs that haven't finished
+    Jobs,
+    /// Stop a job. `id` can be a prefix of the job's id.
+    CancelJob {
+        id: String,
+    },
     Open {
         id: String,
         online: bool,
@@ -69,6 +102,44 @@ pub enum Request {
     UpdateOffline(OfflineData),
     #[serde(skip)]
     UpdateMeta(Meta),
+    /// Sent once the page at an item's url has been fetched
+    #[serde(skip)]
+    UpdatePage {
+        id: String,
+        url: Url,
+        page: PageMeta,
+    },
+    /// A scheduled retry of a failed download
+    #[serde(skip)]
+    RetryDownload(String),
+    /// Sent by the index once the documents for these ids are committed
+    #[serde(skip)]
+    IndexCommitted(Vec<String>),
+    /// Sent by the indexing pipeline when an item couldn't be indexed
+    #[serde(skip)]
+    IndexFailed {
+        id: String,
+        error: String,
+    },
+    /// Sent by the indexing pipeline when it drops an item that was cancelled
+    #[serde(skip)]
+    IndexCancelled {
+        id: String,
+    },
+}
+
+impl Request {
+    /// Whether this points an item at a `file://` url, which has monkd read a
+    /// file off its own disk
+    pub fn reads_local_file(&self) -> bool {
+        let url = match self {
+            Request::Add { url, .. } => url.clone(),
+            Request::Edit { edit, .. } => edit.url.as_deref().and_then(|url| Url::parse(url).ok()),
+            _ => None,
+        };
+
+        matches!(url, Some(url) if url.scheme() == "file")
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -85,6 +156,8 @@ pub struct Edit {
     pub name: Option<String>,
     pub url: Option<String>,
     pub comment: Option<String>,
+    #[serde(default)]
     pub add_tags: Vec<String>,
+    #[serde(default)]
     pub remove_tags: Vec<String>,
 }
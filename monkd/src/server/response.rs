@@ -3,14 +3,23 @@ use std::path::PathBuf;
 use tantivy::Snippet;
 
 use crate::error::Error;
-use crate::metadata::offline_store::Status as OfflineStatus;
+use crate::jobs::JobInfo;
+use crate::metadata::offline_store::{OfflineData, Status as OfflineStatus};
 use crate::metadata::{meta::IndexStatus, Meta};
 use crate::status::StatusResponse;
 
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub enum Response {
+    Hello {
+        version: String,
+        protocol: u32,
+        features: Vec<String>,
+    },
     NewId(String),
     Item(Meta),
+    /// A note and its body
+    Note(Meta, String),
+    Offline(OfflineData),
     List(Vec<Meta>),
     Error(String),
     Custom(String),
@@ -22,10 +31,14 @@ pub enum Response {
     IndexStatus(String, Option<IndexStatus>),
     Indexing(String),
     Status(StatusResponse),
+    Jobs(Vec<JobInfo>),
     SearchResult(Vec<(Meta, SnippetDef)>), // Meta, Fragment, Highlight
     Many(Vec<Response>),
     Open(PathBuf),
     Unhandled,
+    /// The request couldn't be deserialized, e.g. because it's from a newer
+    /// `monk`
+    UnknownRequest(String),
     Ok,
 }
 
@@ -33,6 +46,7 @@ impl From<Error> for Response {
     fn from(e: Error) -> Self {
         match e {
             Error::AlreadyExists(id) => Response::Error(format!("`{}` already exists", id)),
+            Error::IdNotFound(id) => Response::NotFound(id),
             Error::TooManyMetas(id, metas) => Response::TooManyMeta(id, metas),
             e => Response::Error(e.to_string()),
         }
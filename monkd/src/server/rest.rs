@@ -0,0 +1,406 @@
+// REST routes for clients other than `monk`.
+//
+// Every route builds a `Request`, sends it to the daemon the same way the
+// JSON-body endpoint does, and replies with the response's payload as plain
+// JSON and a matching status code, so clients don't need to know how the
+// `Request` and `Response` enums are encoded.
+
+use async_channel::Sender;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::oneshot;
+use url::Url;
+use warp::{
+    http::StatusCode,
+    reply::{self, Json, WithStatus},
+    Filter, Rejection, Reply,
+};
+
+use super::{
+    dispatch, refuse_local_file,
+    request::{Edit, Request},
+    response::Response,
+};
+
+// Largest request body accepted, in bytes
+const MAX_BODY: u64 = 64 * 1024;
+
+#[derive(Debug, Deserialize)]
+struct ListQuery {
+    count: Option<usize>,
+    /// Comma separated
+    tags: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchQuery {
+    q: String,
+    count: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NewItem {
+    name: Option<String>,
+    url: Option<Url>,
+    comment: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+/// `local_files` is whether clients can add `file://` items
+pub fn routes(
+    sender: Sender<(Request, Option<oneshot::Sender<Response>>)>,
+    local_files: bool,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let sender = warp::any().map(move || sender.clone());
+    let local_files = warp::any().map(move || local_files);
+    let body = warp::body::content_length_limit(MAX_BODY);
+
+    // GET /items?count=&tags=
+    let list = warp::get()
+        .and(warp::path!("items"))
+        .and(warp::query::<ListQuery>())
+        .and(sender.clone())
+        .and_then(list);
+
+    // GET /items/{id}
+    let get = warp::get()
+        .and(warp::path!("items" / String))
+        .and(sender.clone())
+        .and_then(get);
+
+    // POST /items
+    let add = warp::post()
+        .and(warp::path!("items"))
+        .and(body.and(warp::body::json()))
+        .and(sender.clone())
+        .and(local_files)
+        .and_then(add);
+
+    // PATCH /items/{id}
+    let edit = warp::patch()
+        .and(warp::path!("items" / String))
+        .and(body.and(warp::body::json()))
+        .and(sender.clone())
+        .and(local_files)
+        .and_then(edit);
+
+    // DELETE /items/{id}
+    let delete = warp::delete()
+        .and(warp::path!("items" / String))
+        .and(sender.clone())
+        .and_then(delete);
+
+    // GET /items/{id}/offline
+    let offline = warp::get()
+        .and(warp::path!("items" / String / "offline"))
+        .and(sender.clone())
+        .and_then(offline);
+
+    // GET /search?q=&count=
+    let search = warp::get()
+        .and(warp::path!("search"))
+        .and(warp::query::<SearchQuery>())
+        .and(sender)
+        .and_then(search);
+
+    list.or(get)
+        .or(add)
+        .or(edit)
+        .or(delete)
+        .or(offline)
+        .or(search)
+}
+
+async fn list(
+    query: ListQuery,
+    sender: Sender<(Request, Option<oneshot::Sender<Response>>)>,
+) -> Result<impl Reply, Rejection> {
+    let tags = query
+        .tags
+        .map(|tags| {
+            tags.split(',')
+                .filter(|t| !t.is_empty())
+                .map(ToOwned::to_owned)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let request = Request::List {
+        count: query.count,
+        tags,
+    };
+
+    Ok(into_reply(dispatch(&sender, request).await))
+}
+
+async fn get(
+    id: String,
+    sender: Sender<(Request, Option<oneshot::Sender<Response>>)>,
+) -> Result<impl Reply, Rejection> {
+    Ok(into_reply(dispatch(&sender, Request::Get { id }).await))
+}
+
+async fn add(
+    item: NewItem,
+    sender: Sender<(Request, Option<oneshot::Sender<Response>>)>,
+    local_files: bool,
+) -> Result<impl Reply, Rejection> {
+    if item.name.is_none() && item.url.is_none() && item.comment.is_none() {
+        let error = Response::Error("either name, url, or comment must be set".to_string());
+        return Ok(into_reply(Some(error)));
+    }
+
+    let request = Request::Add {
+        name: item.name,
+        url: item.url,
+        comment: item.comment,
+        tags: item.tags,
+    };
+
+    if let Some(refused) = refuse_local_file(&request, local_files) {
+        return Ok(into_reply(Some(refused)));
+    }
+
+    let reply = into_reply(dispatch(&sender, request).await);
+
+    if reply.status == StatusCode::OK {
+        Ok(reply.with_status(StatusCode::CREATED))
+    } else {
+        Ok(reply)
+    }
+}
+
+async fn edit(
+    id: String,
+    edit: Edit,
+    sender: Sender<(Request, Option<oneshot::Sender<Response>>)>,
+    local_files: bool,
+) -> Result<impl Reply, Rejection> {
+    let request = Request::Edit { id, edit };
+
+    if let Some(refused) = refuse_local_file(&request, local_files) {
+        return Ok(into_reply(Some(refused)));
+    }
+
+    Ok(into_reply(dispatch(&sender, request).await))
+}
+
+async fn delete(
+    id: String,
+    sender: Sender<(Request, Option<oneshot::Sender<Response>>)>,
+) -> Result<impl Reply, Rejection> {
+    Ok(into_reply(dispatch(&sender, Request::Delete { id }).await))
+}
+
+async fn offline(
+    id: String,
+    sender: Sender<(Request, Option<oneshot::Sender<Response>>)>,
+) -> Result<impl Reply, Rejection> {
+    Ok(into_reply(
+        dispatch(&sender, Request::GetOffline { id }).await,
+    ))
+}
+
+async fn search(
+    query: SearchQuery,
+    sender: Sender<(Request, Option<oneshot::Sender<Response>>)>,
+) -> Result<impl Reply, Rejection> {
+    let request = Request::Search {
+        count: query.count,
+        query: query.q,
+    };
+
+    Ok(into_reply(dispatch(&sender, request).await))
+}
+
+pub(super) struct JsonReply {
+    body: Value,
+    status: StatusCode,
+}
+
+impl JsonReply {
+    fn with_status(self, status: StatusCode) -> Self {
+        Self { status, ..self }
+    }
+}
+
+impl Reply for JsonReply {
+    fn into_response(self) -> reply::Response {
+        let reply: WithStatus<Json> = reply::with_status(reply::json(&self.body), self.status);
+        reply.into_response()
+    }
+}
+
+// The response's payload and the status code that goes with it. `None` means
+// the daemon never answered, e.g. because handling the request failed.
+pub(super) fn into_reply(response: Option<Response>) -> JsonReply {
+    let response = match response {
+        Some(response) => response,
+        None => {
+            return error_reply(
+                "monkd failed to handle the request",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        }
+    };
+
+    let (body, status) = match response {
+        Response::Item(meta) => (json!(meta), StatusCode::OK),
+        Response::List(metas) => (json!(metas), StatusCode::OK),
+        Response::Offline(data) => (json!(data), StatusCode::OK),
+        Response::SearchResult(results) => {
+            let results: Vec<Value> = results
+                .into_iter()
+                .map(|(meta, snippet)| json!({ "item": meta, "snippet": snippet }))
+                .collect();
+
+            (json!(results), StatusCode::OK)
+        }
+        Response::NotFound(id) => (error(format!("`{}` not found", id)), StatusCode::NOT_FOUND),
+        Response::TooManyMeta(id, metas) => (
+            json!({
+                "error": format!("`{}` matches more than one item", id),
+                "matches": metas,
+            }),
+            StatusCode::CONFLICT,
+        ),
+        Response::NoAdapterFound(id) => (
+            error(format!("no adapter can handle `{}`", id)),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        ),
+        Response::Unhandled => (
+            error("monkd could not handle the request"),
+            StatusCode::UNPROCESSABLE_ENTITY,
+        ),
+        // Internal errors never get a response, so these come from the client
+        Response::Error(e) => (error(e), StatusCode::BAD_REQUEST),
+        Response::Ok => (json!({}), StatusCode::OK),
+        response => (json!(response), StatusCode::OK),
+    };
+
+    JsonReply { body, status }
+}
+
+pub(super) fn error_reply(message: impl Into<String>, status: StatusCode) -> JsonReply {
+    JsonReply {
+        body: error(message),
+        status,
+    }
+}
+
+fn error(message: impl Into<String>) -> Value {
+    json!({ "error": message.into() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A daemon that says yes to everything
+    fn daemon() -> Sender<(Request, Option<oneshot::Sender<Response>>)> {
+        let (sender, receiver) =
+            async_channel::unbounded::<(Request, Option<oneshot::Sender<Response>>)>();
+
+        tokio::spawn(async move {
+            while let Ok((_, response)) = receiver.recv().await {
+                if let Some(response) = response {
+                    let _ = response.send(Response::Ok);
+                }
+            }
+        });
+
+        sender
+    }
+
+    #[tokio::test]
+    async fn failed_requests_are_server_errors() {
+        let (sender, receiver) =
+            async_channel::unbounded::<(Request, Option<oneshot::Sender<Response>>)>();
+
+        // Fails the first request it gets, like the daemon's run loop would
+        // on an internal error
+        tokio::spawn(async move {
+            let mut failed = false;
+
+            while let Ok((_, response)) = receiver.recv().await {
+                let res = if failed {
+                    Ok(Response::Ok)
+                } else {
+                    failed = true;
+                    Err(crate::error::Error::Custom("disk full".to_string()))
+                };
+
+                crate::respond(res, response);
+            }
+        });
+
+        let routes = routes(sender, false);
+
+        let failed = warp::test::request().path("/items/a1").reply(&routes).await;
+        assert_eq!(failed.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let served = warp::test::request().path("/items/a1").reply(&routes).await;
+        assert_eq!(served.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn file_urls_need_a_trusted_client() {
+        let untrusted = routes(daemon(), false);
+        let trusted = routes(daemon(), true);
+
+        let add = |url: &str| {
+            warp::test::request()
+                .method("POST")
+                .path("/items")
+                .json(&json!({ "url": url }))
+        };
+
+        let refused = add("file:///etc/passwd").reply(&untrusted).await;
+        assert_eq!(refused.status(), StatusCode::BAD_REQUEST);
+
+        let edited = warp::test::request()
+            .method("PATCH")
+            .path("/items/a1")
+            .json(&json!({ "url": "file:///etc/passwd" }))
+            .reply(&untrusted)
+            .await;
+        assert_eq!(edited.status(), StatusCode::BAD_REQUEST);
+
+        let page = add("https://example.com").reply(&untrusted).await;
+        assert_eq!(page.status(), StatusCode::CREATED);
+
+        let file = add("file:///home/ada/paper.pdf").reply(&trusted).await;
+        assert_eq!(file.status(), StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn file_urls_need_a_trusted_client_on_the_json_endpoint() {
+        let request = json!({ "Add": {
+            "name": null,
+            "url": "file:///etc/passwd",
+            "comment": null,
+            "tags": [],
+        }});
+
+        let refused = crate::server::handle(daemon(), false, request.clone())
+            .await
+            .unwrap()
+            .into_response();
+        let body = warp::hyper::body::to_bytes(refused.into_body())
+            .await
+            .unwrap();
+        let response: Response = serde_json::from_slice(&body).unwrap();
+        assert!(matches!(response, Response::Error(_)));
+
+        let accepted = crate::server::handle(daemon(), true, request)
+            .await
+            .unwrap()
+            .into_response();
+        let body = warp::hyper::body::to_bytes(accepted.into_body())
+            .await
+            .unwrap();
+        let response: Response = serde_json::from_slice(&body).unwrap();
+        assert!(matches!(response, Response::Ok));
+    }
+}
@@ -0,0 +1,13 @@
+// The web interface, a single page on top of the REST routes. It is built
+// into the binary, so there is nothing to install next to monkd.
+
+use warp::{Filter, Rejection, Reply};
+
+const INDEX: &str = include_str!("ui/index.html");
+
+pub fn routes() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path("ui"))
+        .and(warp::path::end())
+        .map(|| warp::reply::html(INDEX))
+}
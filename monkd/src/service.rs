@@ -0,0 +1,79 @@
+// Running monkd as a long-lived service, e.g. a systemd user unit.
+//
+// `monkd --foreground` never exits on `daemon.timeout`. Either way SIGINT and
+// SIGTERM stop monkd the same way a `Stop` request does, and when started by
+// systemd with `Type=notify` monkd reports when it's ready and stopping.
+
+/// Resolves once monkd is asked to stop with SIGINT or SIGTERM.
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(e) => {
+                tracing::warn!("[service] can't listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// Tell systemd monkd is up and serving requests.
+pub fn notify_ready() {
+    notify("READY=1");
+}
+
+/// Tell systemd monkd is shutting down.
+pub fn notify_stopping() {
+    notify("STOPPING=1");
+}
+
+// The sd_notify protocol: a datagram to the socket in `$NOTIFY_SOCKET`, which
+// is only set when systemd waits for one.
+#[cfg(unix)]
+fn notify(state: &str) {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+
+    let path = match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return,
+    };
+
+    let res = UnixDatagram::unbound().and_then(|socket| {
+        // Names starting with `@` are in the abstract namespace
+        match path.as_bytes().strip_prefix(b"@") {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                use std::os::unix::net::SocketAddr;
+
+                let addr = SocketAddr::from_abstract_name(name)?;
+                socket.send_to_addr(state.as_bytes(), &addr)
+            }
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => Err(std::io::ErrorKind::Unsupported.into()),
+            None => socket.send_to(state.as_bytes(), &path),
+        }
+    });
+
+    match res {
+        Ok(_) => tracing::debug!("[service] notified systemd: {}", state),
+        Err(e) => tracing::warn!("[service] could not notify systemd: {}", e),
+    }
+}
+
+#[cfg(not(unix))]
+fn notify(_state: &str) {}
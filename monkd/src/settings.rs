@@ -5,7 +5,8 @@ use std::path::{Path, PathBuf};
 use crate::adapter::AdapterType;
 use crate::error::Error;
 use crate::index::settings::IndexSettings;
-use crate::metadata::{file_store::StoreSettings, offline_store::OfflineSettings};
+use crate::jobs::JobSettings;
+use crate::metadata::{offline_store::OfflineSettings, store::StoreSettings};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Settings {
@@ -13,6 +14,8 @@ pub struct Settings {
     store: StoreSettings,
     offline: OfflineSettings,
     index: IndexSettings,
+    #[serde(default)]
+    jobs: JobSettings,
     log_dir: PathBuf,
     adapters: Vec<AdapterType>,
     #[serde(skip)]
@@ -101,6 +104,10 @@ impl Settings {
         &self.index
     }
 
+    pub fn jobs(&self) -> &JobSettings {
+        &self.jobs
+    }
+
     pub fn adapters(&self) -> &[AdapterType] {
         &self.adapters
     }
@@ -118,8 +125,14 @@ impl Default for Settings {
                 store: Default::default(),
                 offline: Default::default(),
                 index: Default::default(),
+                jobs: Default::default(),
                 log_dir: dirs.data_dir().join("logs"),
-                adapters: vec![AdapterType::Http, AdapterType::Youtube],
+                adapters: vec![
+                    AdapterType::Http,
+                    AdapterType::Youtube,
+                    AdapterType::Pdf,
+                    AdapterType::File,
+                ],
                 config_path: PathBuf::new(),
             }
         } else {
@@ -128,8 +141,14 @@ impl Default for Settings {
                 store: Default::default(),
                 offline: Default::default(),
                 index: Default::default(),
+                jobs: Default::default(),
                 log_dir: "./logs".into(),
-                adapters: vec![AdapterType::Http, AdapterType::Youtube],
+                adapters: vec![
+                    AdapterType::Http,
+                    AdapterType::Youtube,
+                    AdapterType::Pdf,
+                    AdapterType::File,
+                ],
                 config_path: PathBuf::new(),
             }
         }
@@ -146,6 +165,44 @@ pub struct DaemonSettings {
     pub timeout: usize,
     pub download_after_add: bool,
     pub download_on_open: bool,
+    /// Clients have to send this as a bearer token when set
+    #[serde(default)]
+    pub token: Option<String>,
+    /// Serve HTTPS instead of HTTP when set
+    #[serde(default)]
+    pub tls: Option<TlsSettings>,
+    /// Listen on `address`:`port`
+    #[serde(default = "default_tcp")]
+    pub tcp: bool,
+    /// Also listen on this Unix socket, which only the current user can
+    /// connect to. `monk` prefers it over TCP. Defaults to
+    /// `$XDG_RUNTIME_DIR/monk/monkd.sock`.
+    #[serde(default = "default_socket")]
+    pub socket: Option<PathBuf>,
+    /// Accept `file://` items from clients on TCP that don't send a token.
+    /// They can always be added over the Unix socket or with the token.
+    #[serde(default)]
+    pub allow_file_urls: bool,
+}
+
+fn default_tcp() -> bool {
+    true
+}
+
+fn default_socket() -> Option<PathBuf> {
+    if cfg!(unix) {
+        crate::get_dirs().and_then(|dirs| dirs.runtime_dir().map(|dir| dir.join("monkd.sock")))
+    } else {
+        None
+    }
+}
+
+/// A PEM encoded certificate and private key. `monk` trusts `cert` when
+/// connecting, so a self-signed certificate works.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsSettings {
+    pub cert: PathBuf,
+    pub key: PathBuf,
 }
 
 impl Default for DaemonSettings {
@@ -156,6 +213,11 @@ impl Default for DaemonSettings {
             timeout: 10000,
             download_after_add: true,
             download_on_open: true,
+            token: None,
+            tls: None,
+            tcp: default_tcp(),
+            socket: default_socket(),
+            allow_file_urls: false,
         }
     }
 }
@@ -3,9 +3,9 @@ use std::fs::DirEntry;
 use serde::{Deserialize, Serialize};
 
 use crate::error::Error;
-use crate::index::Index;
+use crate::index::{Index, Pipeline, RebuildProgress};
 use crate::metadata::offline_store::{OfflineStore, Status as OfflineStatus};
-use crate::metadata::{file_store::FileStore, meta::IndexStatus, Meta};
+use crate::metadata::{meta::IndexStatus, Meta, MetaStore};
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct MetaStatus {
@@ -50,7 +50,7 @@ pub struct FileStoreStatus {
 }
 
 impl FileStoreStatus {
-    pub fn new(store: &FileStore) -> Result<Self, Error> {
+    pub fn new(store: &dyn MetaStore) -> Result<Self, Error> {
         use std::fs::File;
 
         let file = File::open(store.file())?;
@@ -95,7 +95,7 @@ impl OfflineStoreStatus {
 }
 
 // Recursively gets size of a file
-fn get_size(entry: DirEntry) -> usize {
+pub(crate) fn get_size(entry: DirEntry) -> usize {
     use std::fs::read_dir;
     let mut size = 0;
     if entry.file_type().unwrap().is_dir() {
@@ -114,10 +114,18 @@ fn get_size(entry: DirEntry) -> usize {
 pub struct TIndexStatus {
     pub item_count: usize,
     pub bytes_on_disk: usize,
+    #[serde(default)]
+    pub rebuild: Option<RebuildProgress>,
+    /// Items waiting for an indexing worker
+    #[serde(default)]
+    pub queue_depth: usize,
+    /// Items being extracted or written to the index
+    #[serde(default)]
+    pub in_progress: usize,
 }
 
 impl TIndexStatus {
-    pub fn new(index: &Index) -> Result<Self, Error> {
+    pub fn new(index: &Index, pipeline: &Pipeline) -> Result<Self, Error> {
         use std::fs::read_dir;
 
         let item_count = index.count_indexed_items()?;
@@ -131,6 +139,9 @@ impl TIndexStatus {
         Ok(Self {
             item_count,
             bytes_on_disk,
+            rebuild: index.rebuild_progress().cloned(),
+            queue_depth: pipeline.queue_depth(),
+            in_progress: pipeline.in_progress(),
         })
     }
 }